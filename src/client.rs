@@ -1,26 +1,261 @@
+use crate::server::{MAX_JOIN_RESPONSE_SIZE, RULES_LENGTH};
+use crate::stream::{NetError, Stream};
+use crate::wrap::wrap_delta;
 use crossterm::input::AsyncReader;
 use crossterm::{input, AlternateScreen, InputEvent, KeyEvent, RawScreen};
 use lazy_static::lazy_static;
-use std::collections::HashMap;
-use std::io::{stdin, stdout, Read, Write};
-use std::net::TcpStream;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{stdin, stdout, BufWriter, Read, Write};
+use std::num::NonZeroU8;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex, Once};
 use std::thread;
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 #[derive(Copy, Clone, Debug)]
 enum ShowLeaderboard {
     Hide = 0,
     ByScore,
     ByKills,
+    ByLength,
+    /// Only when the server has scoring zones
+    ByBanked,
+}
+
+#[derive(Copy, Clone, Debug)]
+enum ShowNicknames {
+    All = 0,
+    /// Only the snakes close to mine
+    Nearby,
+    Hide,
+}
+
+/// The top left corner and size of a famine, and seconds until it ends
+type FamineArea = ((u16, u16), (u16, u16), u16);
+
+/// For how many more frames the body of a dead snake fades, and its fields in the world
+type FadingSnake = (u8, Vec<(u16, u16)>);
+
+/// The stream of a joined game, my ID, the world size and whether the X and Y edges are walls
+type Joined = (Stream, u16, (u16, u16), (bool, bool));
+
+/// Everything the server tells about a snake besides its body
+#[derive(Clone)]
+pub struct SnakeInfo {
+    nickname: String,
+    score: u16,
+    kills: u16,
+    fast_mode: bool,
+    length: u16,
+    /// Whether it has a food magnet
+    has_magnet: bool,
+    /// Whether it can't crash yet, after spawning
+    protected: bool,
+    /// The score banked in the scoring zones
+    banked: u16,
+    bot: bool,
+}
+
+/// Keeps track of the direction changes sent to the server,
+/// to let the player know when a turn was rejected
+struct DirectionState {
+    /// The last direction sent to server that wasn't applied yet
+    pending: Option<u8>,
+    /// My head position in the last frame
+    last_head_position: Option<(u16, u16)>,
+    /// The direction my snake moved in the last frame
+    moved: Option<u8>,
+    /// When the last turn was rejected by the server
+    rejected_at: Option<Instant>,
+    /// A relative turn made before the pending one was applied, it's sent once it is.
+    /// The server only applies the last turn of each tick, and two turns would make a 180
+    queued: Option<u8>,
+}
+
+/// All the parsed data of a single game frame
+#[derive(Clone)]
+struct Frame {
+    snakes: HashMap<u16, SnakeInfo>,
+    /// The tier of the field each food is on, as decided by the server
+    foods: HashMap<(i8, i8), u8>,
+    snake_parts: HashMap<(i8, i8), u16>,
+    /// How far each snake part is from the head of its snake, the head being 0
+    part_depths: HashMap<(i8, i8), u16>,
+    my_position: (u16, u16),
+    head_positions: HashMap<(u16, u16), u16>,
+    magnets: HashSet<(i8, i8)>,
+    /// The owners of the fields that are someone's territory, when painting
+    territory: HashMap<(i8, i8), u16>,
+    phases: HashSet<(i8, i8)>,
+    /// The IDs of the snakes that pass through other snakes right now
+    phasing: HashSet<u16>,
+}
+
+/// How my snake was doing the last time it was seen, for the summary after the game
+struct Summary {
+    score: u16,
+    kills: u16,
+    /// My place by score and the amount of snakes alive
+    place: (String, usize),
+    /// How long the snake had been alive
+    survived: Duration,
+}
+
+/// Keeps track of how often the server sends frames, to know when to predict one
+struct Prediction {
+    /// When the last frame from server was received
+    last_frame_at: Instant,
+    /// The average time between frames from server
+    frame_interval: Option<Duration>,
+    /// How many frames were predicted since the last frame from server
+    predicted_frames: u32,
+}
+
+impl Prediction {
+    /// Updates the timing after a frame from server is received
+    fn frame_received(&mut self) {
+        let elapsed = self.last_frame_at.elapsed();
+        self.frame_interval = Some(match self.frame_interval {
+            Some(interval) => (interval * 7 + elapsed) / 8,
+            None => elapsed,
+        });
+        self.last_frame_at = Instant::now();
+        self.predicted_frames = 0;
+    }
+    /// Returns how long to wait for the next frame from server before predicting it
+    fn timeout(&self) -> Duration {
+        match self.frame_interval {
+            Some(interval) if self.predicted_frames < MAX_PREDICTED_FRAMES => (interval
+                * (self.predicted_frames + 1))
+                .checked_sub(self.last_frame_at.elapsed())
+                .unwrap_or_default(),
+            _ => Duration::from_secs(1),
+        }
+    }
 }
 
 lazy_static! {
+    static ref DIRECTION_STATE: Mutex<DirectionState> = Mutex::new(DirectionState {
+        pending: None,
+        last_head_position: None,
+        moved: None,
+        rejected_at: None,
+        queued: None,
+    });
+    /// The round-trip time to the server in milliseconds, as measured by the server
+    static ref PING: Mutex<Option<u16>> = Mutex::new(None);
+    /// The game speed in ticks per second, as sent in the last frame
+    static ref GAME_SPEED: Mutex<u8> = Mutex::new(0);
+    /// The last drawn frame, used for predicting the next one
+    static ref LAST_FRAME: Mutex<Option<Frame>> = Mutex::new(None);
     static ref SHOW_LEADERBOARD: Mutex<ShowLeaderboard> = Mutex::new(ShowLeaderboard::ByScore);
+    static ref SHOW_NICKNAMES: Mutex<ShowNicknames> = Mutex::new(ShowNicknames::All);
+    /// Whether the scores of other snakes are shown next to their nicknames
+    static ref SHOW_SCORE_LABELS: Mutex<bool> = Mutex::new(false);
+    /// Whether snakes are shaded from head to tail, needs a terminal with true color support
+    static ref GRADIENT: Mutex<bool> = Mutex::new(false);
+    /// Whether the seams where the world wraps around are marked
+    static ref SHOW_EDGES: Mutex<bool> = Mutex::new(true);
+    /// Whether a coordinate grid fixed to the world is drawn under everything
+    static ref SHOW_GRID: Mutex<bool> = Mutex::new(false);
+    /// Whether the summary of the rules of the server is shown
+    static ref SHOW_RULES: Mutex<bool> = Mutex::new(false);
+    /// The rules of the server as sent when joining, a line of text for each
+    static ref RULES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+    /// Whether food is drawn as symbols of its tier instead of colored blocks, for colorblind players
+    static ref FOOD_NUMBERS: Mutex<bool> = Mutex::new(false);
+    /// Seconds left until sudden death as last sent by server, 0 if it's on, `None` if the server has no sudden death
+    static ref SUDDEN_DEATH: Mutex<Option<u16>> = Mutex::new(None);
+    /// The top left corner and size of the area where no food grows,
+    /// and seconds until that ends. `None` if there's no famine
+    static ref FAMINE: Mutex<Option<FamineArea>> = Mutex::new(None);
+    /// Whether the server disabled fast mode
+    static ref FAST_MODE_DISABLED: Mutex<bool> = Mutex::new(false);
+    /// The score needed to turn on fast mode
+    static ref FAST_MIN_SCORE: Mutex<u16> = Mutex::new(1);
+    /// The size of the scoring zones and their top left corners in the world
+    static ref SCORING_ZONES: Mutex<(u16, Vec<(u16, u16)>)> = Mutex::new((0, Vec::new()));
+    /// Whether an admin paused the game
+    static ref PAUSED: Mutex<bool> = Mutex::new(false);
+    /// Recent kills: when they happened, the killer's and the victim's nicknames
+    static ref KILL_FEED: Mutex<VecDeque<(Instant, String, String)>> = Mutex::new(VecDeque::new());
+    /// My last kill streak: when it was reported, how many kills in a row and the bonus score
+    static ref KILL_STREAK: Mutex<Option<(Instant, u16, u16)>> = Mutex::new(None);
+    /// Whether the game is played in rounds won by the last snake left alive
+    static ref LAST_STANDING: Mutex<bool> = Mutex::new(false);
+    /// When the last round ended and the text announcing its winner
+    static ref VICTORY: Mutex<Option<(Instant, String)>> = Mutex::new(None);
+    /// The shortest time between two drawn frames, `None` if every frame is drawn
+    static ref MIN_FRAME_INTERVAL: Mutex<Option<Duration>> = Mutex::new(None);
+    /// When the last frame was drawn
+    static ref LAST_DRAW: Mutex<Option<Instant>> = Mutex::new(None);
+    /// When the last announcement from the server came and its text
+    static ref ANNOUNCEMENT: Mutex<Option<(Instant, String)>> = Mutex::new(None);
+    /// Whether the screen is dimmed when I'm away from the keyboard
+    static ref AFK_DIM: Mutex<bool> = Mutex::new(false);
+    /// Whether only ASCII characters are drawn, for terminals without Unicode support
+    static ref ASCII: Mutex<bool> = Mutex::new(false);
+    /// Whether the terminal bell rings when my score goes up
+    static ref BELL: Mutex<bool> = Mutex::new(false);
+    /// When the bell last rang
+    static ref LAST_BELL: Mutex<Option<Instant>> = Mutex::new(None);
+    /// The recording of everything drawn, `None` if it's not recorded
+    static ref CAST: Mutex<Option<Cast>> = Mutex::new(None);
+    /// When I last pressed a key
+    static ref LAST_INPUT: Mutex<Instant> = Mutex::new(Instant::now());
+    /// Bodies of the snakes that died recently: for how many more frames they fade and their fields in the world
+    static ref FADING: Mutex<VecDeque<FadingSnake>> = Mutex::new(VecDeque::new());
+    /// Whether the screen has to be cleared before drawing the next frame
+    static ref CLEAR_SCREEN: Mutex<bool> = Mutex::new(false);
+    /// Whether to print a summary of the game when leaving it
+    static ref SHOW_SUMMARY: Mutex<bool> = Mutex::new(false);
+    /// My snake's stats from the last frame it was in
+    static ref SUMMARY: Mutex<Option<Summary>> = Mutex::new(None);
+    /// When my current snake was given to me
+    static ref ALIVE_SINCE: Mutex<Instant> = Mutex::new(Instant::now());
 }
 
+// How many kills are shown in the kill feed at once
+const KILL_FEED_LENGTH: usize = 5;
+// How long a kill stays in the kill feed
+const KILL_FEED_DURATION: Duration = Duration::from_secs(3);
+// How far (in fields on each axis) the heads of other snakes can be from mine
+// for their nicknames to be shown when only the nearby ones are
+const NEARBY_NICKNAME_DISTANCE: i32 = 10;
+// How close (in fields) my head has to be to a wall for it to turn red
+const WALL_WARNING_DISTANCE: u16 = 5;
+// How long a kill streak message is shown
+const KILL_STREAK_DURATION: Duration = Duration::from_secs(2);
+// How long the winner of a round is shown
+const VICTORY_DURATION: Duration = Duration::from_secs(3);
+// How long an announcement from the server is shown
+const ANNOUNCEMENT_DURATION: Duration = Duration::from_secs(4);
+// How long the rejected turn warning is shown
+const REJECTED_TURN_DURATION: Duration = Duration::from_secs(1);
+// How long without pressing any keys until I'm shown as away from the keyboard
+const AFK_DELAY: Duration = Duration::from_secs(20);
+// The shortest time between two rings of the bell, so eating a lot at once rings it only once
+const BELL_INTERVAL: Duration = Duration::from_millis(300);
+// How many seconds before sudden death the countdown is shown
+const SUDDEN_DEATH_WARNING: u16 = 30;
+// How many world fields apart the lines of the coordinate grid are
+const GRID_SPACING: i32 = 10;
+// How many frames can be predicted in a row while waiting for the server
+const MAX_PREDICTED_FRAMES: u32 = 2;
+// For how many frames the body of a dead snake stays on the screen, fading away
+const FADE_FRAMES: u8 = 6;
+// At most this many dead snakes fade at once, the oldest ones disappear first
+const MAX_FADING_SNAKES: usize = 16;
+// The terminal size used when it can't be fetched, for example when the output is piped
+const DEFAULT_TERMINAL_SIZE: (u16, u16) = (98, 30);
+// The smallest terminal size that the frame can be drawn in without breaking the layout
+const MIN_TERMINAL_SIZE: (u16, u16) = (80, 20);
+
 const SNAKE_COLORS: [&str; 9] = [
     "\x1b[41;30;1m",  // Red
     "\x1b[46;30;1m",  // Cyan
@@ -33,6 +268,24 @@ const SNAKE_COLORS: [&str; 9] = [
     "\x1b[106;30;1m", // Light Cyan
 ];
 
+// The background colors of `SNAKE_COLORS` in RGB, for shading the snakes
+const SNAKE_COLORS_RGB: [(u8, u8, u8); 9] = [
+    (205, 0, 0),     // Red
+    (0, 205, 205),   // Cyan
+    (127, 127, 127), // Dark Gray
+    (255, 85, 85),   // Light Red
+    (85, 255, 85),   // Light Green
+    (255, 255, 85),  // Light Yellow
+    (85, 85, 255),   // Light Blue
+    (255, 85, 255),  // Light Magenta
+    (85, 255, 255),  // Light Cyan
+];
+// How dark the tail of a shaded snake is, compared to its head
+const GRADIENT_TAIL_BRIGHTNESS: f32 = 0.35;
+// How dark the territory of a snake is, compared to the snake itself
+const TERRITORY_BRIGHTNESS: f32 = 0.3;
+
+// Colors of the food tiers, by how much food there is on the whole field.
 // In tuples, first is for foreground, second is for background
 const FOOD_COLORS: [(&str, &str); 4] = [
     ("\x1b[32m", "\x1b[42m"), // Green = 1 food
@@ -40,68 +293,212 @@ const FOOD_COLORS: [(&str, &str); 4] = [
     ("\x1b[34m", "\x1b[44m"), // Blue = 5 foods
     ("\x1b[35m", "\x1b[45m"), // Magenta = 11 or more foods
 ];
+// The same tiers as FOOD_COLORS, for drawing food as symbols
+const FOOD_SYMBOLS: [&str; 4] = ["1", "2", "5", "+"];
+
+// Food density in the heat map, from no food to the most food
+const HEATMAP_COLORS: [&str; 8] = [
+    "\x1b[40m",       // Black
+    "\x1b[48;5;17m",  // Dark Blue
+    "\x1b[48;5;21m",  // Blue
+    "\x1b[48;5;30m",  // Teal
+    "\x1b[48;5;34m",  // Green
+    "\x1b[48;5;184m", // Yellow
+    "\x1b[48;5;208m", // Orange
+    "\x1b[48;5;196m", // Red
+];
+
+// The biggest message the server can send during the game, a frame being the longest
+const MAX_MESSAGE_SIZE: usize = u16::MAX as usize;
 
 // Magic networking bytes:
 const MAGIC_NET_REQUEST_TO_PLAY: u8 = 0x00;
+const MAGIC_NET_SERVER_STATUS: u8 = 0x01;
 const MAGIC_NET_CHANGE_DIRECTION: u8 = 0x02;
 const MAGIC_NET_DEATH: u8 = 0x03;
 const MAGIC_NET_GAME_DATA: u8 = 0x04;
 const MAGIC_NET_ERROR: u8 = 0x05;
 const MAGIC_NET_JOINED_GAME: u8 = 0x06;
+const MAGIC_NET_KILLFEED: u8 = 0x07;
 const MAGIC_NET_TOGGLE_FAST: u8 = 0x08;
 const MAGIC_NET_EXIT: u8 = 0x09;
+const MAGIC_NET_PING: u8 = 0x0A;
+const MAGIC_NET_PONG: u8 = 0x0B;
+const MAGIC_NET_REQUEST_TO_SPECTATE: u8 = 0x0C;
+const MAGIC_NET_MOVE_CAMERA: u8 = 0x0D;
+const MAGIC_NET_WORLD_DUMP: u8 = 0x0E;
+const MAGIC_NET_RESPAWNED: u8 = 0x0F;
+const MAGIC_NET_PLAYER_STATS: u8 = 0x10;
+const MAGIC_NET_SUDDEN_DEATH: u8 = 0x11;
+const MAGIC_NET_PAUSED: u8 = 0x12;
+const MAGIC_NET_KILL_STREAK: u8 = 0x13;
+const MAGIC_NET_JOIN_ROOM: u8 = 0x15;
+const MAGIC_NET_FOLLOW_NEXT_SNAKE: u8 = 0x16;
+const MAGIC_NET_VICTORY: u8 = 0x17;
+const MAGIC_NET_ANNOUNCE: u8 = 0x18;
+const MAGIC_NET_FAMINE: u8 = 0x19;
+
+// Kinds of errors, the second byte of each error message.
+// Only a full server is worth waiting for, the other errors don't go away by themselves
+const ERROR_SERVER_FULL: u8 = 1;
+
+// How long to wait before trying to join a full server again
+const SERVER_FULL_RETRY_DELAY: Duration = Duration::from_secs(5);
+// How often the dumped frames are flushed to the file
+const DUMP_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+// Sections of the world dump
+const WORLD_DUMP_HEADER: u8 = 0;
+const WORLD_DUMP_FOODS: u8 = 2;
+const WORLD_DUMP_END: u8 = 3;
+
+// The ID of the snake parts that are walls of the map
+const WALL_ID: u16 = u16::MAX;
 
 pub enum Exit {
     Continue,
+    /// A frame in between the last two frames was drawn, the last frame has to be drawn later
+    Interpolated,
     Death,
+    /// Died, but the server will give a new snake soon
+    Respawning,
+    /// The last frame was drawn too recently, the new one has to be drawn at the given time
+    Throttled(Instant),
+}
+
+/// Settings for reconnecting to the server when the connection fails
+#[derive(Copy, Clone, Debug)]
+pub struct RetryOptions {
+    /// How many times to try connecting before giving up
+    pub attempts: u32,
+    /// The delay before the first retry, doubled after each failed attempt
+    pub base_delay: Duration,
+}
+
+/// Everything the client is started with, from the command line and the config file
+#[derive(Clone, Debug)]
+pub struct ClientOptions {
+    /// IP address of the server, ignored if `socket` is given
+    pub ip: String,
+    pub port: u16,
+    pub nickname: String,
+    pub retry: RetryOptions,
+    /// Predict the movement of my snake when frames from server are late
+    pub predict: bool,
+    /// The Unix domain socket of the server to connect to instead of the IP
+    pub socket: Option<PathBuf>,
+    /// Watch the game instead of playing
+    pub spectate: bool,
+    /// The room to join instead of the main game
+    pub room: Option<String>,
+    /// How long to wait for data from the server before giving up
+    pub timeout: Duration,
+    /// Draw frames in between the frames from server
+    pub smooth: bool,
+    /// Shade the snakes from head to tail
+    pub gradient: bool,
+    /// Steer with left and right only, relative to where the snake is heading
+    pub relative_controls: bool,
+    /// Dim the screen after a while without pressing any keys
+    pub afk_dim: bool,
+    /// Append every message from the server to this file
+    pub dump_frames: Option<PathBuf>,
+    /// Draw only ASCII characters
+    pub ascii: bool,
+    /// Ring the terminal bell when eating
+    pub bell: bool,
+    /// Draw at most this many frames per second
+    pub max_fps: Option<NonZeroU8>,
+    /// Print the final score, kills, place and time survived when leaving
+    pub summary: bool,
+    /// Record the game to this file as an asciinema cast
+    pub cast: Option<PathBuf>,
+}
+
+/// Returns `false` if the locale of the terminal isn't UTF-8, so the glyphs would be garbled
+pub fn locale_is_utf8() -> bool {
+    // The first of these that is set decides the character set
+    let locale = ["LC_ALL", "LC_CTYPE", "LANG"]
+        .iter()
+        .filter_map(|variable| std::env::var(variable).ok())
+        .find(|value| !value.is_empty())
+        .unwrap_or_default()
+        .to_lowercase();
+    locale.contains("utf-8") || locale.contains("utf8")
 }
 
 /// Connects to the server and starts the client
-pub fn start(ip: String, port: u16, nickname: String) {
-    println!("connecting to {}:{} with nickname {}", ip, port, nickname);
-    let mut stream = match TcpStream::connect((&ip[..], port)) {
-        Ok(stream) => stream,
-        Err(e) => {
-            println!("Couldn't connect to host: {}", e);
-            return;
-        }
+pub fn start(options: ClientOptions) {
+    // The settings that are needed all the time, or in the threads
+    let ClientOptions {
+        port,
+        predict,
+        spectate,
+        smooth,
+        relative_controls,
+        ..
+    } = options;
+    let (ip, nickname) = (&options.ip, &options.nickname);
+    *GRADIENT.lock().unwrap() = options.gradient;
+    *SHOW_SUMMARY.lock().unwrap() = options.summary;
+    *AFK_DIM.lock().unwrap() = options.afk_dim;
+    *ASCII.lock().unwrap() = options.ascii;
+    *BELL.lock().unwrap() = options.bell;
+    *MIN_FRAME_INTERVAL.lock().unwrap() = options
+        .max_fps
+        .map(|max_fps| Duration::from_secs(1) / max_fps.get() as u32);
+
+    match &options.socket {
+        Some(path) => println!(
+            "connecting to {} with nickname {}",
+            path.display(),
+            nickname
+        ),
+        None => println!("connecting to {}:{} with nickname {}", ip, port, nickname),
+    }
+    let (stream, mut my_id, world_size, walls) = match join_game(
+        ip,
+        port,
+        options.socket.as_deref(),
+        nickname,
+        options.retry,
+        spectate,
+        options.room.as_deref(),
+    ) {
+        Some(joined) => joined,
+        None => return,
     };
+    println!("Connected successfully!");
 
-    // Send my nickname as a request to connect to the game
-    let mut bytes: Vec<u8> = vec![MAGIC_NET_REQUEST_TO_PLAY];
-    bytes.extend_from_slice(nickname.as_bytes());
-    send_to_stream(&mut stream, &bytes);
+    // Give up on the server if it doesn't send anything for too long
+    stream
+        .set_read_timeout(Some(options.timeout))
+        .expect("set_read_timeout call failed");
 
-    // Read the response
-    let (my_id, world_size) = match read_from_stream(&mut stream) {
-        Err(_) => {
-            println!("Connection lost after requesting to join game");
-            return;
-        }
-        Ok(bytes) => {
-            if bytes[0] == MAGIC_NET_ERROR {
-                // It's an error
-                println!(
-                    "Error from server: {}",
-                    std::str::from_utf8(&bytes[1..]).unwrap_or("{corrupted error}")
-                );
-                return;
-            } else if bytes[0] == MAGIC_NET_JOINED_GAME && bytes.len() == 7 {
-                // It's a confirmation that I joined the game, with my ID and the world size
-                (
-                    u16::from_be_bytes([bytes[1], bytes[2]]),
-                    (
-                        u16::from_be_bytes([bytes[3], bytes[4]]),
-                        u16::from_be_bytes([bytes[5], bytes[6]]),
-                    ),
-                )
-            } else {
-                println!("Corrupted message from server: disconnecting.");
-                return;
+    // Forget everything from the last game
+    forget_last_snake();
+    *PING.lock().unwrap() = None;
+    *SUDDEN_DEATH.lock().unwrap() = None;
+    *FAMINE.lock().unwrap() = None;
+    *PAUSED.lock().unwrap() = false;
+    *KILL_STREAK.lock().unwrap() = None;
+    *VICTORY.lock().unwrap() = None;
+    *ANNOUNCEMENT.lock().unwrap() = None;
+    *LAST_INPUT.lock().unwrap() = Instant::now();
+
+    // Keep every message from server for debugging, if asked to
+    let mut dump = options.dump_frames.as_deref().and_then(|path| {
+        match OpenOptions::new().create(true).append(true).open(path) {
+            Ok(file) => Some(FrameDump {
+                file: BufWriter::new(file),
+                flushed_at: Instant::now(),
+            }),
+            Err(e) => {
+                println!("Can't dump frames to {}: {}", path.display(), e);
+                None
             }
         }
-    };
-    println!("Connected successfully!");
+    });
 
     // Spawn the thread for handling user input and sending to server
     let stream_ref = Arc::new(Mutex::new(Some(
@@ -115,9 +512,22 @@ pub fn start(ip: String, port: u16, nickname: String) {
     let input = input();
     let async_reader = input.read_async();
 
+    // Record everything that's drawn, if asked to. Playing again keeps the same recording going
+    if let Some(path) = &options.cast {
+        let mut recording = CAST.lock().unwrap();
+        if recording.is_none() {
+            match Cast::create(path, terminal_size()) {
+                Ok(new_cast) => *recording = Some(new_cast),
+                Err(e) => println!("Can't record to {}: {}", path.display(), e),
+            }
+        }
+    }
+
     // Get the terminal ready
+    restore_terminal_on_panic();
     // Hide the carriage
     print!("\x1b[?25l");
+    record("\x1b[?25l");
     // Move to an alternate screen and into raw mode
     let alternate_screen_guard = AlternateScreen::to_alternate(true)
         .expect("Failed to put terminal into alternative screen.");
@@ -131,68 +541,679 @@ pub fn start(ip: String, port: u16, nickname: String) {
                 async_reader,
                 exit_input_handler_clone,
                 alternate_screen_guard,
+                spectate,
+                relative_controls,
             )
         })
         .unwrap();
 
+    // A thread for reading messages from server, so that the main thread
+    // can draw predicted frames while waiting for them
+    let (messages_sender, messages) = mpsc::channel();
+    thread::Builder::new()
+        .name("server_reader".to_string())
+        .spawn(move || {
+            let mut stream = stream;
+            loop {
+                let message = read_from_stream(&mut stream, MAX_MESSAGE_SIZE);
+                if let (Some(dump), Ok(bytes)) = (&mut dump, &message) {
+                    dump.write(bytes);
+                }
+                let lost = message.is_err();
+                if messages_sender.send(message).is_err() || lost {
+                    return;
+                }
+            }
+        })
+        .unwrap();
+
+    let mut prediction = Prediction {
+        last_frame_at: Instant::now(),
+        frame_interval: None,
+        predicted_frames: 0,
+    };
+
+    // When the last frame from server has to be drawn, after a frame in between was drawn
+    // or because the frames are coming faster than they can be drawn
+    let mut frame_due_at: Option<Instant> = None;
+
     // The main thread will be reading data from server and drawing it for the user
     loop {
-        let bytes = match read_from_stream(&mut stream) {
+        let message = if predict || smooth || frame_due_at.is_some() {
+            let mut timeout = if predict {
+                prediction.timeout()
+            } else {
+                Duration::from_secs(1)
+            };
+            if let Some(due_at) = frame_due_at {
+                timeout = timeout.min(due_at.saturating_duration_since(Instant::now()));
+            }
+            match messages.recv_timeout(timeout) {
+                Ok(message) => message,
+                Err(RecvTimeoutError::Timeout) => {
+                    if let Some(due_at) = frame_due_at {
+                        if due_at <= Instant::now() {
+                            frame_due_at = None;
+                            draw_last_frame(my_id, world_size, walls);
+                            continue;
+                        }
+                    }
+                    // The frame is late, draw what it should most likely look like
+                    if predict && prediction.frame_interval.is_some() {
+                        predict_frame(my_id, world_size, walls);
+                        prediction.predicted_frames += 1;
+                    }
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => Err(NetError::ConnectionClosed),
+            }
+        } else {
+            messages.recv().unwrap_or(Err(NetError::ConnectionClosed))
+        };
+        let bytes = match message {
             Err(_) => {
                 exit_input_handler.store(true, Ordering::Relaxed);
                 join_handle.join().unwrap();
                 println!("Unexpectedly lost connection to server.");
+                print_summary();
+                finish_cast();
                 return;
             }
             Ok(bytes) => bytes,
         };
+        if !bytes.is_empty() && bytes[0] == MAGIC_NET_GAME_DATA {
+            prediction.frame_received();
+        }
+        if bytes.len() == 7 && bytes[0] == MAGIC_NET_PING {
+            // Answer with the same sequence number right away
+            if let Some(s) = stream_ref.lock().unwrap().as_mut() {
+                send_to_stream(s, &[MAGIC_NET_PONG, bytes[1], bytes[2], bytes[3], bytes[4]]);
+            }
+            // And remember the last round-trip time measured by server
+            let rtt = u16::from_be_bytes([bytes[5], bytes[6]]);
+            *PING.lock().unwrap() = if rtt == u16::MAX { None } else { Some(rtt) };
+            continue;
+        }
+        if bytes.len() == 3 && bytes[0] == MAGIC_NET_RESPAWNED {
+            // The server gave me a new snake, carry on with the new ID
+            my_id = u16::from_be_bytes([bytes[1], bytes[2]]);
+            forget_last_snake();
+            continue;
+        }
         // Handle it and draw the frame
-        if let Exit::Death = handle_server_message(bytes, my_id, world_size) {
+        let exit = handle_server_message(bytes, my_id, world_size, walls, smooth);
+        if let Exit::Interpolated = exit {
+            // Draw the real frame halfway to the next one
+            match prediction.frame_interval {
+                Some(interval) => frame_due_at = Some(Instant::now() + interval / 2),
+                None => draw_last_frame(my_id, world_size, walls),
+            }
+            continue;
+        }
+        if let Exit::Throttled(due_at) = exit {
+            // A newer frame might come before then, it's drawn instead
+            frame_due_at = Some(due_at);
+            continue;
+        }
+        if let Exit::Respawning = exit {
+            // Don't predict the movement of the dead snake
+            *LAST_FRAME.lock().unwrap() = None;
+            let (w, h) = terminal_size();
+            let text = if *LAST_STANDING.lock().unwrap() {
+                "You died! Waiting for the next round..."
+            } else {
+                "You died! Respawning..."
+            };
+            let output = format!(
+                "\x1b[{line};{column}H\x1b[107;30;1m{text}\x1b[0m",
+                line = h.saturating_sub(1) / 2,
+                column = (w as usize).saturating_sub(text.len()) / 2,
+                text = text
+            );
+            print!("{}", output);
+            record(&output);
+            std::io::stdout().flush().unwrap();
+            continue;
+        }
+        if let Exit::Death = exit {
             *stream_ref.lock().unwrap() = None;
-            if let Some((w, h)) = term_size::dimensions() {
-                let text = "You died! Play again? [y/n]";
-                let line = (h - 1) / 2;
-                let column = (w - text.len()) / 2;
-                print!(
-                    "\x1b[{line};{column}H\x1b[107;30;1m{text}\x1b[0m",
-                    line = line,
-                    column = column,
-                    text = text
-                );
-                std::io::stdout().flush().unwrap();
-                let stdin = stdin();
-                let mut stdinlock = stdin.lock();
-                let mut c = [0u8];
-                loop {
-                    stdinlock.read_exact(&mut c[..]).unwrap();
-                    if c[0] == b'y' {
-                        drop(stdinlock);
-                        exit_input_handler.store(true, Ordering::Relaxed);
-                        join_handle.join().unwrap();
-                        start(ip, port, nickname);
-                        return;
-                    }
-                    if c[0] == b'n' {
-                        break;
-                    }
+            let (w, h) = terminal_size();
+            let text = "You died! Play again? [y/n], or [s] to spectate";
+            let line = h.saturating_sub(1) / 2;
+            let column = (w as usize).saturating_sub(text.len()) / 2;
+            let output = format!(
+                "\x1b[{line};{column}H\x1b[107;30;1m{text}\x1b[0m",
+                line = line,
+                column = column,
+                text = text
+            );
+            print!("{}", output);
+            record(&output);
+            std::io::stdout().flush().unwrap();
+            let stdin = stdin();
+            let mut stdinlock = stdin.lock();
+            let mut c = [0u8];
+            loop {
+                // Treat a closed stdin as a no
+                if stdinlock.read_exact(&mut c[..]).is_err() {
+                    break;
+                }
+                // Spectating is just joining again without a snake
+                if c[0] == b'y' || c[0] == b's' {
+                    drop(stdinlock);
+                    exit_input_handler.store(true, Ordering::Relaxed);
+                    join_handle.join().unwrap();
+                    start(ClientOptions {
+                        spectate: spectate || c[0] == b's',
+                        ..options
+                    });
+                    return;
+                }
+                if c[0] == b'n' {
+                    break;
                 }
             }
             exit_input_handler.store(true, Ordering::Relaxed);
             join_handle.join().unwrap();
+            print_summary();
+            finish_cast();
+            return;
+        }
+    }
+}
+
+/// Makes a panic anywhere in the client get the terminal out of raw mode and the alternate screen
+/// and show the carriage again before the panic message is printed, so the terminal stays usable
+fn restore_terminal_on_panic() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let default_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            let _ = RawScreen::disable_raw_mode();
+            let _ = stdout().write_all(b"\x1b[?1049l\x1b[?25h");
+            let _ = stdout().flush();
+            default_hook(info);
+        }));
+    });
+}
+
+/// Prints how my last snake did, if asked to and if I had one
+fn print_summary() {
+    if !*SHOW_SUMMARY.lock().unwrap() {
+        return;
+    }
+    if let Some(summary) = SUMMARY.lock().unwrap().as_ref() {
+        let survived = summary.survived.as_secs();
+        println!("Game over!");
+        println!("  Score:    {}", summary.score);
+        println!("  Kills:    {}", summary.kills);
+        println!("  Place:    {} of {}", summary.place.0, summary.place.1);
+        println!("  Survived: {}:{:02}", survived / 60, survived % 60);
+    }
+}
+
+/// Asks the server for my lifetime statistics and prints them
+pub fn show_stats(ip: &str, port: u16, socket: Option<&Path>, nickname: &str) {
+    let mut stream = match Stream::connect(ip, port, socket) {
+        Ok(stream) => stream,
+        Err(e) => {
+            println!("Couldn't connect to host: {}", e);
+            return;
+        }
+    };
+
+    // Send my nickname as a request for the statistics
+    let mut bytes: Vec<u8> = vec![MAGIC_NET_PLAYER_STATS];
+    bytes.extend_from_slice(nickname.as_bytes());
+    send_to_stream(&mut stream, &bytes);
+
+    // Read the response
+    let bytes = match read_from_stream(&mut stream, MAX_JOIN_RESPONSE_SIZE) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            println!("Connection lost after requesting statistics: {}", e);
+            return;
+        }
+    };
+    if bytes[0] == MAGIC_NET_ERROR {
+        println!("Error from server: {}", error_text(&bytes));
+    } else if bytes[0] == MAGIC_NET_PLAYER_STATS && bytes.len() == 1 {
+        println!("{} hasn't played on this server yet.", nickname);
+    } else if bytes[0] == MAGIC_NET_PLAYER_STATS && bytes.len() == 19 {
+        let longest_life = u32::from_be_bytes([bytes[15], bytes[16], bytes[17], bytes[18]]);
+        println!("Statistics of {}:", nickname);
+        println!(
+            "  Games played: {}",
+            u32::from_be_bytes([bytes[1], bytes[2], bytes[3], bytes[4]])
+        );
+        println!(
+            "  Food eaten:   {}",
+            u32::from_be_bytes([bytes[5], bytes[6], bytes[7], bytes[8]])
+        );
+        println!(
+            "  Kills:        {}",
+            u32::from_be_bytes([bytes[9], bytes[10], bytes[11], bytes[12]])
+        );
+        println!(
+            "  Best score:   {}",
+            u16::from_be_bytes([bytes[13], bytes[14]])
+        );
+        println!(
+            "  Longest life: {}m {}s",
+            longest_life / 60,
+            longest_life % 60
+        );
+    } else {
+        println!("Corrupted message from server.");
+    }
+}
+
+/// Asks the server for the longest nickname it allows, after escaping.
+/// Returns `None` if it can't be found out
+pub fn max_nickname_length(ip: &str, port: u16, socket: Option<&Path>) -> Option<usize> {
+    let mut stream = Stream::connect(ip, port, socket).ok()?;
+    send_to_stream(&mut stream, &[MAGIC_NET_SERVER_STATUS]);
+    let bytes = read_from_stream(&mut stream, u16::MAX as usize).ok()?;
+    status_max_nickname_length(&bytes)
+}
+
+/// Reads the longest nickname allowed from the server status. It's the last byte, but only
+/// if everything before it adds up, since older servers end the status before it
+pub fn status_max_nickname_length(status: &[u8]) -> Option<usize> {
+    // max players, bots, humans, world size, food rate and game speed
+    let mut i = 12;
+    // The 3 leaderboards, each entry is a nickname and a 2 byte value
+    for _board in 0..3 {
+        let entries = *status.get(i)?;
+        i += 1;
+        for _entry in 0..entries {
+            i += 1 + *status.get(i)? as usize + 2;
+        }
+    }
+    // uptime, peak humans, connections and unique connections
+    i += 14;
+    // The rooms, each is a name and a 2 byte player count
+    let rooms = *status.get(i)?;
+    i += 1;
+    for _room in 0..rooms {
+        i += 1 + *status.get(i)? as usize + 2;
+    }
+    if i + 1 != status.len() {
+        return None;
+    }
+    Some(status[i] as usize)
+}
+
+/// Downloads the whole world from the server and shows how densely the food is spread over it,
+/// so the food settings can be tuned. The server must allow world dumps
+pub fn show_food_heatmap(ip: &str, port: u16, socket: Option<&Path>) {
+    let mut stream = match Stream::connect(ip, port, socket) {
+        Ok(stream) => stream,
+        Err(e) => {
+            println!("Couldn't connect to host: {}", e);
+            return;
+        }
+    };
+    send_to_stream(&mut stream, &[MAGIC_NET_WORLD_DUMP]);
+
+    // Read the dump, only the world size and the foods matter here
+    let mut world_size = None;
+    let mut foods: Vec<(u16, u16, u8)> = Vec::new();
+    loop {
+        let bytes = match read_from_stream(&mut stream, MAX_MESSAGE_SIZE) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                println!("Connection lost while downloading the world: {}", e);
+                return;
+            }
+        };
+        if bytes[0] == MAGIC_NET_ERROR {
+            println!("Error from server: {}", error_text(&bytes));
+            return;
+        }
+        if bytes.len() < 2 || bytes[0] != MAGIC_NET_WORLD_DUMP {
+            println!("Corrupted message from server.");
             return;
         }
+        match bytes[1] {
+            // World width (2 bytes), world height (2 bytes), walled edges (1 byte)
+            WORLD_DUMP_HEADER if bytes.len() == 7 => {
+                world_size = Some((
+                    u16::from_be_bytes([bytes[2], bytes[3]]),
+                    u16::from_be_bytes([bytes[4], bytes[5]]),
+                ));
+            }
+            // Any amount of foods, each is x (2 bytes), y (2 bytes), amount (1 byte)
+            WORLD_DUMP_FOODS => {
+                for food in bytes[2..].chunks_exact(5) {
+                    foods.push((
+                        u16::from_be_bytes([food[0], food[1]]),
+                        u16::from_be_bytes([food[2], food[3]]),
+                        food[4],
+                    ));
+                }
+            }
+            WORLD_DUMP_END => break,
+            _ => {}
+        }
+    }
+    let world_size = match world_size {
+        Some(world_size) if world_size.0 > 0 && world_size.1 > 0 => world_size,
+        _ => {
+            println!("Corrupted message from server.");
+            return;
+        }
+    };
+
+    // Fit the whole world in the terminal, leaving space for the legend below.
+    // Every bucket of fields is drawn as 2 characters
+    let real_terminal_size = terminal_size();
+    let columns = (real_terminal_size.0 as usize / 2)
+        .min(world_size.0 as usize)
+        .max(1);
+    let rows = (real_terminal_size.1 as usize)
+        .saturating_sub(3)
+        .min(world_size.1 as usize)
+        .max(1);
+
+    // Sum up the food in every bucket. Food positions have twice the resolution of the world
+    let mut buckets = vec![0u32; columns * rows];
+    let mut total_food: u32 = 0;
+    for (x, y, amount) in foods {
+        let column = (x as usize / 2) * columns / world_size.0 as usize;
+        let row = (y as usize / 2) * rows / world_size.1 as usize;
+        buckets[(row * columns + column).min(columns * rows - 1)] += amount as u32;
+        total_food += amount as u32;
+    }
+    let most_food = buckets.iter().copied().max().unwrap_or(0).max(1);
+
+    let mut to_print = String::new();
+    for row in buckets.chunks(columns) {
+        for &food in row {
+            // Any food at all gets at least the first color after black
+            let level = (food as f32 * (HEATMAP_COLORS.len() - 1) as f32 / most_food as f32).ceil()
+                as usize;
+            to_print += HEATMAP_COLORS[level];
+            to_print += "  \x1b[0m";
+        }
+        to_print += "\n";
+    }
+    to_print += &format!(
+        "World {}x{}, {} food in total. Every square is about {}x{} fields, with up to {} food\n",
+        world_size.0,
+        world_size.1,
+        total_food,
+        world_size.0 as usize / columns,
+        world_size.1 as usize / rows,
+        most_food
+    );
+    to_print += "No food ";
+    for color in HEATMAP_COLORS.iter() {
+        to_print += color;
+        to_print += "  \x1b[0m";
     }
+    to_print += " Most food";
+    println!("{}", to_print);
+}
+
+/// Forgets the directions and the last frame of the previous snake
+fn forget_last_snake() {
+    {
+        let mut direction_state = DIRECTION_STATE.lock().unwrap();
+        direction_state.pending = None;
+        direction_state.last_head_position = None;
+        direction_state.moved = None;
+        direction_state.rejected_at = None;
+        direction_state.queued = None;
+    }
+    *LAST_FRAME.lock().unwrap() = None;
+    FADING.lock().unwrap().clear();
+    *SUMMARY.lock().unwrap() = None;
+    *ALIVE_SINCE.lock().unwrap() = Instant::now();
+}
+
+/// A file that all messages from server are appended to, each as the time it was received
+/// in milliseconds since the Unix epoch (8 bytes), its length (2 bytes) and the message itself
+struct FrameDump {
+    file: BufWriter<File>,
+    /// When the buffered messages were last written to the file
+    flushed_at: Instant,
+}
+
+impl FrameDump {
+    /// Appends a message, writing errors are ignored so that they don't get in the way of playing
+    fn write(&mut self, message: &[u8]) {
+        let received_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_millis() as u64);
+        let _ = self.file.write_all(&received_at.to_be_bytes()[..]);
+        let _ = self
+            .file
+            .write_all(&(message.len() as u16).to_be_bytes()[..]);
+        let _ = self.file.write_all(message);
+        if self.flushed_at.elapsed() >= DUMP_FLUSH_INTERVAL {
+            self.flushed_at = Instant::now();
+            let _ = self.file.flush();
+        }
+    }
+}
+
+/// A recording of the terminal in the asciinema v2 format: a JSON header with the terminal size,
+/// then every output as a JSON array of the seconds since the start, "o" and the text
+struct Cast {
+    file: BufWriter<File>,
+    /// When the recording started
+    started_at: Instant,
+    /// When the buffered output was last written to the file
+    flushed_at: Instant,
+}
+
+impl Cast {
+    /// Starts a new recording of a terminal of the given size, replacing the file if it exists
+    fn create(path: &Path, (width, height): (u16, u16)) -> std::io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs());
+        writeln!(
+            file,
+            "{{\"version\": 2, \"width\": {}, \"height\": {}, \"timestamp\": {}}}",
+            width, height, timestamp
+        )?;
+        Ok(Cast {
+            file,
+            started_at: Instant::now(),
+            flushed_at: Instant::now(),
+        })
+    }
+    /// Appends the output, writing errors are ignored so that they don't get in the way of playing
+    fn write(&mut self, output: &str) {
+        let _ = writeln!(
+            self.file,
+            "[{:.6}, \"o\", \"{}\"]",
+            self.started_at.elapsed().as_secs_f64(),
+            json_escape(output)
+        );
+        if self.flushed_at.elapsed() >= DUMP_FLUSH_INTERVAL {
+            self.flushed_at = Instant::now();
+            let _ = self.file.flush();
+        }
+    }
+}
+
+/// Adds the output to the recording, if there is one
+fn record(output: &str) {
+    if let Some(cast) = CAST.lock().unwrap().as_mut() {
+        cast.write(output);
+    }
+}
+
+/// Writes the rest of the recording to the file and stops recording
+fn finish_cast() {
+    if let Some(mut cast) = CAST.lock().unwrap().take() {
+        let _ = cast.file.flush();
+    }
+}
+
+/// Escapes the text to be put inside quotes in JSON, including the escape codes
+fn json_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => escaped += "\\\"",
+            '\\' => escaped += "\\\\",
+            '\n' => escaped += "\\n",
+            '\r' => escaped += "\\r",
+            '\t' => escaped += "\\t",
+            c if (c as u32) < 0x20 || c == '\x7f' => escaped += &format!("\\u{:04x}", c as u32),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Connects to the server and requests to join the game (or to spectate it) in the given room,
+/// or in the main game if it's `None`. Retries with exponential backoff if the connection fails,
+/// and waits for a free slot if the server is full. Returns the stream, my ID, the world size
+/// and whether the X and Y edges of the world are walls if joined successfully
+pub fn join_game(
+    ip: &str,
+    port: u16,
+    socket: Option<&Path>,
+    nickname: &str,
+    retry: RetryOptions,
+    spectate: bool,
+    room: Option<&str>,
+) -> Option<Joined> {
+    let mut delay = retry.base_delay;
+    let mut attempt = 0;
+    while attempt < retry.attempts {
+        attempt += 1;
+        if attempt > 1 {
+            println!(
+                "Retrying in {:.1} seconds... (attempt {}/{})",
+                delay.as_secs_f64(),
+                attempt,
+                retry.attempts
+            );
+            sleep(delay);
+            delay *= 2;
+        }
+
+        let mut stream = match Stream::connect(ip, port, socket) {
+            Ok(stream) => stream,
+            Err(e) => {
+                println!("Couldn't connect to host: {}", e);
+                continue;
+            }
+        };
+
+        // Ask for the room first, the request to join goes there
+        if let Some(room) = room {
+            let mut bytes = vec![MAGIC_NET_JOIN_ROOM];
+            bytes.extend_from_slice(room.as_bytes());
+            send_to_stream(&mut stream, &bytes);
+        }
+
+        // Send my nickname as a request to connect to the game
+        let mut bytes: Vec<u8> = vec![if spectate {
+            MAGIC_NET_REQUEST_TO_SPECTATE
+        } else {
+            MAGIC_NET_REQUEST_TO_PLAY
+        }];
+        bytes.extend_from_slice(nickname.as_bytes());
+        send_to_stream(&mut stream, &bytes);
+
+        // Read the response
+        match read_from_stream(&mut stream, MAX_JOIN_RESPONSE_SIZE) {
+            Err(_) => {
+                println!("Connection lost after requesting to join game");
+                continue;
+            }
+            Ok(bytes) => {
+                if bytes.len() >= 2 && bytes[0] == MAGIC_NET_ERROR && bytes[1] == ERROR_SERVER_FULL
+                {
+                    // Wait for a free slot, this doesn't count as a failed attempt
+                    println!(
+                        "{}, retrying in {} seconds...",
+                        error_text(&bytes),
+                        SERVER_FULL_RETRY_DELAY.as_secs()
+                    );
+                    sleep(SERVER_FULL_RETRY_DELAY);
+                    attempt = 0;
+                    delay = retry.base_delay;
+                    continue;
+                } else if bytes[0] == MAGIC_NET_ERROR {
+                    // It's an error
+                    println!("Error from server: {}", error_text(&bytes));
+                    return None;
+                } else if bytes[0] == MAGIC_NET_JOINED_GAME
+                    && bytes.len() >= 12
+                    && bytes.len() == 12 + 4 * bytes[11] as usize + RULES_LENGTH
+                {
+                    // It's a confirmation that I joined the game, with my ID, the world size,
+                    // which edges of the world are walls, whether fast mode is disabled,
+                    // whether the game is played in rounds, the score needed for fast mode,
+                    // the scoring zones and the rest of the rules
+                    *RULES.lock().unwrap() = rules_summary(&bytes);
+                    *FAST_MODE_DISABLED.lock().unwrap() = bytes[7] & 0b100 != 0;
+                    *LAST_STANDING.lock().unwrap() = bytes[7] & 0b1000 != 0;
+                    *FAST_MIN_SCORE.lock().unwrap() = u16::from_be_bytes([bytes[8], bytes[9]]);
+                    *SCORING_ZONES.lock().unwrap() = (
+                        bytes[10] as u16,
+                        bytes[12..bytes.len() - RULES_LENGTH]
+                            .chunks(4)
+                            .map(|zone| {
+                                (
+                                    u16::from_be_bytes([zone[0], zone[1]]),
+                                    u16::from_be_bytes([zone[2], zone[3]]),
+                                )
+                            })
+                            .collect(),
+                    );
+                    return Some((
+                        stream,
+                        u16::from_be_bytes([bytes[1], bytes[2]]),
+                        (
+                            u16::from_be_bytes([bytes[3], bytes[4]]),
+                            u16::from_be_bytes([bytes[5], bytes[6]]),
+                        ),
+                        (bytes[7] & 0b01 != 0, bytes[7] & 0b10 != 0),
+                    ));
+                } else {
+                    println!("Corrupted message from server: disconnecting.");
+                    return None;
+                }
+            }
+        }
+    }
+    println!("Giving up after {} attempts.", retry.attempts);
+    None
 }
 
 /// Reads all input from user and sends valid directions to server
 pub fn handle_input(
-    stream: Arc<Mutex<Option<TcpStream>>>,
+    stream: Arc<Mutex<Option<Stream>>>,
     mut input: AsyncReader,
     exit: Arc<AtomicBool>,
     altscreen_guard: AlternateScreen,
+    spectating: bool,
+    relative_controls: bool,
 ) {
+    // Spectators move the camera instead of the snake
+    let turn = |s: &mut Stream, direction: u8| {
+        if spectating {
+            move_camera(s, direction);
+        } else {
+            send_direction(s, direction);
+        }
+    };
+    // With relative controls, left and right turn the snake relative to where it's heading,
+    // and up and down do nothing. The camera always moves the usual way
+    let relative = relative_controls && !spectating;
     loop {
         if let Some(event) = input.next() {
+            *LAST_INPUT.lock().unwrap() = Instant::now();
             match event {
                 // ctrl-c or Q to quit the game
                 InputEvent::Keyboard(KeyEvent::Ctrl('c'))
@@ -205,38 +1226,60 @@ pub fn handle_input(
                         std::io::stdout().flush().unwrap();
                         // Send message to server
                         send_to_stream(s, &[MAGIC_NET_EXIT]);
+                        print_summary();
+                        finish_cast();
                         std::process::exit(0);
                     }
                 }
-                // A or Left arrow - move left
+                // A or Left arrow - move left (or turn left)
                 InputEvent::Keyboard(KeyEvent::Char('a'))
                 | InputEvent::Keyboard(KeyEvent::Left) => {
                     if let Some(s) = stream.lock().unwrap().as_mut() {
-                        send_direction(s, 0);
+                        if relative {
+                            turn_relative(s, false);
+                        } else {
+                            turn(s, 0);
+                        }
                     }
                 }
                 // S or Down arrow - move down
                 InputEvent::Keyboard(KeyEvent::Char('s'))
                 | InputEvent::Keyboard(KeyEvent::Down) => {
                     if let Some(s) = stream.lock().unwrap().as_mut() {
-                        send_direction(s, 3);
+                        if !relative {
+                            turn(s, 3);
+                        }
                     }
                 }
-                // D or Right arrow - move right
+                // D or Right arrow - move right (or turn right)
                 InputEvent::Keyboard(KeyEvent::Char('d'))
                 | InputEvent::Keyboard(KeyEvent::Right) => {
                     if let Some(s) = stream.lock().unwrap().as_mut() {
-                        send_direction(s, 2);
+                        if relative {
+                            turn_relative(s, true);
+                        } else {
+                            turn(s, 2);
+                        }
                     }
                 }
                 // W or Up arrow - move up
                 InputEvent::Keyboard(KeyEvent::Char('w')) | InputEvent::Keyboard(KeyEvent::Up) => {
                     if let Some(s) = stream.lock().unwrap().as_mut() {
-                        send_direction(s, 1);
+                        if !relative {
+                            turn(s, 1);
+                        }
                     }
                 }
-                // Space to toggle fast mode
-                InputEvent::Keyboard(KeyEvent::Char(' ')) => {
+                // Space to follow the next snake, when spectating
+                InputEvent::Keyboard(KeyEvent::Char(' ')) if spectating => {
+                    if let Some(s) = stream.lock().unwrap().as_mut() {
+                        send_to_stream(s, &[MAGIC_NET_FOLLOW_NEXT_SNAKE]);
+                    }
+                }
+                // Space to toggle fast mode, unless the server disabled it
+                InputEvent::Keyboard(KeyEvent::Char(' '))
+                    if !*FAST_MODE_DISABLED.lock().unwrap() =>
+                {
                     if let Some(s) = stream.lock().unwrap().as_mut() {
                         toggle_fast_mode(s);
                     }
@@ -244,13 +1287,56 @@ pub fn handle_input(
                 // L to toggle leaderboard
                 InputEvent::Keyboard(KeyEvent::Char('l')) => {
                     // Toggle
-                    let next = (*SHOW_LEADERBOARD.lock().unwrap() as u8 + 1) % 3;
+                    let modes = if SCORING_ZONES.lock().unwrap().1.is_empty() {
+                        4
+                    } else {
+                        5
+                    };
+                    let next = (*SHOW_LEADERBOARD.lock().unwrap() as u8 + 1) % modes;
                     *SHOW_LEADERBOARD.lock().unwrap() = match next {
                         0 => ShowLeaderboard::Hide,
                         1 => ShowLeaderboard::ByScore,
-                        _ => ShowLeaderboard::ByKills,
+                        2 => ShowLeaderboard::ByKills,
+                        3 => ShowLeaderboard::ByLength,
+                        _ => ShowLeaderboard::ByBanked,
+                    }
+                }
+                // N to cycle through showing all nicknames, only the nearby ones and none
+                InputEvent::Keyboard(KeyEvent::Char('n')) => {
+                    let next = (*SHOW_NICKNAMES.lock().unwrap() as u8 + 1) % 3;
+                    *SHOW_NICKNAMES.lock().unwrap() = match next {
+                        0 => ShowNicknames::All,
+                        1 => ShowNicknames::Nearby,
+                        _ => ShowNicknames::Hide,
                     }
                 }
+                // I to toggle the scores of other snakes
+                InputEvent::Keyboard(KeyEvent::Char('i')) => {
+                    let mut show_score_labels = SHOW_SCORE_LABELS.lock().unwrap();
+                    *show_score_labels = !*show_score_labels;
+                }
+                // E to toggle the edge markers
+                InputEvent::Keyboard(KeyEvent::Char('e')) => {
+                    let mut show_edges = SHOW_EDGES.lock().unwrap();
+                    *show_edges = !*show_edges;
+                }
+                // G to toggle the coordinate grid
+                InputEvent::Keyboard(KeyEvent::Char('g')) => {
+                    let mut show_grid = SHOW_GRID.lock().unwrap();
+                    *show_grid = !*show_grid;
+                }
+                // ? to toggle the summary of the rules
+                InputEvent::Keyboard(KeyEvent::Char('?')) => {
+                    let mut show_rules = SHOW_RULES.lock().unwrap();
+                    *show_rules = !*show_rules;
+                }
+                // F to toggle drawing food as symbols
+                InputEvent::Keyboard(KeyEvent::Char('f')) => {
+                    let mut food_numbers = FOOD_NUMBERS.lock().unwrap();
+                    *food_numbers = !*food_numbers;
+                }
+                // R to clear the screen and draw the next frame from scratch, if it got garbled
+                InputEvent::Keyboard(KeyEvent::Char('r')) => request_redraw(),
                 _ => (),
             }
         }
@@ -265,42 +1351,220 @@ pub fn handle_input(
             }
             return;
         }
+        if relative {
+            if let Some(s) = stream.lock().unwrap().as_mut() {
+                send_queued_turn(s);
+            }
+        }
         sleep(Duration::from_millis(1));
     }
 }
 
 /// Sends a new direction to server
-pub fn send_direction(mut stream: &mut TcpStream, direction: u8) {
+pub fn send_direction(mut stream: &mut Stream, direction: u8) {
+    let mut direction_state = DIRECTION_STATE.lock().unwrap();
+    // Don't send the direction the snake is already going to move to,
+    // for example when the key is being held and repeated
+    if direction_state.pending.or(direction_state.moved) == Some(direction) {
+        return;
+    }
     let mut bytes: Vec<u8> = vec![MAGIC_NET_CHANGE_DIRECTION];
     bytes.push(direction);
     send_to_stream(&mut stream, &bytes);
+    direction_state.pending = Some(direction);
+}
+
+/// Figures out which way my snake moved since the last frame
+/// and checks if the pending direction change was applied or rejected
+pub fn update_direction_state(my_position: (u16, u16), world_size: (u16, u16)) {
+    let mut direction_state = DIRECTION_STATE.lock().unwrap();
+    let last_position = match direction_state.last_head_position.replace(my_position) {
+        Some(position) => position,
+        None => return,
+    };
+
+    // The movement vector, taking the world wrapping into account
+    direction_state.moved = match (
+        wrap_delta(my_position.0, last_position.0, world_size.0).signum(),
+        wrap_delta(my_position.1, last_position.1, world_size.1).signum(),
+    ) {
+        (-1, 0) => Some(0),
+        (0, -1) => Some(1),
+        (1, 0) => Some(2),
+        (0, 1) => Some(3),
+        _ => return,
+    };
+    let moved = direction_state.moved.unwrap();
+
+    if let Some(pending) = direction_state.pending {
+        if pending == moved {
+            // Applied
+            direction_state.pending = None;
+        } else if (pending + 2) % 4 == moved {
+            // Snakes can't do 180 degree turns, so the server ignored it,
+            // and the turn queued after it makes no sense anymore
+            direction_state.pending = None;
+            direction_state.queued = None;
+            direction_state.rejected_at = Some(Instant::now());
+        }
+    }
+}
+
+/// Sends a message to server asking to move the camera to the given direction, when spectating
+pub fn move_camera(stream: &mut Stream, direction: u8) {
+    send_to_stream(stream, &[MAGIC_NET_MOVE_CAMERA, direction]);
+}
+
+/// Turns my snake 90 degrees clockwise or counterclockwise from the direction it's heading to,
+/// counting the turn that wasn't applied yet
+pub fn turn_relative(stream: &mut Stream, clockwise: bool) {
+    let mut direction_state = DIRECTION_STATE.lock().unwrap();
+    let heading = match direction_state
+        .queued
+        .or(direction_state.pending)
+        .or(direction_state.moved)
+    {
+        Some(heading) => heading,
+        None => return,
+    };
+    // The directions go clockwise: left, up, right, down
+    let direction = (heading + if clockwise { 1 } else { 3 }) % 4;
+    if direction_state.pending.is_some() {
+        // Wait until the server applies the turn before this one
+        direction_state.queued = Some(direction);
+    } else {
+        drop(direction_state);
+        send_direction(stream, direction);
+    }
+}
+
+/// Sends the relative turn that waited for the one before it, once that one is applied
+pub fn send_queued_turn(stream: &mut Stream) {
+    let mut direction_state = DIRECTION_STATE.lock().unwrap();
+    if direction_state.pending.is_none() {
+        if let Some(direction) = direction_state.queued.take() {
+            drop(direction_state);
+            send_direction(stream, direction);
+        }
+    }
 }
 
 /// Sends a message to server asking to toggle fast mode
-pub fn toggle_fast_mode(mut stream: &mut TcpStream) {
+pub fn toggle_fast_mode(mut stream: &mut Stream) {
     let bytes = vec![MAGIC_NET_TOGGLE_FAST];
     send_to_stream(&mut stream, &bytes);
 }
 
 /// Handles data sent by server and if the data is new game data, draws the new frame to user
-pub fn handle_server_message(data: Vec<u8>, my_id: u16, world_size: (u16, u16)) -> Exit {
+pub fn handle_server_message(
+    data: Vec<u8>,
+    my_id: u16,
+    world_size: (u16, u16),
+    walls: (bool, bool),
+    smooth: bool,
+) -> Exit {
     // Messages starting with:
     //  - \x03 mean that I died
     //  - \x04 mean that it's the game data
+    //  - \x07 mean that someone killed someone
+    //  - \x11 mean how long until sudden death
+    //  - \x12 mean that the game was paused or resumed
+    //  - \x13 mean that I got another kill in a row
+    //  - \x17 mean that someone won the round
+    //  - \x18 mean that the server announced something to everyone
+    //  - \x19 mean where the famine is, or that it's over
     if data.len() == 1 && data[0] == MAGIC_NET_DEATH {
         // Exit
         return Exit::Death;
+    } else if data.len() == 2 && data[0] == MAGIC_NET_DEATH && data[1] == 1 {
+        // Wait for the new snake
+        return Exit::Respawning;
+    } else if data.len() == 2 && data[0] == MAGIC_NET_PAUSED {
+        *PAUSED.lock().unwrap() = data[1] != 0;
+    } else if data.len() == 5 && data[0] == MAGIC_NET_KILL_STREAK {
+        *KILL_STREAK.lock().unwrap() = Some((
+            Instant::now(),
+            u16::from_be_bytes([data[1], data[2]]),
+            u16::from_be_bytes([data[3], data[4]]),
+        ));
+    } else if data[0] == MAGIC_NET_VICTORY {
+        // The winner's nickname follows, nobody won if there's none.
+        // If I won, my snake is still in the last frame
+        let winner = String::from_utf8_lossy(&data[1..]).into_owned();
+        let me = LAST_FRAME
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|frame| frame.snakes.get(&my_id).map(|snake| snake.nickname.clone()));
+        let text = if winner.is_empty() {
+            "Nobody won the round".to_string()
+        } else if me.as_ref() == Some(&winner) {
+            "You won the round!".to_string()
+        } else {
+            format!("{} won the round", winner)
+        };
+        *VICTORY.lock().unwrap() = Some((Instant::now(), text));
+    } else if data[0] == MAGIC_NET_ANNOUNCE {
+        let text = String::from_utf8_lossy(&data[1..]).into_owned();
+        *ANNOUNCEMENT.lock().unwrap() = Some((Instant::now(), text));
+    } else if data.len() == 3 && data[0] == MAGIC_NET_SUDDEN_DEATH {
+        *SUDDEN_DEATH.lock().unwrap() = Some(u16::from_be_bytes([data[1], data[2]]));
+    } else if data.len() == 1 && data[0] == MAGIC_NET_FAMINE {
+        *FAMINE.lock().unwrap() = None;
+    } else if data.len() == 11 && data[0] == MAGIC_NET_FAMINE {
+        *FAMINE.lock().unwrap() = Some((
+            (
+                u16::from_be_bytes([data[1], data[2]]),
+                u16::from_be_bytes([data[3], data[4]]),
+            ),
+            (
+                u16::from_be_bytes([data[5], data[6]]),
+                u16::from_be_bytes([data[7], data[8]]),
+            ),
+            u16::from_be_bytes([data[9], data[10]]),
+        ));
+    } else if data[0] == MAGIC_NET_KILLFEED {
+        let mut i = 1; // next byte to read
+
+        // A message that is cut short is ignored
+        let mut nicknames = Vec::with_capacity(2);
+        for _nickname in 0..2 {
+            let nickname_length = match data.get(i) {
+                Some(&length) => length as usize,
+                None => return Exit::Continue,
+            };
+            i += 1;
+            let nickname = match data.get(i..i + nickname_length) {
+                Some(nickname) => nickname,
+                None => return Exit::Continue,
+            };
+            nicknames.push(String::from_utf8_lossy(nickname).into_owned());
+            i += nickname_length;
+        }
+        let victim = nicknames.pop().unwrap();
+        let killer = nicknames.pop().unwrap();
+
+        let mut kill_feed = KILL_FEED.lock().unwrap();
+        kill_feed.push_back((Instant::now(), killer, victim));
+        if kill_feed.len() > KILL_FEED_LENGTH {
+            kill_feed.pop_front();
+        }
     } else if data[0] == MAGIC_NET_GAME_DATA {
         // Parse the data
         let mut i = 1; // next byte to read
 
-        // First 2 bytes are the amount of snakes in total
+        // The first byte is the game speed right now, it can change during the game
+        *GAME_SPEED.lock().unwrap() = data[i];
+        i += 1;
+
+        // Then 2 bytes are the amount of snakes in total
         let snake_amount = u16::from_be_bytes([data[i], data[i + 1]]);
         i += 2;
-        // A hashmap pointing snake ID to it's nickname, score and amount of kills
-        let mut snakes: HashMap<u16, (String, u16, u16, bool)> = HashMap::new();
+        // A hashmap pointing snake ID to everything about it but the body
+        let mut snakes: HashMap<u16, SnakeInfo> = HashMap::new();
         // A hashmap mapping head positions to their owner-snakes IDs
         let mut head_positions: HashMap<(u16, u16), u16> = HashMap::new();
+        let mut phasing: HashSet<u16> = HashSet::new();
         for _snake in 0..snake_amount {
             let id = u16::from_be_bytes([data[i], data[i + 1]]);
             i += 2;
@@ -321,78 +1585,377 @@ pub fn handle_server_message(data: Vec<u8>, my_id: u16, world_size: (u16, u16))
             );
             head_positions.insert(head_pos, id);
             i += 4;
-            let fast_mode = u8::from_be_bytes([data[i]]) == 1;
+            let flags = u8::from_be_bytes([data[i]]);
+            let fast_mode = flags & 1 != 0;
+            let has_magnet = flags & 2 != 0;
+            let protected = flags & 4 != 0;
+            let bot = flags & 8 != 0;
+            if flags & 16 != 0 {
+                phasing.insert(id);
+            }
             i += 1;
-            snakes.insert(id, (nickname, score, kills, fast_mode));
+            let length = u16::from_be_bytes([data[i], data[i + 1]]);
+            i += 2;
+            let banked = u16::from_be_bytes([data[i], data[i + 1]]);
+            i += 2;
+            snakes.insert(
+                id,
+                SnakeInfo {
+                    nickname,
+                    score,
+                    kills,
+                    fast_mode,
+                    length,
+                    has_magnet,
+                    protected,
+                    banked,
+                    bot,
+                },
+            );
+        }
+
+        // Foods
+        let foods_amount = u16::from_be_bytes([data[i], data[i + 1]]);
+        i += 2;
+        let mut foods: HashMap<(i8, i8), u8> = HashMap::new();
+        for _food in 0..foods_amount {
+            // The amount of food (data[i + 2]) isn't drawn, only the tier of the whole field
+            foods.insert(
+                (
+                    i8::from_be_bytes([data[i]]), // X pos of food relative to my head
+                    i8::from_be_bytes([data[i + 1]]),
+                ), // Y pos of food relative to my head
+                u8::from_be_bytes([data[i + 3]]),
+            ); // tier of the field
+            i += 4;
+        }
+
+        // Snake parts
+        // (these include a few fields around the view too, so they are not all drawn)
+        let snake_parts_amount = u16::from_be_bytes([data[i], data[i + 1]]);
+        i += 2;
+        let mut snake_parts: HashMap<(i8, i8), u16> = HashMap::new();
+        let mut part_depths: HashMap<(i8, i8), u16> = HashMap::new();
+        for _snake_part in 0..snake_parts_amount {
+            let position = (
+                i8::from_be_bytes([data[i]]),     // X pos of part relative to my head
+                i8::from_be_bytes([data[i + 1]]), // Y pos of part relative to my head
+            );
+            snake_parts.insert(position, u16::from_be_bytes([data[i + 2], data[i + 3]])); // snake ID
+            part_depths.insert(position, u16::from_be_bytes([data[i + 4], data[i + 5]])); // how far from the head
+            i += 6;
+        }
+
+        let my_position = (
+            u16::from_be_bytes([data[i], data[i + 1]]),
+            u16::from_be_bytes([data[i + 2], data[i + 3]]),
+        );
+        i += 4;
+
+        // Food magnets
+        let magnets_amount = u16::from_be_bytes([data[i], data[i + 1]]);
+        i += 2;
+        let mut magnets: HashSet<(i8, i8)> = HashSet::new();
+        for _magnet in 0..magnets_amount {
+            magnets.insert((
+                i8::from_be_bytes([data[i]]),     // X pos of magnet relative to my head
+                i8::from_be_bytes([data[i + 1]]), // Y pos of magnet relative to my head
+            ));
+            i += 2;
+        }
+
+        // Territory
+        let territory_amount = u16::from_be_bytes([data[i], data[i + 1]]);
+        i += 2;
+        let mut territory: HashMap<(i8, i8), u16> = HashMap::new();
+        for _field in 0..territory_amount {
+            territory.insert(
+                (
+                    i8::from_be_bytes([data[i]]),     // X pos of field relative to my head
+                    i8::from_be_bytes([data[i + 1]]), // Y pos of field relative to my head
+                ),
+                u16::from_be_bytes([data[i + 2], data[i + 3]]), // ID of the owner
+            );
+            i += 4;
+        }
+
+        // Phase power-ups
+        let phases_amount = u16::from_be_bytes([data[i], data[i + 1]]);
+        i += 2;
+        let mut phases: HashSet<(i8, i8)> = HashSet::new();
+        for _phase in 0..phases_amount {
+            phases.insert((
+                i8::from_be_bytes([data[i]]), // X pos of phase power-up relative to my head
+                i8::from_be_bytes([data[i + 1]]), // Y pos of phase power-up relative to my head
+            ));
+            i += 2;
+        }
+
+        update_direction_state(my_position, world_size);
+
+        if let Some(snake) = snakes.get(&my_id) {
+            *SUMMARY.lock().unwrap() = Some(Summary {
+                score: snake.score,
+                kills: snake.kills,
+                place: (get_place_by_score(&snakes, my_id), snakes.len()),
+                survived: ALIVE_SINCE.lock().unwrap().elapsed(),
+            });
+        }
+
+        let frame = Frame {
+            snakes,
+            foods,
+            snake_parts,
+            part_depths,
+            my_position,
+            head_positions,
+            magnets,
+            territory,
+            phases,
+            phasing,
+        };
+
+        // Remember it in case the next frame has to be predicted
+        let previous_frame = LAST_FRAME.lock().unwrap().replace(frame.clone());
+
+        if let Some(previous_frame) = &previous_frame {
+            update_fading(previous_frame, &frame, world_size);
+            ring_bell(my_id, previous_frame, &frame);
+        }
+
+        // Don't draw more often than the player wants to
+        let min_frame_interval = *MIN_FRAME_INTERVAL.lock().unwrap();
+        if let (Some(interval), Some(last_draw)) = (min_frame_interval, *LAST_DRAW.lock().unwrap())
+        {
+            if last_draw.elapsed() < interval {
+                return Exit::Throttled(last_draw + interval);
+            }
+        }
+
+        // OK, all the data is read and parsed - time to draw the frame
+        // (or something in between it and the previous one, to make the movement smoother)
+        if smooth {
+            if let Some(blended) = previous_frame
+                .and_then(|previous_frame| blend_frames(&previous_frame, &frame, world_size))
+            {
+                draw_frame(my_id, &blended, world_size, walls);
+                return Exit::Interpolated;
+            }
+        }
+        draw_frame(my_id, &frame, world_size, walls);
+    }
+    Exit::Continue
+}
+
+/// Blends two consecutive frames into one in between them: the snakes already have their new heads,
+/// but still have their old tails. Returns `None` if my snake moved too far for it to look right
+fn blend_frames(previous: &Frame, next: &Frame, world_size: (u16, u16)) -> Option<Frame> {
+    let dx = wrap_delta(next.my_position.0, previous.my_position.0, world_size.0);
+    let dy = wrap_delta(next.my_position.1, previous.my_position.1, world_size.1);
+    // Snakes in fast mode move 2 fields at once
+    if dx.abs() + dy.abs() > 2 {
+        return None;
+    }
+
+    // Everything is drawn relative to my head, so move the old parts the other way
+    let mut blended = next.clone();
+    for (&(x, y), &id) in &previous.snake_parts {
+        // The snakes that died are already gone
+        if next.snakes.contains_key(&id) {
+            let position = (x - dx as i8, y - dy as i8);
+            if let Entry::Vacant(part) = blended.snake_parts.entry(position) {
+                part.insert(id);
+                // The old tails are a part further from the heads by now
+                if let Some(depth) = previous.part_depths.get(&(x, y)) {
+                    blended.part_depths.insert(position, depth + 1);
+                }
+            }
+        }
+    }
+    Some(blended)
+}
+
+/// Ages the bodies of the dead snakes by one frame, and starts fading the bodies of the snakes
+/// that were in the previous frame but are gone now
+fn update_fading(previous: &Frame, next: &Frame, world_size: (u16, u16)) {
+    let mut fading = FADING.lock().unwrap();
+    for (frames_left, _) in fading.iter_mut() {
+        *frames_left -= 1;
+    }
+    fading.retain(|(frames_left, _)| *frames_left > 0);
+
+    for &id in previous.snakes.keys() {
+        if next.snakes.contains_key(&id) {
+            continue;
         }
-
-        // Foods
-        let foods_amount = u16::from_be_bytes([data[i], data[i + 1]]);
-        i += 2;
-        let mut foods: HashMap<(i8, i8), u8> = HashMap::new();
-        for _food in 0..foods_amount {
-            foods.insert(
+        // Remember the fields in the world, since everything on the screen moves with my head
+        let fields: Vec<(u16, u16)> = previous
+            .snake_parts
+            .iter()
+            .filter(|(_, &part_id)| part_id == id)
+            .map(|(&(x, y), _)| {
                 (
-                    i8::from_be_bytes([data[i]]), // X pos of food relative to my head
-                    i8::from_be_bytes([data[i + 1]]),
-                ), // Y pos of food relative to my head
-                u8::from_be_bytes([data[i + 2]]),
-            ); // amount of food there
-            i += 3;
+                    (previous.my_position.0 as i32 + x as i32).rem_euclid(world_size.0 as i32)
+                        as u16,
+                    (previous.my_position.1 as i32 + y as i32).rem_euclid(world_size.1 as i32)
+                        as u16,
+                )
+            })
+            .collect();
+        if fields.is_empty() {
+            continue;
         }
+        fading.push_back((FADE_FRAMES, fields));
+        if fading.len() > MAX_FADING_SNAKES {
+            fading.pop_front();
+        }
+    }
+}
 
-        // Snake parts
-        let snake_parts_amount = u16::from_be_bytes([data[i], data[i + 1]]);
-        i += 2;
-        let mut snake_parts: HashMap<(i8, i8), u16> = HashMap::new();
-        for _snake_part in 0..snake_parts_amount {
-            snake_parts.insert(
-                (
-                    i8::from_be_bytes([data[i]]), // X pos of part relative to my head
-                    i8::from_be_bytes([data[i + 1]]),
-                ), // Y pos of part relative to my head
-                u16::from_be_bytes([data[i + 2], data[i + 3]]),
-            ); // snake ID
-            i += 4;
+/// Draws the last frame received from server again
+fn draw_last_frame(my_id: u16, world_size: (u16, u16), walls: (bool, bool)) {
+    if let Some(frame) = LAST_FRAME.lock().unwrap().as_ref() {
+        draw_frame(my_id, frame, world_size, walls);
+    }
+}
+
+/// Draws a whole frame
+fn draw_frame(my_id: u16, frame: &Frame, world_size: (u16, u16), walls: (bool, bool)) {
+    *LAST_DRAW.lock().unwrap() = Some(Instant::now());
+    draw(my_id, frame, world_size, walls);
+}
+
+/// Moves my snake ahead in the last frame, the way the server most likely will, and draws it.
+/// Only my snake is moved, everything else stays as it was
+pub fn predict_frame(my_id: u16, world_size: (u16, u16), walls: (bool, bool)) {
+    // Nothing moves while the game is paused
+    if *PAUSED.lock().unwrap() {
+        return;
+    }
+    let mut last_frame = LAST_FRAME.lock().unwrap();
+    let frame = match last_frame.as_mut() {
+        Some(frame) => frame,
+        None => return,
+    };
+
+    // The server will turn to the pending direction, unless it's a 180 degree turn
+    let direction = {
+        let direction_state = DIRECTION_STATE.lock().unwrap();
+        match (direction_state.pending, direction_state.moved) {
+            (Some(pending), Some(moved)) if (pending + 2) % 4 != moved => pending,
+            (_, Some(moved)) => moved,
+            _ => return,
         }
+    };
+    let (dx, dy): (i8, i8) = match direction {
+        0 => (-1, 0),
+        1 => (0, -1),
+        2 => (1, 0),
+        _ => (0, 1),
+    };
 
-        let my_position = (
-            u16::from_be_bytes([data[i], data[i + 1]]),
-            u16::from_be_bytes([data[i + 2], data[i + 3]]),
-        );
+    // Snakes in fast mode move twice
+    let moves = match frame.snakes.get(&my_id) {
+        Some(snake) if snake.fast_mode => 2,
+        Some(_) => 1,
+        None => return,
+    };
+    for _ in 0..moves {
+        // Don't predict crashes, let the server decide
+        if frame.snake_parts.contains_key(&(dx, dy)) {
+            return;
+        }
 
-        // // OK, all the data is read and parsed - time to draw the frame
-        draw(
-            my_id,
-            snakes,
-            foods,
-            snake_parts,
-            my_position,
-            world_size,
-            head_positions,
+        // Everything is drawn relative to my head, so move everything else the other way.
+        // My parts are one further from my head after it moves
+        let snake_parts = &frame.snake_parts;
+        frame.part_depths = frame
+            .part_depths
+            .drain()
+            .map(|((x, y), depth)| {
+                let mine = snake_parts.get(&(x, y)) == Some(&my_id);
+                ((x - dx, y - dy), depth + mine as u16)
+            })
+            .collect();
+        frame.part_depths.insert((0, 0), 0);
+        frame.snake_parts = frame
+            .snake_parts
+            .drain()
+            .map(|((x, y), id)| ((x - dx, y - dy), id))
+            .collect();
+        frame.snake_parts.insert((0, 0), my_id);
+        frame.foods = frame
+            .foods
+            .drain()
+            .map(|((x, y), amount)| ((x - 2 * dx, y - 2 * dy), amount))
+            .collect();
+        frame.magnets = frame
+            .magnets
+            .drain()
+            .map(|(x, y)| (x - dx, y - dy))
+            .collect();
+        frame.phases = frame
+            .phases
+            .drain()
+            .map(|(x, y)| (x - dx, y - dy))
+            .collect();
+        frame.territory = frame
+            .territory
+            .drain()
+            .map(|((x, y), id)| ((x - dx, y - dy), id))
+            .collect();
+
+        frame.my_position = (
+            ((frame.my_position.0 as i32 + dx as i32 + world_size.0 as i32) % world_size.0 as i32)
+                as u16,
+            ((frame.my_position.1 as i32 + dy as i32 + world_size.1 as i32) % world_size.1 as i32)
+                as u16,
         );
+        frame.head_positions.retain(|_pos, id| *id != my_id);
+        frame.head_positions.insert(frame.my_position, my_id);
+    }
+
+    draw_frame(my_id, frame, world_size, walls);
+}
+
+/// Returns the size of the terminal, or the default size if it can't be fetched
+pub fn terminal_size() -> (u16, u16) {
+    match term_size::dimensions() {
+        Some((w, h)) => (w as u16, h as u16),
+        None => DEFAULT_TERMINAL_SIZE,
     }
-    Exit::Continue
 }
 
 /// Draws the new frame
-pub fn draw(
-    my_id: u16,
-    snakes_info: HashMap<u16, (String, u16, u16, bool)>,
-    foods: HashMap<(i8, i8), u8>,
-    snake_parts: HashMap<(i8, i8), u16>,
-    my_pos: (u16, u16),
-    world_size: (u16, u16),
-    head_positions: HashMap<(u16, u16), u16>,
-) {
-    let mut to_print = String::new();
-    // First - move the cursor to the top left corner of the terminal
-    to_print += "\x1b[H";
+fn draw(my_id: u16, frame: &Frame, world_size: (u16, u16), walls: (bool, bool)) {
+    let Frame {
+        snakes: snakes_info,
+        foods,
+        snake_parts,
+        part_depths,
+        head_positions,
+        magnets,
+        territory,
+        phases,
+        phasing,
+        ..
+    } = frame;
+    let my_pos = frame.my_position;
+    let mut to_print = frame_start();
 
     // Get terminal size
-    let mut real_terminal_size = (98, 30); // default if couldnt fetch
-    if let Some((w, h)) = term_size::dimensions() {
-        real_terminal_size = (w as u16, h as u16);
+    let real_terminal_size = terminal_size();
+
+    // Don't even try to fit the frame in a terminal that's too small
+    if real_terminal_size.0 < MIN_TERMINAL_SIZE.0 || real_terminal_size.1 < MIN_TERMINAL_SIZE.1 {
+        let output = format!(
+            "\x1b[2J\x1b[Hterminal too small ({}x{}, at least {}x{} needed)",
+            real_terminal_size.0, real_terminal_size.1, MIN_TERMINAL_SIZE.0, MIN_TERMINAL_SIZE.1
+        );
+        print!("{}", output);
+        record(&output);
+        std::io::stdout().flush().unwrap();
+        return;
     }
 
     // Get the dimensions of frame that would fit in this terminal
@@ -406,18 +1969,98 @@ pub fn draw(
 
     let right_side_padding = &" ".repeat(real_terminal_size.0 as usize - width.clone().count() * 2);
 
+    let show_edges = *SHOW_EDGES.lock().unwrap();
+    let food_numbers = *FOOD_NUMBERS.lock().unwrap();
+    let show_grid = *SHOW_GRID.lock().unwrap();
+    let ascii = *ASCII.lock().unwrap();
+
+    // Whether my head is close to the walls on each axis, spectators don't need the warning
+    let near_wall = |wall: bool, position: u16, size: u16| {
+        my_id != 0
+            && wall
+            && (position < WALL_WARNING_DISTANCE || position >= size - WALL_WARNING_DISTANCE)
+    };
+    let near_wall_x = near_wall(walls.0, my_pos.0, world_size.0);
+    let near_wall_y = near_wall(walls.1, my_pos.1, world_size.1);
+
+    // The fields of the dead snakes' bodies and how much they faded, the most recent death wins
+    let mut fading_fields: HashMap<(u16, u16), u8> = HashMap::new();
+    for (frames_left, fields) in FADING.lock().unwrap().iter() {
+        for &field in fields {
+            fading_fields.insert(field, *frames_left);
+        }
+    }
+
+    // Scoring zones don't move, so check them against the position in the world
+    let (zone_size, zones) = SCORING_ZONES.lock().unwrap().clone();
+    let in_zone = |x: u16, y: u16| {
+        zones
+            .iter()
+            .any(|&(zx, zy)| x >= zx && x < zx + zone_size && y >= zy && y < zy + zone_size)
+    };
+    // And so doesn't the famine
+    let famine = *FAMINE.lock().unwrap();
+    let in_famine = |x: u16, y: u16| match famine {
+        Some(((fx, fy), (fw, fh), _)) => x >= fx && x < fx + fw && y >= fy && y < fy + fh,
+        None => false,
+    };
+
+    let gradient = *GRADIENT.lock().unwrap();
+
     // Iterate through all fields in the constructed ranges and check if there's anything there
     for y in height.clone() {
         for x in width.clone() {
-            if x < -24 || x > 24 || y < -14 || y > 14 {
-                to_print += "  ";
+            if !(-24..=24).contains(&x) || !(-14..=14).contains(&y) {
+                // Only the snakes are sent in the margin around the view, so that they can
+                // be seen coming if the terminal is big enough. They're dimmed since nothing
+                // else is known about those fields
+                match snake_parts.get(&(x, y)) {
+                    Some(&id) if snakes_info.contains_key(&id) => {
+                        to_print += SNAKE_COLORS[(id % 9) as usize];
+                        to_print += "\x1b[2m[]\x1b[0m";
+                    }
+                    _ => to_print += "  ",
+                }
+                continue;
+            }
+            // Check if the field is behind a wall
+            let world_x = my_pos.0 as i32 + x as i32;
+            let world_y = my_pos.1 as i32 + y as i32;
+            let behind_wall_x = walls.0 && (world_x < 0 || world_x >= world_size.0 as i32);
+            let behind_wall_y = walls.1 && (world_y < 0 || world_y >= world_size.1 as i32);
+            if behind_wall_x || behind_wall_y {
+                // Walls turn red when my head is getting close to them
+                if (behind_wall_x && near_wall_x) || (behind_wall_y && near_wall_y) {
+                    to_print += "\x1b[41m  \x1b[0m";
+                } else {
+                    to_print += "\x1b[100m  \x1b[0m";
+                }
+                continue;
+            }
+            // Walls of the map are sent as snake parts, but they don't belong to any snake
+            if snake_parts.get(&(x, y)) == Some(&WALL_ID) {
+                to_print += "\x1b[100m  \x1b[0m";
                 continue;
             }
             if snake_parts.contains_key(&(x, y)) {
                 // Get the color
                 to_print += SNAKE_COLORS[(snake_parts[&(x, y)] % 9) as usize];
+                let snake_info = &snakes_info[&snake_parts[&(x, y)]];
+                if let (true, Some(&depth)) = (gradient, part_depths.get(&(x, y))) {
+                    to_print += &shade(snake_parts[&(x, y)], depth, snake_info.length);
+                }
+                // Freshly spawned snakes that can't crash yet shimmer
+                if snake_info.protected {
+                    to_print += "\x1b[5m";
+                }
+                // And phasing snakes are ghostly
+                let phasing = phasing.contains(&snake_parts[&(x, y)]);
+                if phasing {
+                    to_print += "\x1b[2m";
+                }
                 match (
-                    snakes_info[&snake_parts[&(x, y)]].3,
+                    snake_info.fast_mode,
+                    snake_info.has_magnet,
                     head_positions.contains_key(&(
                         ((x as i32 + my_pos.0 as i32 + world_size.0 as i32) % world_size.0 as i32)
                             as u16,
@@ -425,41 +2068,87 @@ pub fn draw(
                             as u16,
                     )),
                 ) {
-                    (_, true) => {
-                        to_print += "φφ"; // Eyes/Head
+                    (_, true, true) => {
+                        to_print += if ascii { "@@" } else { "ΘΘ" }; // Head of a snake with a food magnet
+                    }
+                    (_, _, true) => {
+                        to_print += if ascii { "OO" } else { "φφ" }; // Eyes/Head
+                    }
+                    _ if phasing => {
+                        to_print += if ascii { "::" } else { "░░" }; // phasing snake
                     }
-                    (false, _) => {
+                    (false, _, _) => {
                         to_print += "[]"; // snake moving at normal speed
                     }
-                    (true, _) => {
-                        to_print += "╬╬"; // snake in fast mode
+                    (true, _, _) => {
+                        to_print += if ascii { "##" } else { "╬╬" }; // snake in fast mode
                     }
                 };
 
                 to_print += "\x1b[0m"; // reset colors
+            } else if magnets.contains(&(x, y)) {
+                to_print += "\x1b[1;95m<>\x1b[0m"; // Food magnet
+            } else if phases.contains(&(x, y)) {
+                to_print += "\x1b[1;96m()\x1b[0m"; // Phase power-up
             } else {
+                // Mark the fields where the world wraps around, so it's clear where the edges are
+                let on_seam = show_edges
+                    && ((!walls.0 && world_x.rem_euclid(world_size.0 as i32) == 0)
+                        || (!walls.1 && world_y.rem_euclid(world_size.1 as i32) == 0));
+                // A dead snake's body fades from grey to black, behind the food it left,
+                // the scoring zones are green and the famine is dark red
+                let field = (
+                    world_x.rem_euclid(world_size.0 as i32) as u16,
+                    world_y.rem_euclid(world_size.1 as i32) as u16,
+                );
+                // The grid lines are one character wide vertically, so only the left half of a field is drawn
+                let on_grid_x = show_grid && field.0 as i32 % GRID_SPACING == 0;
+                let on_grid_y = show_grid && field.1 as i32 % GRID_SPACING == 0;
+                let background = match fading_fields.get(&field) {
+                    Some(frames_left) => Some(format!("\x1b[48;5;{}m", 232 + 2 * frames_left)),
+                    None if in_zone(field.0, field.1) => Some("\x1b[48;5;22m".to_string()),
+                    None if in_famine(field.0, field.1) => Some("\x1b[48;5;52m".to_string()),
+                    None => territory.get(&(x, y)).map(|&owner| territory_color(owner)),
+                };
                 // Check for food
                 for i in 0..2 {
                     let fields = (
                         foods.get(&(2 * x + if i == 1 { 1 } else { 0 }, 2 * y)),
                         foods.get(&(2 * x + if i == 1 { 1 } else { 0 }, 2 * y + 1)),
                     );
+                    if let Some(background) = &background {
+                        to_print += background;
+                    }
                     match fields {
                         (None, None) => {
-                            to_print += " ";
+                            let on_grid_line = (on_grid_x && i == 0) || on_grid_y;
+                            to_print += match (on_seam, on_grid_line, ascii) {
+                                (true, _, false) => "\x1b[90m·",
+                                (true, _, true) => "\x1b[90m.",
+                                (false, true, _) if on_grid_x && on_grid_y && i == 0 => {
+                                    "\x1b[38;5;238m+"
+                                }
+                                (false, true, false) => "\x1b[38;5;238m·",
+                                (false, true, true) => "\x1b[38;5;238m.",
+                                (false, false, _) => " ",
+                            };
+                        }
+                        // Both foods are on the same field, so they're of the same tier
+                        (Some(&tier), _) | (None, Some(&tier)) if food_numbers => {
+                            to_print += foodcolor(tier, false);
+                            to_print += FOOD_SYMBOLS[(tier as usize).min(FOOD_SYMBOLS.len() - 1)];
                         }
-                        (Some(amount), None) => {
-                            to_print += foodcolor(*amount, false);
-                            to_print += "▀";
+                        (Some(&tier), None) => {
+                            to_print += foodcolor(tier, false);
+                            to_print += if ascii { "'" } else { "▀" };
                         }
-                        (None, Some(amount)) => {
-                            to_print += foodcolor(*amount, false);
-                            to_print += "▄";
+                        (None, Some(&tier)) => {
+                            to_print += foodcolor(tier, false);
+                            to_print += if ascii { "." } else { "▄" };
                         }
-                        (Some(amount0), Some(amount1)) => {
-                            to_print += foodcolor(*amount0, false);
-                            to_print += foodcolor(*amount1, true);
-                            to_print += "▀";
+                        (Some(&tier), Some(_)) => {
+                            to_print += foodcolor(tier, false);
+                            to_print += if ascii { ":" } else { "█" };
                         }
                     }
                     to_print += "\x1b[0m"; // reset colors
@@ -470,72 +2159,157 @@ pub fn draw(
     }
 
     // Add the status bar at the bottom
-    let status_text = format!(
-        "{nickname}: {score} ({score_place}), {kills} kills ({kills_place})",
-        nickname = snakes_info[&my_id].0,
-        score = snakes_info[&my_id].1,
-        kills = snakes_info[&my_id].2,
-        score_place = get_place_by_score(&snakes_info, my_id),
-        kills_place = get_place_by_kills(&snakes_info, my_id)
-    );
+    let status_text = match snakes_info.get(&my_id) {
+        Some(snake) => {
+            format!(
+                "{nickname}: {score} ({score_place}), {kills} kills ({kills_place})",
+                nickname = snake.nickname,
+                score = snake.score,
+                kills = snake.kills,
+                score_place = get_place_by_score(snakes_info, my_id),
+                kills_place = get_place_by_kills(snakes_info, my_id)
+            )
+        }
+        // Spectators don't have a snake, but the camera can follow someone else's
+        None => match head_positions
+            .get(&my_pos)
+            .and_then(|id| snakes_info.get(id))
+        {
+            Some(snake) => format!("Spectating {}: {}", snake.nickname, snake.score),
+            None => "Spectating".to_string(),
+        },
+    };
+    // With scoring zones, the banked score is what counts in the end
+    let status_text = match snakes_info.get(&my_id) {
+        Some(snake) if !zones.is_empty() => {
+            format!("{}, {} banked", status_text, snake.banked)
+        }
+        _ => status_text,
+    };
+    let afk = *AFK_DIM.lock().unwrap() && LAST_INPUT.lock().unwrap().elapsed() >= AFK_DELAY;
+    let status_text = if afk {
+        format!("[AFK] {}", status_text)
+    } else {
+        status_text
+    };
     let position_text = if real_terminal_size.0 as usize >= status_text.len() + 8 {
-        format!(
-            "{:3.0}#{:3.0}",
-            (1_000f64 * my_pos.0 as f64 / world_size.0 as f64),
-            (1_000f64 * my_pos.1 as f64 / world_size.1 as f64)
-        )
+        if show_grid {
+            // The real coordinates, so they can be read off the grid
+            format!("{},{}", my_pos.0, my_pos.1)
+        } else {
+            format!(
+                "{:3.0}#{:3.0}",
+                (1_000f64 * my_pos.0 as f64 / world_size.0 as f64),
+                (1_000f64 * my_pos.1 as f64 / world_size.1 as f64)
+            )
+        }
     } else {
         "".to_string()
     };
-    let snakes_count_text = format!("{} snakes", snakes_info.len());
+    let game_speed = *GAME_SPEED.lock().unwrap();
+    let snakes_count_text = match *PING.lock().unwrap() {
+        Some(rtt) => format!(
+            "{} snakes, {}ms, {} t/s",
+            snakes_info.len(),
+            rtt,
+            game_speed
+        ),
+        None => format!("{} snakes, {} t/s", snakes_info.len(), game_speed),
+    };
+    // Only the amount of snakes is shown if the rest doesn't fit
+    let short_snakes_count_text = format!("{} snakes", snakes_info.len());
+    // Show my length too, if it fits between the texts on the sides,
+    // and what score I need for fast mode until I have it
+    let fast_min_score = *FAST_MIN_SCORE.lock().unwrap();
+    let status_text = match snakes_info.get(&my_id) {
+        Some(snake) => {
+            let mut with_length = format!("{}, {} long", status_text, snake.length);
+            if snake.score < fast_min_score && !*FAST_MODE_DISABLED.lock().unwrap() {
+                with_length += &format!(", fast at {}", fast_min_score);
+            }
+            let side_width = snakes_count_text.len().max(position_text.len());
+            if real_terminal_size.0 as usize >= with_length.len() + 2 * side_width + 2 {
+                with_length
+            } else {
+                status_text
+            }
+        }
+        None => status_text,
+    };
     to_print += SNAKE_COLORS[(my_id % 9) as usize]; // colors
-    to_print += &snakes_count_text;
-    to_print += &" ".repeat(
-        ((real_terminal_size.0 as usize - status_text.len()) as f64 / 2f64).floor() as usize
-            - snakes_count_text.len(),
+    to_print += &status_bar(
+        real_terminal_size.0 as usize,
+        &[&snakes_count_text, &short_snakes_count_text],
+        &status_text,
+        &position_text,
     );
-    to_print += &status_text;
-    to_print += &" ".repeat(
-        ((real_terminal_size.0 as usize - status_text.len()) as f64 / 2f64).ceil() as usize
-            - position_text.len(),
-    );
-    to_print += &position_text;
     to_print += "\x1b[0m"; // reset colors
 
-    // Print nicknames of snakes
+    // Dim everything drawn so far while I'm away, everything resets colors after itself
+    if afk {
+        to_print = to_print.replace("\x1b[0m", "\x1b[0;2m");
+        to_print.insert_str(0, "\x1b[2m");
+    }
+
+    // Print nicknames of snakes, and their scores if needed
+    let show_score_labels = *SHOW_SCORE_LABELS.lock().unwrap();
+    // Offsets of a head from mine along one axis, on every copy of the world that can be in view.
+    // Behind a wall the world doesn't wrap, so there's only one
+    let head_offsets = |head: u16, me: u16, size: u16, wall: bool| {
+        if wall {
+            vec![head as i32 - me as i32]
+        } else {
+            let delta = wrap_delta(head, me, size);
+            vec![delta - size as i32, delta, delta + size as i32]
+        }
+    };
+    let show_nicknames = *SHOW_NICKNAMES.lock().unwrap();
     for head_pos in head_positions.keys() {
         if head_positions[head_pos] == my_id {
             continue;
         }
-        let nick = &snakes_info[&head_positions[head_pos]].0;
-        let leftpadding = " ".repeat(((10f32 - nick.len() as f32) / 2f32).floor() as usize);
-        let rightpadding = " ".repeat(((10f32 - nick.len() as f32) / 2f32).ceil() as usize);
-        let finalnickname = leftpadding + nick + &rightpadding;
+        if let ShowNicknames::Hide = show_nicknames {
+            break;
+        }
+        let snake = &snakes_info[&head_positions[head_pos]];
+        // Bots are grey, so they can't be mistaken for humans
+        let label_color = if snake.bot { "\x1b[90m" } else { "" };
+        let label = if show_score_labels {
+            format!("{} ({})", snake.nickname, snake.score)
+        } else {
+            snake.nickname.clone()
+        };
+        // Labels are at least 10 characters wide, and centered above the head
+        let label_width = label.len().max(10);
+        let leftpadding =
+            " ".repeat(((label_width as f32 - label.len() as f32) / 2f32).floor() as usize);
+        let rightpadding =
+            " ".repeat(((label_width as f32 - label.len() as f32) / 2f32).ceil() as usize);
+        let finalnickname = leftpadding + &label + &rightpadding;
         let nickname_bytes = finalnickname.as_bytes();
+        let label_offset = 3 + (label_width as i32 - 10) / 2;
+        let offsets_x = head_offsets(head_pos.0, my_pos.0, world_size.0, walls.0);
+        let offsets_y = head_offsets(head_pos.1, my_pos.1, world_size.1, walls.1);
+        if let ShowNicknames::Nearby = show_nicknames {
+            let near = |offsets: &[i32]| {
+                offsets
+                    .iter()
+                    .any(|offset| offset.abs() <= NEARBY_NICKNAME_DISTANCE)
+            };
+            if !near(&offsets_x) || !near(&offsets_y) {
+                continue;
+            }
+        }
 
-        for i in 0..10 {
-            // Theoretically it's possible to display the same nickname on 4 different locations on the screen
-            for l in 0..4 {
+        for i in 0..label_width {
+            // In small worlds it's possible to display the same nickname on several locations on the screen
+            for (dx, dy) in offsets_x
+                .iter()
+                .flat_map(|dx| offsets_y.iter().map(move |dy| (dx, dy)))
+            {
                 // Check if the field is in frame
-                let pos_x = if l / 2 == 1 {
-                    2 * (head_pos.0 as i32
-                        - my_pos.0 as i32
-                        - world_size.0 as i32
-                        - width.start as i32)
-                        + i as i32
-                        - 3
-                } else {
-                    2 * (head_pos.0 as i32 - my_pos.0 as i32 - width.start as i32) + i as i32 - 3
-                };
-                let pos_y = if l % 2 == 0 {
-                    (head_pos.1 as i32
-                        - my_pos.1 as i32
-                        - world_size.1 as i32
-                        - height.start as i32)
-                        + 2
-                } else {
-                    (head_pos.1 as i32 - my_pos.1 as i32 - height.start as i32) + 2
-                };
+                let pos_x = 2 * (dx - width.start as i32) + i as i32 - label_offset;
+                let pos_y = (dy - height.start as i32) + 2;
                 if pos_x >= 0
                     && pos_x <= real_terminal_size.0 as i32
                     && pos_y >= 0
@@ -546,9 +2320,10 @@ pub fn draw(
                     } // Skip spaces
                       // Move to the required position and print the text
                     to_print += &format!(
-                        "\x1b[{line};{column}H{text}",
+                        "\x1b[{line};{column}H{color}{text}\x1b[0m",
                         line = pos_y,
                         column = pos_x,
+                        color = label_color,
                         text = std::str::from_utf8(&[nickname_bytes[i]]).unwrap()
                     );
                 }
@@ -558,18 +2333,24 @@ pub fn draw(
 
     // If needed, print leaderboard
     let show_board = *SHOW_LEADERBOARD.lock().unwrap();
-    if let ShowLeaderboard::ByScore | ShowLeaderboard::ByKills = show_board {
+    if let ShowLeaderboard::ByScore
+    | ShowLeaderboard::ByKills
+    | ShowLeaderboard::ByLength
+    | ShowLeaderboard::ByBanked = show_board
+    {
         let column = real_terminal_size.0 - 20;
         let (by_what, board) = match show_board {
-            ShowLeaderboard::ByScore => ("Score", get_top_by_score(&snakes_info)),
-            ShowLeaderboard::ByKills => ("Kills", get_top_by_kills(&snakes_info)),
+            ShowLeaderboard::ByScore => ("Score", get_top_by_score(snakes_info)),
+            ShowLeaderboard::ByKills => ("Kills", get_top_by_kills(snakes_info)),
+            ShowLeaderboard::ByLength => ("Length", get_top_by_length(snakes_info)),
+            ShowLeaderboard::ByBanked => ("Banked", get_top_by_banked(snakes_info)),
             _ => ("", Vec::new()),
         };
         let mut board = board.into_iter();
         to_print += &format!(
-            "\x1b[1;{column}H\x1b[100;4;1m      By {what}       \x1b[0m",
+            "\x1b[1;{column}H\x1b[100;4;1m{title:^21}\x1b[0m",
             column = column,
-            what = by_what
+            title = format!("By {}", by_what)
         );
         for ln in 2..11 {
             let player = board.next();
@@ -588,26 +2369,335 @@ pub fn draw(
         }
     }
 
+    // If needed, print the rules of the server in the middle of the screen
+    if *SHOW_RULES.lock().unwrap() {
+        let rules = RULES.lock().unwrap();
+        let width = rules.iter().map(|rule| rule.len()).max().unwrap_or(0) + 4;
+        let height = rules
+            .len()
+            .min((real_terminal_size.1 as usize).saturating_sub(3));
+        let column = (real_terminal_size.0 as usize).saturating_sub(width) / 2 + 1;
+        let top = (real_terminal_size.1 as usize).saturating_sub(height) / 2;
+        to_print += &format!(
+            "\x1b[{line};{column}H\x1b[100;4;1m{title:^width$}\x1b[0m",
+            line = top,
+            column = column,
+            title = "Rules",
+            width = width
+        );
+        for (ln, rule) in rules.iter().take(height).enumerate() {
+            to_print += &format!(
+                "\x1b[{line};{column}H\x1b[100m  {rule:width$}\x1b[0m",
+                line = top + 1 + ln,
+                column = column,
+                rule = rule,
+                width = width - 2
+            );
+        }
+    }
+
+    // Let the player know if their last turn was rejected
+    if let Some(rejected_at) = DIRECTION_STATE.lock().unwrap().rejected_at {
+        if rejected_at.elapsed() < REJECTED_TURN_DURATION {
+            let text = "Can't turn back!";
+            to_print += &format!(
+                "\x1b[{line};{column}H\x1b[101;30;1m{text}\x1b[0m",
+                line = real_terminal_size.1 - 1,
+                column = (real_terminal_size.0 as usize - text.len()) / 2,
+                text = text
+            );
+        }
+    }
+
+    // Print the kill feed in the top left corner
+    let mut kill_feed = KILL_FEED.lock().unwrap();
+    while let Some((time, _, _)) = kill_feed.front() {
+        if time.elapsed() < KILL_FEED_DURATION {
+            break;
+        }
+        kill_feed.pop_front();
+    }
+    for (ln, (time, killer, victim)) in kill_feed.iter().enumerate() {
+        // Fade out kills that are about to disappear
+        let color = if time.elapsed() < KILL_FEED_DURATION * 2 / 3 {
+            "\x1b[107;30m"
+        } else {
+            "\x1b[100;37m"
+        };
+        to_print += &format!(
+            "\x1b[{line};1H{color}{killer} {cross} {victim}\x1b[0m",
+            line = ln + 1,
+            color = color,
+            cross = if *ASCII.lock().unwrap() { "x" } else { "✗" },
+            killer = killer,
+            victim = victim
+        );
+    }
+    drop(kill_feed);
+
+    // Let everyone know that the game is paused, in the middle of the screen
+    if *PAUSED.lock().unwrap() {
+        let text = "  PAUSED  ";
+        to_print += &format!(
+            "\x1b[{line};{column}H\x1b[107;30;1m{text}\x1b[0m",
+            line = real_terminal_size.1 / 2 - 2,
+            column = (real_terminal_size.0 as usize).saturating_sub(text.len()) / 2 + 1,
+            text = text
+        );
+    }
+
+    // Celebrate kill streaks above the middle of the screen
+    if let Some((time, streak, bonus)) = *KILL_STREAK.lock().unwrap() {
+        if time.elapsed() < KILL_STREAK_DURATION {
+            let text = format!("  {} +{}  ", kill_streak_name(streak), bonus);
+            to_print += &format!(
+                "\x1b[{line};{column}H\x1b[43;30;1m{text}\x1b[0m",
+                line = real_terminal_size.1 / 2 - 4,
+                column = (real_terminal_size.0 as usize).saturating_sub(text.len()) / 2 + 1,
+                text = text
+            );
+        }
+    }
+
+    // Show the last announcement from the server at the top of the screen
+    if let Some((time, text)) = &*ANNOUNCEMENT.lock().unwrap() {
+        if time.elapsed() < ANNOUNCEMENT_DURATION {
+            let text = format!("  {}  ", text);
+            to_print += &format!(
+                "\x1b[{line};{column}H\x1b[45;97;1m{text}\x1b[0m",
+                line = 2,
+                column = (real_terminal_size.0 as usize).saturating_sub(text.len()) / 2 + 1,
+                text = text
+            );
+        }
+    }
+
+    // Announce the winner of the last round in the middle of the screen
+    if let Some((time, text)) = &*VICTORY.lock().unwrap() {
+        if time.elapsed() < VICTORY_DURATION {
+            let text = format!("  {}  ", text);
+            to_print += &format!(
+                "\x1b[{line};{column}H\x1b[42;30;1m{text}\x1b[0m",
+                line = real_terminal_size.1 / 2,
+                column = (real_terminal_size.0 as usize).saturating_sub(text.len()) / 2 + 1,
+                text = text
+            );
+        }
+    }
+
+    // Warn about sudden death or the famine right above the status bar
+    let sudden_death_text = match *SUDDEN_DEATH.lock().unwrap() {
+        Some(0) => Some("SUDDEN DEATH - the food is rotting away!".to_string()),
+        Some(seconds) if seconds <= SUDDEN_DEATH_WARNING => {
+            Some(format!("Sudden death in {}s", seconds))
+        }
+        _ => None,
+    };
+    let famine_text = famine
+        .map(|(.., seconds)| format!("FAMINE - no food grows in the red area for {}s", seconds));
+    if let Some(text) = sudden_death_text.or(famine_text) {
+        to_print += &format!(
+            "\x1b[{line};{column}H\x1b[41;97;1m{text}\x1b[0m",
+            line = real_terminal_size.1 - 1,
+            column = (real_terminal_size.0 as usize).saturating_sub(text.len()) / 2 + 1,
+            text = text
+        );
+    }
+
     // Print and flush the output
+    record(&to_print);
     let stdout = stdout();
     let mut lock = stdout.lock();
     lock.write_all(to_print.as_bytes()).unwrap();
     lock.flush().unwrap();
 }
 
+/// Returns the background color of a snake part, the further from the head the darker
+fn shade(id: u16, depth: u16, length: u16) -> String {
+    let (r, g, b) = SNAKE_COLORS_RGB[(id % 9) as usize];
+    let brightness = 1f32
+        - (1f32 - GRADIENT_TAIL_BRIGHTNESS) * (depth as f32 / (length.max(2) - 1) as f32).min(1f32);
+    format!(
+        "\x1b[48;2;{};{};{}m",
+        (r as f32 * brightness) as u8,
+        (g as f32 * brightness) as u8,
+        (b as f32 * brightness) as u8
+    )
+}
+
+/// Returns the background color of the territory of the given snake, a darker shade of its color
+fn territory_color(id: u16) -> String {
+    let (r, g, b) = SNAKE_COLORS_RGB[(id % 9) as usize];
+    format!(
+        "\x1b[48;2;{};{};{}m",
+        (r as f32 * TERRITORY_BRIGHTNESS) as u8,
+        (g as f32 * TERRITORY_BRIGHTNESS) as u8,
+        (b as f32 * TERRITORY_BRIGHTNESS) as u8
+    )
+}
+
+/// Returns what a kill streak of the given length is called
+fn kill_streak_name(streak: u16) -> String {
+    match streak {
+        2 => "Double kill!".to_string(),
+        3 => "Triple kill!".to_string(),
+        4 => "Quadra kill!".to_string(),
+        _ => format!("{} kills in a row!", streak),
+    }
+}
+
+/// Describes the rules of the server in a few lines of text, from the confirmation
+/// that I joined the game
+fn rules_summary(bytes: &[u8]) -> Vec<String> {
+    let rules = &bytes[bytes.len() - RULES_LENGTH..];
+    let ticks =
+        |at: usize| u32::from_be_bytes([rules[at], rules[at + 1], rules[at + 2], rules[at + 3]]);
+    // Durations are sent in ticks, but players think in seconds
+    let game_speed = rules[2].max(1) as u32;
+    let seconds = |ticks: u32| ((ticks + game_speed / 2) / game_speed).max(1);
+
+    let mut lines = vec![format!(
+        "World: {}x{}, {}",
+        u16::from_be_bytes([bytes[3], bytes[4]]),
+        u16::from_be_bytes([bytes[5], bytes[6]]),
+        match bytes[7] & 0b11 {
+            0 => "wraps around",
+            1 => "walls on the left and right",
+            2 => "walls on the top and bottom",
+            _ => "walled in",
+        }
+    )];
+    lines.push(match rules[3] {
+        0 => format!("Speed: {} t/s", rules[2]),
+        max_game_speed => format!("Speed: {} t/s, speeding up to {}", rules[2], max_game_speed),
+    });
+    lines.push(if bytes[7] & 0b100 != 0 {
+        "Fast mode: disabled".to_string()
+    } else {
+        format!(
+            "Fast mode: from {} score",
+            u16::from_be_bytes([bytes[8], bytes[9]])
+        )
+    });
+    if bytes[7] & 0b1000 != 0 {
+        lines.push("Rounds: the last snake standing wins".to_string());
+    }
+    lines.push(format!(
+        "Turning back: {}",
+        if rules[0] & 0b1 != 0 {
+            "allowed"
+        } else {
+            "not allowed"
+        }
+    ));
+    lines.push(format!(
+        "Head-on crashes: {}",
+        match rules[1] {
+            0 => "both die",
+            1 => "the longest survives",
+            _ => "both die, both get a kill",
+        }
+    ));
+    match u16::from_be_bytes([rules[4], rules[5]]) {
+        0 => (),
+        max_length => lines.push(format!("Max length: {}", max_length)),
+    }
+    match ticks(6) {
+        0 => (),
+        ticks => lines.push(format!("Spawn protection: {}s", seconds(ticks))),
+    }
+    match ticks(10) {
+        0 => (),
+        ticks => lines.push(format!("Food magnets: last {}s", seconds(ticks))),
+    }
+    match ticks(14) {
+        0 => (),
+        ticks => lines.push(format!(
+            "Phasing: pass through other snakes for {}s",
+            seconds(ticks)
+        )),
+    }
+    match ticks(18) {
+        0 => (),
+        ticks => lines.push(format!(
+            "Sudden death: after {}s without deaths",
+            seconds(ticks)
+        )),
+    }
+    match ticks(22) {
+        0 => (),
+        ticks => lines.push(format!("Famines: {}s apart", seconds(ticks))),
+    }
+    match ticks(26) {
+        0 => (),
+        ticks => lines.push(format!("AFK players: kicked after {}s", seconds(ticks))),
+    }
+    lines.push(format!("Dead snakes: drop {}% of their score", rules[30]));
+    if rules[31] > 0 {
+        lines.push(format!(
+            "Food piles: +{}% score for each food beyond the first",
+            rules[31]
+        ));
+    }
+    if bytes[11] > 0 {
+        lines.push(format!(
+            "Scoring zones: {}, bank your score in them",
+            bytes[11]
+        ));
+    }
+    if rules[0] & 0b100 != 0 {
+        lines.push("Territory: closing a loop claims the area inside".to_string());
+    }
+    if rules[0] & 0b1000 != 0 {
+        lines.push("Kill streaks: rewarded with bonus score".to_string());
+    }
+    if rules[0] & 0b10 != 0 {
+        lines.push("Respawning: automatic".to_string());
+    }
+    if rules[0] & 0b10000 != 0 {
+        lines.push("Nicknames: unique".to_string());
+    }
+    if rules[0] & 0b100000 != 0 {
+        lines.push("World dumps: allowed".to_string());
+    }
+    lines
+}
+
+/// Lays out the status bar with the text in the middle and the texts on the sides.
+/// The first of the left texts that fits is used, and the side texts that don't fit are left out
+fn status_bar(width: usize, left: &[&str], middle: &str, right: &str) -> String {
+    // The middle text is cut short if even it doesn't fit
+    let middle: String = middle.chars().take(width).collect();
+    let middle_width = middle.chars().count();
+    let left_space = (width - middle_width) / 2;
+    let right_space = width - middle_width - left_space;
+    // Keep at least one space between the texts
+    let left = left
+        .iter()
+        .find(|text| text.len() < left_space)
+        .copied()
+        .unwrap_or("");
+    let right = if right.len() < right_space { right } else { "" };
+    format!(
+        "{}{}{}{}{}",
+        left,
+        " ".repeat(left_space - left.len()),
+        middle,
+        " ".repeat(right_space - right.len()),
+        right
+    )
+}
+
 /// Get place amongst all alive snakes sorting by score
-pub fn get_place_by_score(snakes_data: &HashMap<u16, (String, u16, u16, bool)>, id: u16) -> String {
+pub fn get_place_by_score(snakes_data: &HashMap<u16, SnakeInfo>, id: u16) -> String {
     // Get the scores and sort them
-    let mut scores: Vec<u16> = snakes_data
-        .iter()
-        .map(|(_id, (_nickname, score, _kills, _fast_mode))| *score)
-        .collect();
+    let mut scores: Vec<u16> = snakes_data.values().map(|snake| snake.score).collect();
     scores.sort();
     scores.reverse();
     let mut place: u16 = 1;
     // Iterate through all the scores to find out the place
     for score in scores {
-        if score == snakes_data[&id].1 {
+        if score == snakes_data[&id].score {
             break;
         }
         place += 1;
@@ -622,18 +2712,15 @@ pub fn get_place_by_score(snakes_data: &HashMap<u16, (String, u16, u16, bool)>,
 }
 
 /// Get place amongst all alive snakes sorting by kills
-pub fn get_place_by_kills(snakes_data: &HashMap<u16, (String, u16, u16, bool)>, id: u16) -> String {
+pub fn get_place_by_kills(snakes_data: &HashMap<u16, SnakeInfo>, id: u16) -> String {
     // Get the kills and sort them
-    let mut kills: Vec<u16> = snakes_data
-        .iter()
-        .map(|(_id, (_nickname, _score, kills, _fast_mode))| *kills)
-        .collect();
+    let mut kills: Vec<u16> = snakes_data.values().map(|snake| snake.kills).collect();
     kills.sort();
     kills.reverse();
     let mut place: u16 = 1;
     // Iterate through all the kills to find out the place
     for amount in kills {
-        if amount == snakes_data[&id].2 {
+        if amount == snakes_data[&id].kills {
             break;
         }
         place += 1;
@@ -647,32 +2734,57 @@ pub fn get_place_by_kills(snakes_data: &HashMap<u16, (String, u16, u16, bool)>,
     format!("{}{}", place, suffix)
 }
 
-pub fn get_top_by_score(
-    snakes_data: &HashMap<u16, (String, u16, u16, bool)>,
-) -> Vec<(u16, String)> {
+pub fn get_top_by_score(snakes_data: &HashMap<u16, SnakeInfo>) -> Vec<(u16, String)> {
     let mut scores: Vec<(u16, String)> = snakes_data
-        .iter()
-        .map(|(_id, (nickname, score, _kills, _fast_mode))| (*score, nickname.clone()))
+        .values()
+        .map(|snake| (snake.score, snake.nickname.clone()))
         .collect();
     scores.sort_unstable();
     scores.reverse();
     scores
 }
 
-pub fn get_top_by_kills(
-    snakes_data: &HashMap<u16, (String, u16, u16, bool)>,
-) -> Vec<(u16, String)> {
+pub fn get_top_by_kills(snakes_data: &HashMap<u16, SnakeInfo>) -> Vec<(u16, String)> {
     let mut scores: Vec<(u16, String)> = snakes_data
-        .iter()
-        .map(|(_id, (nickname, _score, kills, _fast_mode))| (*kills, nickname.clone()))
+        .values()
+        .map(|snake| (snake.kills, snake.nickname.clone()))
+        .collect();
+    scores.sort_unstable();
+    scores.reverse();
+    scores
+}
+
+pub fn get_top_by_banked(snakes_data: &HashMap<u16, SnakeInfo>) -> Vec<(u16, String)> {
+    let mut scores: Vec<(u16, String)> = snakes_data
+        .values()
+        .map(|snake| (snake.banked, snake.nickname.clone()))
+        .collect();
+    scores.sort_unstable();
+    scores.reverse();
+    scores
+}
+
+pub fn get_top_by_length(snakes_data: &HashMap<u16, SnakeInfo>) -> Vec<(u16, String)> {
+    let mut scores: Vec<(u16, String)> = snakes_data
+        .values()
+        .map(|snake| (snake.length, snake.nickname.clone()))
         .collect();
     scores.sort_unstable();
     scores.reverse();
     scores
 }
 
+/// Returns the text of an error message from server
+fn error_text(bytes: &[u8]) -> &str {
+    // The first byte is the magic byte and the second is the kind of the error
+    bytes
+        .get(2..)
+        .and_then(|text| std::str::from_utf8(text).ok())
+        .unwrap_or("{corrupted error}")
+}
+
 /// Sends bytes to stream, with the buffer length appended to the beginning as an u8 integer
-pub fn send_to_stream(stream: &mut TcpStream, data: &[u8]) {
+pub fn send_to_stream(stream: &mut Stream, data: &[u8]) {
     let size: [u8; 1] = u8::to_be_bytes(data.len() as u8);
     let mut message: Vec<u8> = Vec::new();
     message.extend_from_slice(&size);
@@ -681,35 +2793,68 @@ pub fn send_to_stream(stream: &mut TcpStream, data: &[u8]) {
     stream.write_all(&message).unwrap();
 }
 
-/// Reads 1 message from stream
+/// Reads 1 message, no bigger than `max_size` bytes, from stream
 /// Returns `Ok(bytes)` if the reading was successful
 /// and `Err(e)` if an error was encountered while reading
-pub fn read_from_stream(stream: &mut TcpStream) -> Result<Vec<u8>, std::io::ErrorKind> {
+pub fn read_from_stream(stream: &mut Stream, max_size: usize) -> Result<Vec<u8>, NetError> {
     // Figure out the size of the incoming message
     let mut size = [0u8; 2];
-    if let Err(e) = stream.read_exact(&mut size) {
-        return Err(e.kind());
-    }
+    stream.read_exact(&mut size)?;
     let size = u16::from_be_bytes(size);
+    // Every message has at least the magic byte,
+    // and don't even allocate the memory for messages that are too big
+    if size == 0 || size as usize > max_size {
+        return Err(NetError::Malformed);
+    }
 
     // Get the actual message
     let mut bytes = vec![0u8; size as usize];
-    if let Err(e) = stream.read_exact(&mut bytes) {
-        return Err(e.kind());
-    }
+    stream.read_exact(&mut bytes)?;
     Ok(bytes)
 }
 
-pub fn foodcolor(amount: u8, bg: bool) -> &'static str {
-    let t = if amount < 2 {
-        FOOD_COLORS[0]
-    } else if amount < 5 {
-        FOOD_COLORS[1]
-    } else if amount < 11 {
-        FOOD_COLORS[2]
-    } else {
-        FOOD_COLORS[3]
-    };
+/// Rings the terminal bell if my score went up since the previous frame, unless it rang just now.
+/// The bell is only printed, it's flushed together with the frame
+fn ring_bell(my_id: u16, previous_frame: &Frame, frame: &Frame) {
+    if !*BELL.lock().unwrap() {
+        return;
+    }
+    let score = |frame: &Frame| frame.snakes.get(&my_id).map(|snake| snake.score);
+    match (score(previous_frame), score(frame)) {
+        (Some(previous_score), Some(score)) if score > previous_score => {}
+        _ => return,
+    }
+    let mut last_bell = LAST_BELL.lock().unwrap();
+    if let Some(last_bell) = *last_bell {
+        if last_bell.elapsed() < BELL_INTERVAL {
+            return;
+        }
+    }
+    *last_bell = Some(Instant::now());
+    print!("\x07");
+    record("\x07");
+}
+
+/// Makes the next frame be drawn from scratch, without the fading trails
+pub fn request_redraw() {
+    FADING.lock().unwrap().clear();
+    *CLEAR_SCREEN.lock().unwrap() = true;
+}
+
+/// Returns what a frame starts with: moving the cursor to the top left corner of the terminal,
+/// after getting rid of anything left on the screen if a redraw was requested
+fn frame_start() -> String {
+    let mut start = String::new();
+    if std::mem::replace(&mut *CLEAR_SCREEN.lock().unwrap(), false) {
+        start += "\x1b[2J";
+    }
+    start += "\x1b[H";
+    start
+}
+
+/// Returns the color of the given food tier, sent by the server
+pub fn foodcolor(tier: u8, bg: bool) -> &'static str {
+    let t = FOOD_COLORS[(tier as usize).min(FOOD_COLORS.len() - 1)];
 
     if bg {
         t.1
@@ -717,3 +2862,99 @@ pub fn foodcolor(amount: u8, bg: bool) -> &'static str {
         t.0
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::client_stream_pair;
+
+    /// Reads the directions sent to the server so far
+    fn sent_directions(server_side: &mut std::net::TcpStream) -> Vec<u8> {
+        let mut directions = Vec::new();
+        let mut message = [0u8; 3];
+        while server_side.read_exact(&mut message).is_ok() {
+            assert_eq!(message[..2], [2, MAGIC_NET_CHANGE_DIRECTION]);
+            directions.push(message[2]);
+        }
+        directions
+    }
+
+    #[test]
+    fn quick_relative_turns_are_sent_one_at_a_time() {
+        let (mut stream, mut server_side) = client_stream_pair();
+        server_side
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+
+        // Going up
+        forget_last_snake();
+        update_direction_state((10, 10), (40, 40));
+        update_direction_state((10, 9), (40, 40));
+
+        // Turning right twice in one tick would be a 180, so the second turn waits
+        turn_relative(&mut stream, true);
+        turn_relative(&mut stream, true);
+        send_queued_turn(&mut stream);
+        assert_eq!(sent_directions(&mut server_side), [2]);
+
+        // Until the server turns the snake right
+        update_direction_state((11, 9), (40, 40));
+        send_queued_turn(&mut stream);
+        assert_eq!(sent_directions(&mut server_side), [3]);
+        update_direction_state((11, 10), (40, 40));
+        assert_eq!(DIRECTION_STATE.lock().unwrap().moved, Some(3));
+    }
+
+    #[test]
+    fn kill_feed_messages_that_are_cut_short_are_ignored() {
+        for message in &[
+            &[MAGIC_NET_KILLFEED][..],
+            &[MAGIC_NET_KILLFEED, 5, b'k'],
+            &[MAGIC_NET_KILLFEED, 1, b'k', 3, b'v'],
+        ] {
+            handle_server_message(message.to_vec(), 1, (40, 40), (false, false), false);
+        }
+        assert!(KILL_FEED.lock().unwrap().is_empty());
+
+        handle_server_message(
+            vec![MAGIC_NET_KILLFEED, 1, b'k', 1, b'v'],
+            1,
+            (40, 40),
+            (false, false),
+            false,
+        );
+        let kill_feed = KILL_FEED.lock().unwrap();
+        assert_eq!(kill_feed.len(), 1);
+        assert_eq!(kill_feed[0].1, "k");
+        assert_eq!(kill_feed[0].2, "v");
+    }
+
+    #[test]
+    fn side_texts_of_the_status_bar_are_left_out_when_they_dont_fit() {
+        let status = "abcdefghij: 1200 (10th), 20 kills (10th)";
+        let left: &[&str] = &["12 snakes, 45ms, 10 t/s", "12 snakes"];
+        let bar = status_bar(80, left, status, "500#500");
+        assert_eq!(bar.len(), 80);
+        assert!(bar.starts_with("12 snakes "));
+        assert_eq!(bar[20..60], *status);
+        assert!(bar.ends_with(" 500#500"));
+
+        // The full text is used if there's room for it
+        assert!(status_bar(100, left, status, "").starts_with(left[0]));
+        // And the status itself is cut short in a tiny terminal
+        assert_eq!(status_bar(20, left, status, "500#500"), &status[..20]);
+    }
+
+    #[test]
+    fn a_redraw_starts_the_next_frame_from_scratch() {
+        FADING
+            .lock()
+            .unwrap()
+            .push_back((FADE_FRAMES, vec![(1, 1)]));
+        request_redraw();
+        assert!(FADING.lock().unwrap().is_empty());
+        assert_eq!(frame_start(), "\x1b[2J\x1b[H");
+        // Only once, the frames after it are drawn over the last one again
+        assert_eq!(frame_start(), "\x1b[H");
+    }
+}
@@ -0,0 +1,165 @@
+use std::convert::TryFrom;
+use std::env;
+use std::fs;
+use std::io;
+use std::num::NonZeroU8;
+use std::path::PathBuf;
+
+/// Defaults for the client, read from `~/.config/multisnake/config.toml`.
+/// Anything given on the command line overrides them
+#[derive(Default)]
+pub struct Config {
+    /// The file the config was read from
+    pub path: PathBuf,
+    pub nickname: Option<String>,
+    /// IP address or hostname of the server
+    pub server: Option<String>,
+    pub port: Option<u16>,
+    pub socket: Option<PathBuf>,
+    pub predict: bool,
+    pub smooth: bool,
+    pub gradient: bool,
+    pub relative_controls: bool,
+    pub afk_dim: bool,
+    pub ascii: bool,
+    pub bell: bool,
+    pub max_fps: Option<NonZeroU8>,
+    pub summary: bool,
+}
+
+/// A value of a single setting
+enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+}
+
+impl Config {
+    /// Reads the config file, returns the default config if there isn't one.
+    ///
+    /// The file is a flat subset of TOML: one `key = value` per line, where the value is
+    /// a string in double quotes, an integer or a boolean, and lines starting with `#` are comments
+    pub fn load() -> Result<Self, String> {
+        let path = match config_path() {
+            Some(path) => path,
+            None => return Ok(Config::default()),
+        };
+        let data = match fs::read_to_string(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Config::default()),
+            Err(e) => return Err(format!("Can't read {}: {}", path.display(), e)),
+        };
+
+        let mut config = Config {
+            path,
+            ..Config::default()
+        };
+        for (number, line) in data.lines().enumerate() {
+            config
+                .parse_line(line)
+                .map_err(|e| format!("{} line {}: {}", config.path.display(), number + 1, e))?;
+        }
+        Ok(config)
+    }
+    /// Parses a single line of the config file and applies the setting in it
+    fn parse_line(&mut self, line: &str) -> Result<(), &'static str> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(());
+        }
+        let mut parts = line.splitn(2, '=');
+        let key = parts.next().unwrap().trim();
+        let value = parse_value(parts.next().ok_or("expected key = value")?.trim())?;
+
+        match (key, value) {
+            ("nickname", Value::String(nickname)) => self.nickname = Some(nickname),
+            ("server", Value::String(server)) => self.server = Some(server),
+            ("port", Value::Integer(port)) => {
+                self.port =
+                    Some(u16::try_from(port).map_err(|_| "port has to be between 0 and 65535")?);
+            }
+            ("socket", Value::String(socket)) => self.socket = Some(PathBuf::from(socket)),
+            ("max_fps", Value::Integer(max_fps)) => {
+                self.max_fps = Some(
+                    u8::try_from(max_fps)
+                        .ok()
+                        .and_then(NonZeroU8::new)
+                        .ok_or("max_fps has to be between 1 and 255")?,
+                );
+            }
+            ("predict", Value::Boolean(value)) => self.predict = value,
+            ("smooth", Value::Boolean(value)) => self.smooth = value,
+            ("gradient", Value::Boolean(value)) => self.gradient = value,
+            ("relative_controls", Value::Boolean(value)) => self.relative_controls = value,
+            ("afk_dim", Value::Boolean(value)) => self.afk_dim = value,
+            ("ascii", Value::Boolean(value)) => self.ascii = value,
+            ("bell", Value::Boolean(value)) => self.bell = value,
+            ("summary", Value::Boolean(value)) => self.summary = value,
+            ("nickname", _) | ("server", _) | ("socket", _) => {
+                return Err("expected a string in double quotes")
+            }
+            ("port", _) | ("max_fps", _) => return Err("expected an integer"),
+            ("predict", _)
+            | ("smooth", _)
+            | ("gradient", _)
+            | ("relative_controls", _)
+            | ("afk_dim", _)
+            | ("ascii", _)
+            | ("bell", _)
+            | ("summary", _) => return Err("expected true or false"),
+            _ => return Err("unknown setting"),
+        }
+        Ok(())
+    }
+}
+
+/// Parses the value of a setting, with an optional comment after it
+fn parse_value(data: &str) -> Result<Value, &'static str> {
+    if let Some(rest) = data.strip_prefix('"') {
+        // Only \" and \\ are escaped inside strings
+        let mut string = String::new();
+        let mut characters = rest.chars();
+        loop {
+            match characters.next().ok_or("missing closing \"")? {
+                '"' => break,
+                '\\' => match characters.next() {
+                    Some(character @ '"') | Some(character @ '\\') => string.push(character),
+                    _ => return Err("only \\\" and \\\\ can be escaped"),
+                },
+                character => string.push(character),
+            }
+        }
+        check_comment(characters.as_str())?;
+        return Ok(Value::String(string));
+    }
+
+    let end = data.find('#').unwrap_or(data.len());
+    check_comment(&data[end..])?;
+    match data[..end].trim() {
+        "true" => Ok(Value::Boolean(true)),
+        "false" => Ok(Value::Boolean(false)),
+        value => value
+            .parse()
+            .map(Value::Integer)
+            .map_err(|_| "expected a string, an integer, true or false"),
+    }
+}
+
+/// Makes sure that nothing but a comment follows a value
+fn check_comment(rest: &str) -> Result<(), &'static str> {
+    let rest = rest.trim();
+    if rest.is_empty() || rest.starts_with('#') {
+        Ok(())
+    } else {
+        Err("unexpected characters after the value")
+    }
+}
+
+/// Returns the path of the config file, in `$XDG_CONFIG_HOME` or `~/.config`
+fn config_path() -> Option<PathBuf> {
+    let config_dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_dir.join("multisnake").join("config.toml"))
+}
@@ -1,17 +1,35 @@
 #![feature(option_unwrap_none)]
 
 mod client;
+mod config;
 mod server;
+mod stream;
+#[cfg(test)]
+mod testing;
+mod wrap;
 
 use dns_lookup::lookup_host;
-use std::num::NonZeroU8;
+use std::net::IpAddr;
+use std::num::{NonZeroU64, NonZeroU8};
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::Duration;
 use structopt::StructOpt;
 
+// The longest nickname allowed, counting the special characters escaped.
+// Servers can lower it, but not raise it
+pub const MAX_NICKNAME_LENGTH: usize = 10;
+// The longest a phase power-up can last, so nobody passes through everyone for the whole game
+const MAX_PHASE_DURATION: u32 = 200;
+// The port clients connect to if neither the arguments nor the config file give one
+const DEFAULT_PORT: u16 = 50403;
+
 struct WorldSize(u16, u16);
 
 impl FromStr for WorldSize {
-    type Err = &'static str;
+    type Err = String;
 
     fn from_str(data: &str) -> Result<Self, Self::Err> {
         let mut split = data.split('x');
@@ -26,17 +44,191 @@ impl FromStr for WorldSize {
             .ok_or("Failed to parse height")?;
 
         if split.next().is_some() {
-            return Err("Extra data");
+            return Err("Extra data".to_string());
         }
 
         if width < 20 || height < 20 {
-            return Err("Width and height have to be at least 20");
+            return Err("Width and height have to be at least 20".to_string());
+        }
+        if width > server::MAX_WORLD_SIZE || height > server::MAX_WORLD_SIZE {
+            return Err(format!(
+                "Width and height can't be more than {}",
+                server::MAX_WORLD_SIZE
+            ));
         }
 
         Ok(WorldSize(width, height))
     }
 }
 
+struct FoodValues(Vec<(u8, u16)>);
+
+impl FromStr for FoodValues {
+    type Err = &'static str;
+
+    fn from_str(data: &str) -> Result<Self, Self::Err> {
+        let mut values = Vec::new();
+
+        for pair in data.split(',') {
+            let mut split = pair.split(':');
+
+            let amount = split
+                .next()
+                .and_then(|num| num.parse::<u8>().ok())
+                .ok_or("Failed to parse food amount")?;
+            let weight = split
+                .next()
+                .and_then(|num| num.parse::<u16>().ok())
+                .ok_or("Failed to parse food weight")?;
+
+            if split.next().is_some() {
+                return Err("Extra data");
+            }
+
+            if amount == 0 {
+                return Err("Food amount has to be at least 1");
+            }
+
+            values.push((amount, weight));
+        }
+
+        if values.iter().all(|&(_amount, weight)| weight == 0) {
+            return Err("At least one food amount has to have a non-zero weight");
+        }
+
+        Ok(FoodValues(values))
+    }
+}
+
+struct BotPersonalities(Vec<(server::bot::Personality, u16)>);
+
+impl FromStr for BotPersonalities {
+    type Err = &'static str;
+
+    fn from_str(data: &str) -> Result<Self, Self::Err> {
+        let mut personalities = Vec::new();
+
+        for pair in data.split(',') {
+            let mut split = pair.split(':');
+
+            let personality = match split.next() {
+                Some("greedy") => server::bot::Personality::Greedy,
+                Some("cowardly") => server::bot::Personality::Cowardly,
+                Some("aggressive") => server::bot::Personality::Aggressive,
+                _ => return Err("Personality has to be greedy, cowardly or aggressive"),
+            };
+            let weight = split
+                .next()
+                .and_then(|num| num.parse::<u16>().ok())
+                .ok_or("Failed to parse personality weight")?;
+
+            if split.next().is_some() {
+                return Err("Extra data");
+            }
+
+            personalities.push((personality, weight));
+        }
+
+        if personalities
+            .iter()
+            .all(|&(_personality, weight)| weight == 0)
+        {
+            return Err("At least one personality has to have a non-zero weight");
+        }
+
+        Ok(BotPersonalities(personalities))
+    }
+}
+
+struct KillStreakBonus(Vec<u16>);
+
+impl FromStr for KillStreakBonus {
+    type Err = &'static str;
+
+    fn from_str(data: &str) -> Result<Self, Self::Err> {
+        let bonuses = data
+            .split(',')
+            .map(|num| num.parse::<u16>())
+            .collect::<Result<Vec<u16>, _>>()
+            .map_err(|_| "Failed to parse kill streak bonus")?;
+
+        Ok(KillStreakBonus(bonuses))
+    }
+}
+
+struct Fraction(f32);
+
+impl FromStr for Fraction {
+    type Err = &'static str;
+
+    fn from_str(data: &str) -> Result<Self, Self::Err> {
+        let fraction = data
+            .parse::<f32>()
+            .map_err(|_| "Failed to parse fraction")?;
+
+        if !(0.0..=1.0).contains(&fraction) {
+            return Err("Fraction has to be between 0 and 1");
+        }
+
+        Ok(Fraction(fraction))
+    }
+}
+
+struct Edges(server::Edge, server::Edge);
+
+impl FromStr for Edges {
+    type Err = &'static str;
+
+    fn from_str(data: &str) -> Result<Self, Self::Err> {
+        let parse_edge = |edge: Option<&str>| match edge {
+            Some("wrap") => Ok(server::Edge::Wrap),
+            Some("wall") => Ok(server::Edge::Wall),
+            _ => Err("Edges have to be either \"wrap\" or \"wall\""),
+        };
+
+        let mut split = data.split(',');
+
+        let x = parse_edge(split.next())?;
+        let y = parse_edge(split.next())?;
+
+        if split.next().is_some() {
+            return Err("Extra data");
+        }
+
+        Ok(Edges(x, y))
+    }
+}
+
+struct FoodOverflow(server::FoodOverflow);
+
+impl FromStr for FoodOverflow {
+    type Err = &'static str;
+
+    fn from_str(data: &str) -> Result<Self, Self::Err> {
+        match data {
+            "anywhere" => Ok(FoodOverflow(server::FoodOverflow::Anywhere)),
+            "nearby" => Ok(FoodOverflow(server::FoodOverflow::Nearby)),
+            "discard" => Ok(FoodOverflow(server::FoodOverflow::Discard)),
+            _ => Err("Food overflow has to be \"anywhere\", \"nearby\" or \"discard\""),
+        }
+    }
+}
+
+struct HeadOn(server::HeadOn);
+
+impl FromStr for HeadOn {
+    type Err = &'static str;
+
+    fn from_str(data: &str) -> Result<Self, Self::Err> {
+        match data {
+            "both-die" => Ok(HeadOn(server::HeadOn::BothDie)),
+            "longest" => Ok(HeadOn(server::HeadOn::Longest)),
+            "both-credited" => Ok(HeadOn(server::HeadOn::BothCredited)),
+            _ => Err("Head-on has to be \"both-die\", \"longest\" or \"both-credited\""),
+        }
+    }
+}
+
 struct Nickname(String);
 
 impl FromStr for Nickname {
@@ -47,15 +239,56 @@ impl FromStr for Nickname {
             return Err("Empty nicknames are not allowed");
         }
 
-        if data.len() > 10 {
-            return Err("Nickname is too long");
+        // The server escapes nicknames before checking their length, so do the same here
+        if data.escape_default().to_string().len() > MAX_NICKNAME_LENGTH {
+            return Err("Nickname is too long (special characters count as several)");
         }
 
         Ok(Nickname(data.to_owned()))
     }
 }
 
+struct NicknameLength(usize);
+
+impl FromStr for NicknameLength {
+    type Err = String;
+
+    fn from_str(data: &str) -> Result<Self, Self::Err> {
+        let length = data
+            .parse::<usize>()
+            .map_err(|_| "Failed to parse nickname length".to_string())?;
+
+        if length == 0 || length > MAX_NICKNAME_LENGTH {
+            return Err(format!(
+                "Nickname length has to be between 1 and {}",
+                MAX_NICKNAME_LENGTH
+            ));
+        }
+
+        Ok(NicknameLength(length))
+    }
+}
+
+struct PhaseDuration(u32);
+
+impl FromStr for PhaseDuration {
+    type Err = &'static str;
+
+    fn from_str(data: &str) -> Result<Self, Self::Err> {
+        let ticks = data
+            .parse::<u32>()
+            .map_err(|_| "Failed to parse phase duration")?;
+
+        if ticks == 0 || ticks > MAX_PHASE_DURATION {
+            return Err("Phase duration has to be between 1 and 200 ticks");
+        }
+
+        Ok(PhaseDuration(ticks))
+    }
+}
+
 #[derive(StructOpt)]
+#[allow(clippy::large_enum_variant)]
 enum Args {
     Server {
         /// Amount of bots in the game (0-65535)
@@ -66,76 +299,840 @@ enum Args {
         #[structopt(default_value = "10", short = "f")]
         food_rate: NonZeroU8,
 
+        /// Amounts of food that can spawn on a single field, with their weights (amount:weight,...)
+        #[structopt(default_value = "1:1", long = "food-value")]
+        food_value: FoodValues,
+
         /// Ticks per second (1-255)
         #[structopt(default_value = "10", short = "s")]
         game_speed: NonZeroU8,
 
+        /// Speed the game up over time, up to this many ticks per second (1-255)
+        #[structopt(long = "difficulty-ramp")]
+        difficulty_ramp: Option<NonZeroU8>,
+
+        /// How many seconds it takes for the game to reach the speed of --difficulty-ramp
+        #[structopt(default_value = "300", long = "ramp-duration")]
+        ramp_duration: u64,
+
+        /// Slow the game down when the server can't keep up with the game speed,
+        /// and speed it back up when it can. Without this it only warns
+        #[structopt(long = "adapt-speed")]
+        adapt_speed: bool,
+
         /// Player limit for the server (0-65535)
         #[structopt(default_value = "50", short = "m")]
         max_players: u16,
 
-        /// The size of the world (20-65535)
+        /// Maximum length a snake can grow to, food eaten past it only adds to the score
+        #[structopt(short = "l", long = "max-length")]
+        max_length: Option<u16>,
+
+        /// Kick players that don't send any input for this many ticks, bots on this machine are never kicked
+        #[structopt(short = "a", long = "afk-timeout")]
+        afk_timeout: Option<u32>,
+
+        /// The size of the world (20-32767)
         #[structopt(default_value = "200x200", short = "w")]
         world_size: WorldSize,
 
+        /// Refuse to start if the world (-w) and all the rooms (--max-rooms) together
+        /// could take more than this many bytes of memory
+        #[structopt(default_value = "1073741824", long = "max-world-bytes")]
+        max_world_bytes: u64,
+
         /// Initializes server on this port
         #[structopt(default_value = "50403", short = "p")]
         port: u16,
+
+        /// What happens at the left/right and top/bottom edges of the world (wrap or wall)
+        #[structopt(default_value = "wrap,wrap", long = "edges")]
+        edges: Edges,
+
+        /// Listen on a Unix domain socket at this path instead of the port
+        #[structopt(long = "socket", parse(from_os_str))]
+        socket: Option<PathBuf>,
+
+        /// Allow anyone to download the whole world, for external map viewers
+        #[structopt(long = "world-dump")]
+        world_dump: bool,
+
+        /// Give dead players a new snake after a short delay instead of disconnecting them
+        #[structopt(long = "auto-respawn")]
+        auto_respawn: bool,
+
+        /// The maximum total amount of food in the world, food over it is discarded
+        #[structopt(long = "max-food")]
+        max_food: Option<u32>,
+
+        /// What happens to food dropped on a field that already has 255 food: put it in a random
+        /// place (anywhere), on the closest field with room for it (nearby) or lose it (discard)
+        #[structopt(default_value = "anywhere", long = "food-overflow")]
+        food_overflow: FoodOverflow,
+
+        /// Spawn food magnet power-ups that pull in the food around the snake for this many ticks
+        #[structopt(long = "magnet-duration")]
+        magnet_duration: Option<u32>,
+
+        /// Spawn rare phase power-ups that let the snake pass through the bodies of other snakes
+        /// for this many ticks (1-200). Running into a head still kills it
+        #[structopt(long = "phase-duration")]
+        phase_duration: Option<PhaseDuration>,
+
+        /// Allow snakes to turn 180 degrees, crashing into their own necks
+        #[structopt(long = "allow-reverse")]
+        allow_reverse: bool,
+
+        /// What happens when snakes run into each other head-on: they all die (both-die), the
+        /// longest one survives and gets the kills (longest), or they all die and each gets a kill
+        /// for each of the others (both-credited). A snake left running into a protected head
+        /// dies as if it ran into its body
+        #[structopt(default_value = "both-die", long = "head-on")]
+        head_on: HeadOn,
+
+        /// How many seconds to wait for data from a connection before dropping it
+        #[structopt(default_value = "60", long = "timeout")]
+        timeout: NonZeroU64,
+
+        /// Start sudden death after this many ticks without any deaths, making the food rot away
+        #[structopt(long = "sudden-death")]
+        sudden_death: Option<u32>,
+
+        /// Start a famine this many ticks after the last one ended,
+        /// taking the food away from a part of the world for a while
+        #[structopt(long = "famine")]
+        famine: Option<u32>,
+
+        /// How much of the food in its area a famine takes away (0-1)
+        #[structopt(default_value = "0.9", long = "famine-severity")]
+        famine_severity: Fraction,
+
+        /// Make big piles of food worth more than their size, the score of a food field
+        /// is multiplied by 1 + this * (amount - 1) (0-1)
+        #[structopt(default_value = "0", long = "food-bonus")]
+        food_bonus: Fraction,
+
+        /// The longest nickname allowed on this server, special characters count as several (1-10)
+        #[structopt(default_value = "10", long = "max-nickname-length")]
+        max_nickname_length: NicknameLength,
+
+        /// Serve the server statistics over HTTP on this port, as JSON at /stats and for Prometheus at /metrics
+        #[cfg(feature = "http-stats")]
+        #[structopt(long = "http-port")]
+        http_port: Option<u16>,
+
+        /// Accept admin commands (pause, resume, stats, shutdown, help) on a Unix domain socket at this path
+        #[structopt(long = "admin-socket", parse(from_os_str))]
+        admin_socket: Option<PathBuf>,
+
+        /// How many times in a row a bot can fail to join before it gives up,
+        /// by default they keep trying
+        #[structopt(long = "bot-retries")]
+        bot_retries: Option<u32>,
+
+        /// The host the bots connect to, if the server can't be reached through loopback
+        #[structopt(long = "bot-host", default_value = "127.0.0.1")]
+        bot_host: String,
+
+        /// How the bots play, with their weights (personality:weight,...),
+        /// the personalities are greedy, cowardly and aggressive
+        #[structopt(long = "bot-personalities", default_value = "greedy:1")]
+        bot_personalities: BotPersonalities,
+
+        /// Reward kill streaks with bonus score for the 2nd, 3rd, ... kill in a row (e.g. 10,25,50),
+        /// the last bonus is given for all longer streaks
+        #[structopt(long = "kill-streak-bonus")]
+        kill_streak_bonus: Option<KillStreakBonus>,
+
+        /// How much of the score of a dead snake is dropped as food (0-1), the rest is lost
+        #[structopt(default_value = "1", long = "death-food-fraction")]
+        death_food_fraction: Fraction,
+
+        /// Don't let more than one player use the same nickname at a time
+        #[structopt(long = "unique-names")]
+        unique_names: bool,
+
+        /// Don't let human players use nicknames starting with this prefix [default: bot_],
+        /// so they can't pass as bots
+        #[structopt(long = "reserved-prefix")]
+        reserved_prefix: Option<Option<String>>,
+
+        /// Don't let more than this many players play at once from the same IP address.
+        /// Connections from loopback, like local bots, aren't limited
+        #[structopt(long = "max-players-per-ip")]
+        max_players_per_ip: Option<u16>,
+
+        /// Don't let more than this many spectators watch at once
+        #[structopt(long = "max-spectators")]
+        max_spectators: Option<u16>,
+
+        /// Play in rounds: when only one snake is left alive, it wins the round, the world is
+        /// cleared and everyone gets a new snake. Dead players wait for the next round
+        #[structopt(long = "last-standing")]
+        last_standing: bool,
+
+        /// Paint territory: snakes leave a trail outside of their territory, and closing a loop back
+        /// into it claims the trail and the area inside. The territory earns score all the time
+        #[structopt(long = "paint")]
+        paint: bool,
+
+        /// Tell everyone when someone beats the high score since the server started
+        #[structopt(long = "announce-high-scores")]
+        announce_high_scores: bool,
+
+        /// Log players connecting from the loopback address too, they're left out so that the bots
+        /// don't flood the log
+        #[structopt(long = "log-loopback")]
+        log_loopback: bool,
+
+        /// Disable fast mode, so all snakes always move at the same speed
+        #[structopt(long = "no-fast")]
+        no_fast: bool,
+
+        /// The score a snake needs to turn on fast mode
+        #[structopt(long = "fast-min-score", default_value = "1")]
+        fast_min_score: u16,
+
+        /// Put this many scoring zones in the world, snakes that reach one bank all their score
+        /// and shrink back. The banked score is kept when they die
+        #[structopt(long = "scoring-zones", default_value = "0")]
+        scoring_zones: u8,
+
+        /// Bots leave the game to make room for human players, and come back when they leave
+        #[structopt(long = "bots-make-room")]
+        bots_make_room: bool,
+
+        /// Write the leaderboards to this file as JSON every 5 seconds
+        #[structopt(long = "leaderboard-out", parse(from_os_str))]
+        leaderboard_out: Option<PathBuf>,
+
+        /// Let clients create up to this many rooms with their own worlds, with the same settings
+        #[structopt(long = "max-rooms", default_value = "0")]
+        max_rooms: u16,
+
+        /// New snakes can't crash for this many ticks, or until they turn for the first time
+        #[structopt(long = "spawn-protection")]
+        spawn_protection: Option<u32>,
+
+        /// Build walls from this PBM image, black pixels are walls.
+        /// The world size must be a whole multiple of the image size
+        #[structopt(long = "world-from-image", parse(from_os_str))]
+        world_from_image: Option<PathBuf>,
+
+        /// Keep lifetime statistics of players in this file
+        #[structopt(long = "stats", parse(from_os_str))]
+        stats: Option<PathBuf>,
     },
+    /// Play the game. The nickname, server and some flags can be given
+    /// in ~/.config/multisnake/config.toml instead
     Client {
         /// Your nickname (1-10 characters)
-        nickname: Nickname,
+        nickname: Option<Nickname>,
+
+        /// IP address of the server (ignored if --socket is used)
+        ip: Option<String>,
+
+        /// Port of the server [default: 50403]
+        port: Option<u16>,
+
+        /// How many times to try connecting to the server before giving up
+        #[structopt(default_value = "5", short = "r", long = "retries")]
+        retries: u32,
+
+        /// Delay before the first reconnection attempt in milliseconds, doubled after each attempt
+        #[structopt(default_value = "500", short = "d", long = "retry-delay")]
+        retry_delay: u64,
+
+        /// Predict the movement of your snake when frames from server are late
+        #[structopt(long = "predict")]
+        predict: bool,
+
+        /// Turn off --predict if the config file turns it on
+        #[structopt(long = "no-predict", conflicts_with = "predict")]
+        no_predict: bool,
+
+        /// Make the movement smoother by drawing frames in between the frames from server,
+        /// at the cost of showing everything half a tick late
+        #[structopt(long = "smooth")]
+        smooth: bool,
+
+        /// Turn off --smooth if the config file turns it on
+        #[structopt(long = "no-smooth", conflicts_with = "smooth")]
+        no_smooth: bool,
+
+        /// Shade the snakes from head to tail, needs a terminal with true color support
+        #[structopt(long = "gradient")]
+        gradient: bool,
+
+        /// Turn off --gradient if the config file turns it on
+        #[structopt(long = "no-gradient", conflicts_with = "gradient")]
+        no_gradient: bool,
+
+        /// Steer with left and right only, turning relative to where the snake is heading
+        #[structopt(long = "relative-controls")]
+        relative_controls: bool,
 
-        /// IP address of the server
+        /// Turn off --relative-controls if the config file turns it on
+        #[structopt(long = "no-relative-controls", conflicts_with = "relative_controls")]
+        no_relative_controls: bool,
+
+        /// Dim the screen and show [AFK] in the status bar after 20 seconds without pressing any keys
+        #[structopt(long = "afk-dim")]
+        afk_dim: bool,
+
+        /// Turn off --afk-dim if the config file turns it on
+        #[structopt(long = "no-afk-dim", conflicts_with = "afk_dim")]
+        no_afk_dim: bool,
+
+        /// Draw only ASCII characters, for terminals without Unicode support
+        #[structopt(long = "ascii")]
+        ascii: bool,
+
+        /// Turn off --ascii if the config file turns it on
+        #[structopt(long = "no-ascii", conflicts_with = "ascii")]
+        no_ascii: bool,
+
+        /// Ring the terminal bell when you eat
+        #[structopt(long = "bell")]
+        bell: bool,
+
+        /// Turn off --bell if the config file turns it on
+        #[structopt(long = "no-bell", conflicts_with = "bell")]
+        no_bell: bool,
+
+        /// Draw at most this many frames per second, for slow terminals or fast servers.
+        /// The frames in between are skipped, the newest one is always drawn in the end
+        #[structopt(long = "max-fps")]
+        max_fps: Option<NonZeroU8>,
+
+        /// Print your final score, kills, place and time survived when leaving the game
+        #[structopt(long = "summary")]
+        summary: bool,
+
+        /// Turn off --summary if the config file turns it on
+        #[structopt(long = "no-summary", conflicts_with = "summary")]
+        no_summary: bool,
+
+        /// Connect to the server's Unix domain socket at this path instead of the IP
+        #[structopt(long = "socket", parse(from_os_str))]
+        socket: Option<PathBuf>,
+
+        /// Watch the game instead of playing, the camera is moved with the movement keys
+        #[structopt(long = "spectate")]
+        spectate: bool,
+
+        /// Join this room instead of the main game, it's created if it doesn't exist yet
+        #[structopt(long = "room")]
+        room: Option<String>,
+
+        /// Append every message received from the server to this file, with the time it was received
+        #[structopt(long = "dump-frames", parse(from_os_str))]
+        dump_frames: Option<PathBuf>,
+
+        /// Record the game to this file as an asciinema cast, to play it back or share it
+        #[structopt(long = "cast", parse(from_os_str))]
+        cast: Option<PathBuf>,
+
+        /// Show your lifetime statistics on the server instead of playing
+        #[structopt(long = "stats")]
+        stats: bool,
+
+        /// How many seconds to wait for data from the server before giving up
+        #[structopt(default_value = "60", long = "timeout")]
+        timeout: NonZeroU64,
+    },
+    /// Run bots in this process, playing on any server
+    Bots {
+        /// Amount of bots to run (0-65535)
+        count: u16,
+
+        /// IP address of the server (ignored if --socket is used)
+        ip: String,
+
+        /// Port of the server
+        #[structopt(default_value = "50403")]
+        port: u16,
+
+        /// Connect to the server's Unix domain socket at this path instead of the IP
+        #[structopt(long = "socket", parse(from_os_str))]
+        socket: Option<PathBuf>,
+
+        /// How many times in a row a bot can fail to join before it gives up
+        #[structopt(default_value = "5", short = "r", long = "retries")]
+        retries: u32,
+
+        /// How the bots play, with their weights (personality:weight,...),
+        /// the personalities are greedy, cowardly and aggressive
+        #[structopt(long = "personalities", default_value = "greedy:1")]
+        personalities: BotPersonalities,
+    },
+    /// Show how the food is spread over the world, the server must allow world dumps
+    Heatmap {
+        /// IP address of the server (ignored if --socket is used)
         ip: String,
 
         /// Port of the server
         #[structopt(default_value = "50403")]
         port: u16,
+
+        /// Connect to the server's Unix domain socket at this path instead of the IP
+        #[structopt(long = "socket", parse(from_os_str))]
+        socket: Option<PathBuf>,
     },
 }
 
+impl Args {
+    /// Checks the flags that don't make sense together, and returns what's wrong with them
+    fn conflicts(&self) -> Vec<&'static str> {
+        let mut conflicts = Vec::new();
+        match self {
+            Args::Server {
+                bots,
+                game_speed,
+                difficulty_ramp,
+                max_players,
+                socket,
+                sudden_death,
+                admin_socket,
+                bots_make_room,
+                leaderboard_out,
+                stats,
+                no_fast,
+                fast_min_score,
+                max_players_per_ip,
+                reserved_prefix,
+                last_standing,
+                ..
+            } => {
+                if *max_players == 0 {
+                    conflicts.push("-m has to be at least 1, or nobody can play");
+                }
+                if bots > max_players {
+                    conflicts.push("-b can't be more than -m, the extra bots would never get in");
+                }
+                if *bots_make_room && *bots == 0 {
+                    conflicts.push("--bots-make-room needs some bots (-b)");
+                }
+                if matches!(difficulty_ramp, Some(max_speed) if max_speed <= game_speed) {
+                    conflicts.push("--difficulty-ramp has to be faster than -s");
+                }
+                if sudden_death.is_some() && *max_players < 2 {
+                    conflicts.push("--sudden-death needs at least 2 players (-m)");
+                }
+                if *last_standing && *max_players < 2 {
+                    conflicts.push("--last-standing needs at least 2 players (-m)");
+                }
+                if socket.is_some() && socket == admin_socket {
+                    conflicts.push("--socket and --admin-socket can't be the same path");
+                }
+                if let (Some(leaderboard_out), Some(stats)) = (leaderboard_out, stats) {
+                    // Each of them is written through a temporary file next to it
+                    let leaderboard_temporary =
+                        server::temporary_file(leaderboard_out, server::leaderboard::WRITER_NAME);
+                    let stats_temporary = server::temporary_file(stats, server::stats::WRITER_NAME);
+                    if leaderboard_out == stats {
+                        conflicts.push("--leaderboard-out and --stats can't be the same file");
+                    } else if leaderboard_temporary == *stats
+                        || stats_temporary == *leaderboard_out
+                        || leaderboard_temporary == stats_temporary
+                    {
+                        conflicts.push(
+                            "--leaderboard-out and --stats can't be each other's temporary files",
+                        );
+                    }
+                }
+                if *fast_min_score == 0 {
+                    conflicts.push("--fast-min-score has to be at least 1, fast mode costs score");
+                }
+                if *no_fast && *fast_min_score != 1 {
+                    conflicts.push("--fast-min-score does nothing with --no-fast");
+                }
+                if matches!(reserved_prefix, Some(Some(prefix)) if prefix.is_empty()) {
+                    conflicts.push("--reserved-prefix can't be empty, or only bots can play");
+                }
+                if *max_players_per_ip == Some(0) {
+                    conflicts.push("--max-players-per-ip has to be at least 1, or nobody can play");
+                }
+            }
+            Args::Client {
+                spectate,
+                stats,
+                room,
+                dump_frames,
+                cast,
+                ..
+            } => {
+                if *stats && *spectate {
+                    conflicts.push("--stats and --spectate can't be used together");
+                }
+                if *stats && room.is_some() {
+                    conflicts.push("--stats and --room can't be used together");
+                }
+                if *stats && cast.is_some() {
+                    conflicts.push("--stats and --cast can't be used together");
+                }
+                if cast.is_some() && cast == dump_frames {
+                    conflicts.push("--cast and --dump-frames can't be the same file");
+                }
+            }
+            Args::Bots { .. } | Args::Heatmap { .. } => {}
+        }
+        conflicts
+    }
+}
+
+/// Whether a flag of the client is on. The command line can turn it on or off,
+/// otherwise the config file decides
+fn flag(on: bool, off: bool, config: bool) -> bool {
+    on || (config && !off)
+}
+
+/// Resolves the IP address of the given hostname, and exits if it can't be found.
+/// IP addresses and localhost are returned as they are
+fn resolve_host(host: String) -> String {
+    if host == "localhost" || host.parse::<IpAddr>().is_ok() {
+        return host;
+    }
+    match lookup_host(&host).map(|addresses| addresses.first().copied()) {
+        Ok(Some(address)) => address.to_string(),
+        _ => {
+            println!("error: can't resolve the IP of host {}", host);
+            std::process::exit(1);
+        }
+    }
+}
+
 fn main() {
     let args = Args::from_args();
 
+    // Refuse to start with flags that would only make a broken game
+    let conflicts = args.conflicts();
+    if !conflicts.is_empty() {
+        for conflict in conflicts {
+            println!("error: {}", conflict);
+        }
+        std::process::exit(1);
+    }
+
     match args {
         Args::Server {
             bots,
             food_rate,
+            food_value: FoodValues(food_values),
             game_speed,
+            difficulty_ramp,
+            ramp_duration,
+            adapt_speed,
             max_players,
+            max_length,
+            afk_timeout,
             world_size: WorldSize(width, height),
+            max_world_bytes,
             port,
+            socket,
+            edges: Edges(edge_x, edge_y),
+            world_dump,
+            auto_respawn,
+            stats,
+            max_food,
+            food_overflow: FoodOverflow(food_overflow),
+            magnet_duration,
+            phase_duration,
+            allow_reverse,
+            head_on: HeadOn(head_on),
+            timeout,
+            sudden_death,
+            famine,
+            famine_severity: Fraction(famine_severity),
+            food_bonus: Fraction(food_bonus),
+            max_nickname_length: NicknameLength(max_nickname_length),
+            #[cfg(feature = "http-stats")]
+            http_port,
+            admin_socket,
+            bot_retries,
+            bot_host,
+            bot_personalities: BotPersonalities(bot_personalities),
+            kill_streak_bonus,
+            death_food_fraction: Fraction(death_food_fraction),
+            unique_names,
+            reserved_prefix,
+            max_players_per_ip,
+            max_spectators,
+            last_standing,
+            paint,
+            announce_high_scores,
+            log_loopback,
+            no_fast,
+            fast_min_score,
+            scoring_zones,
+            bots_make_room,
+            leaderboard_out,
+            max_rooms,
+            spawn_protection,
+            world_from_image,
         } => {
+            // Better to stop here than to run out of memory while allocating the world
+            // Every room gets a world of its own, as big as the main one
+            let worlds = 1 + max_rooms as u64;
+            if let Err(e) =
+                server::check_world_bytes((width, height), paint, worlds, max_world_bytes)
+            {
+                println!("error: {}", e);
+                std::process::exit(1);
+            }
+
+            // A big food rate leaves a small world with barely any food, the server adds more
+            let food = server::food_for_rate((width, height), food_rate.get());
+            if food < server::MIN_INITIAL_FOOD {
+                println!(
+                    "warning: -f {} gives a {}x{} world only {} food, it starts with {} instead",
+                    food_rate,
+                    width,
+                    height,
+                    food,
+                    server::MIN_INITIAL_FOOD
+                );
+            }
+
+            let wall_map = match world_from_image {
+                Some(path) => match server::map::load(&path, (width, height)) {
+                    Ok(wall_map) => Some(Arc::new(wall_map)),
+                    Err(e) => {
+                        println!("error: {}", e);
+                        std::process::exit(1);
+                    }
+                },
+                None => None,
+            };
+
+            // Make sure the bots will find the server before starting it.
+            // Over the Unix domain socket they don't need the host at all
+            let bot_host = if socket.is_some() {
+                bot_host
+            } else {
+                resolve_host(bot_host)
+            };
+
             server::Server::start(
-                max_players,
-                game_speed.into(),
-                port,
-                (width, height),
-                food_rate.into(),
-                bots,
+                server::ServerConfig {
+                    max_players,
+                    world_size: (width, height),
+                    game_speed: game_speed.into(),
+                    max_game_speed: difficulty_ramp.map(Into::into),
+                    ramp_duration: Duration::from_secs(ramp_duration),
+                    adapt_speed,
+                    food_rate: food_rate.into(),
+                    port,
+                    bots,
+                    bots_make_room,
+                    socket,
+                    max_length,
+                    food_values,
+                    afk_timeout,
+                    edges: (edge_x, edge_y),
+                    world_dump,
+                    auto_respawn,
+                    max_food,
+                    food_overflow,
+                    magnet_duration,
+                    phase_duration: phase_duration.map(|PhaseDuration(ticks)| ticks),
+                    allow_reverse,
+                    head_on,
+                    read_timeout: Duration::from_secs(timeout.get()),
+                    sudden_death,
+                    famine,
+                    famine_severity,
+                    food_bonus,
+                    max_nickname_length,
+                    #[cfg(feature = "http-stats")]
+                    http_port,
+                    kill_streak_bonus: kill_streak_bonus.map(|KillStreakBonus(bonuses)| bonuses),
+                    death_food_fraction,
+                    unique_names,
+                    reserved_prefix: reserved_prefix
+                        .map(|prefix| prefix.unwrap_or_else(|| "bot_".to_string())),
+                    max_players_per_ip,
+                    max_spectators,
+                    last_standing,
+                    paint,
+                    announce_high_scores,
+                    log_loopback,
+                    fast_mode_disabled: no_fast,
+                    fast_min_score,
+                    leaderboard_out,
+                    max_rooms,
+                    spawn_protection,
+                    wall_map,
+                    stats,
+                    scoring_zones,
+                    admin_socket,
+                    bot_retries,
+                    bot_host,
+                    bot_personalities,
+                },
+                Arc::new(AtomicBool::new(false)),
             );
         },
         Args::Client {
-            nickname: Nickname(nickname),
-            mut ip,
+            nickname,
+            ip,
             port,
+            retries,
+            retry_delay,
+            predict,
+            no_predict,
+            smooth,
+            no_smooth,
+            gradient,
+            no_gradient,
+            relative_controls,
+            no_relative_controls,
+            afk_dim,
+            no_afk_dim,
+            ascii,
+            no_ascii,
+            bell,
+            no_bell,
+            max_fps,
+            summary,
+            no_summary,
+            socket,
+            spectate,
+            room,
+            dump_frames,
+            cast,
+            stats,
+            timeout,
         } => {
-            // Resolve the address of the entered hostname
-            if ip != "localhost" {
-                match lookup_host(&ip).expect("could not resolve the IP").get(0) {
-                    Some(addr) => {
-                        ip = addr.to_string();
+            // Fill in whatever wasn't given from the config file
+            let config = match config::Config::load() {
+                Ok(config) => config,
+                Err(e) => {
+                    println!("error: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            let nickname = match nickname {
+                Some(Nickname(nickname)) => nickname,
+                None => match config.nickname.as_deref().map(str::parse) {
+                    Some(Ok(Nickname(nickname))) => nickname,
+                    Some(Err(e)) => {
+                        println!("error: nickname in {}: {}", config.path.display(), e);
+                        std::process::exit(1);
                     }
                     None => {
-                        println!("Could not resolve the IP of host {}", ip);
-                        return;
+                        println!("error: no nickname given, and the config file has none either");
+                        std::process::exit(1);
                     }
+                },
+            };
+            let socket = socket.or(config.socket);
+            let mut ip = match ip.or(config.server) {
+                Some(ip) => ip,
+                // The IP isn't needed to connect to a socket
+                None if socket.is_some() => "localhost".to_string(),
+                None => {
+                    println!("error: no server given, and the config file has none either");
+                    std::process::exit(1);
                 }
+            };
+            let port = port.or(config.port).unwrap_or(DEFAULT_PORT);
+            let predict = flag(predict, no_predict, config.predict);
+            let smooth = flag(smooth, no_smooth, config.smooth);
+            let gradient = flag(gradient, no_gradient, config.gradient);
+            let relative_controls = flag(
+                relative_controls,
+                no_relative_controls,
+                config.relative_controls,
+            );
+            let afk_dim = flag(afk_dim, no_afk_dim, config.afk_dim);
+            let ascii = flag(ascii, no_ascii, config.ascii);
+            let bell = flag(bell, no_bell, config.bell);
+            let max_fps = max_fps.or(config.max_fps);
+            let summary = flag(summary, no_summary, config.summary);
+            if !ascii && !client::locale_is_utf8() {
+                println!("warning: the locale isn't UTF-8, use --ascii if the game looks garbled");
+            }
+
+            // Resolve the address of the entered hostname, the socket doesn't need it
+            if socket.is_none() {
+                ip = resolve_host(ip);
+            }
+
+            if stats {
+                client::show_stats(&ip, port, socket.as_deref(), &nickname);
+                return;
             }
 
             // Start the client
-            client::start(ip, port, nickname);
+            client::start(client::ClientOptions {
+                ip,
+                port,
+                nickname,
+                retry: client::RetryOptions {
+                    attempts: retries,
+                    base_delay: Duration::from_millis(retry_delay),
+                },
+                predict,
+                socket,
+                spectate,
+                room,
+                timeout: Duration::from_secs(timeout.get()),
+                smooth,
+                gradient,
+                relative_controls,
+                afk_dim,
+                dump_frames,
+                ascii,
+                bell,
+                max_fps,
+                summary,
+                cast,
+            });
+        }
+        Args::Bots {
+            count,
+            mut ip,
+            port,
+            socket,
+            retries,
+            personalities: BotPersonalities(personalities),
+        } => {
+            // Resolve the address of the entered hostname, the socket doesn't need it
+            if socket.is_none() {
+                ip = resolve_host(ip);
+            }
+
+            // The server may allow shorter nicknames than the default
+            let max_nickname_length = client::max_nickname_length(&ip, port, socket.as_deref())
+                .unwrap_or(MAX_NICKNAME_LENGTH);
+
+            println!("Spawning {} bots...", count);
+            // The bots run until they can't join anymore
+            for handle in server::bot::spawn_bots(
+                count,
+                ip,
+                port,
+                socket,
+                max_nickname_length,
+                None,
+                Some(retries),
+                personalities,
+            ) {
+                handle.join().unwrap();
+            }
+        }
+        Args::Heatmap {
+            mut ip,
+            port,
+            socket,
+        } => {
+            // Resolve the address of the entered hostname, the socket doesn't need it
+            if socket.is_none() {
+                ip = resolve_host(ip);
+            }
+
+            client::show_food_heatmap(&ip, port, socket.as_deref());
         }
     }
 }
@@ -1,14 +1,27 @@
-mod bot;
-
+pub mod admin;
+pub mod bot;
+#[cfg(feature = "http-stats")]
+pub mod http;
+pub mod leaderboard;
+pub mod map;
+pub mod paint;
+pub mod rooms;
+pub mod stats;
+#[cfg(test)]
+mod tests;
+
+use crate::stream::{Listener, NetError, Stream};
 use rand::prelude::*;
-use std::collections::{HashMap, VecDeque};
+use stats::Stats;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
 use std::io;
-use std::io::prelude::*;
-use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::process::exit;
-use std::sync::{Arc, Mutex, MutexGuard};
-use std::thread;
-use std::thread::sleep;
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, AtomicU8, Ordering};
+use std::sync::{mpsc, Arc, Mutex, MutexGuard};
+use std::thread::{self, sleep, JoinHandle};
 use std::time::{Duration, Instant};
 
 // Magic networking bytes:
@@ -19,35 +32,424 @@ const MAGIC_NET_DEATH: u8 = 0x03;
 const MAGIC_NET_GAME_DATA: u8 = 0x04;
 const MAGIC_NET_ERROR: u8 = 0x05;
 const MAGIC_NET_JOINED_GAME: u8 = 0x06;
+const MAGIC_NET_KILLFEED: u8 = 0x07;
 const MAGIC_NET_TOGGLE_FAST: u8 = 0x08;
 const MAGIC_NET_EXIT: u8 = 0x09;
-
-/// The main structure, holds everything related to server together
-pub struct Server {
+const MAGIC_NET_PING: u8 = 0x0A;
+const MAGIC_NET_PONG: u8 = 0x0B;
+const MAGIC_NET_REQUEST_TO_SPECTATE: u8 = 0x0C;
+const MAGIC_NET_MOVE_CAMERA: u8 = 0x0D;
+const MAGIC_NET_WORLD_DUMP: u8 = 0x0E;
+const MAGIC_NET_RESPAWNED: u8 = 0x0F;
+const MAGIC_NET_PLAYER_STATS: u8 = 0x10;
+const MAGIC_NET_SUDDEN_DEATH: u8 = 0x11;
+const MAGIC_NET_PAUSED: u8 = 0x12;
+const MAGIC_NET_KILL_STREAK: u8 = 0x13;
+const MAGIC_NET_REQUEST_TO_PLAY_AS_BOT: u8 = 0x14;
+const MAGIC_NET_JOIN_ROOM: u8 = 0x15;
+const MAGIC_NET_FOLLOW_NEXT_SNAKE: u8 = 0x16;
+const MAGIC_NET_VICTORY: u8 = 0x17;
+const MAGIC_NET_ANNOUNCE: u8 = 0x18;
+const MAGIC_NET_FAMINE: u8 = 0x19;
+
+// The kinds of errors, sent as the second byte of each error message, before the error text
+const ERROR_BAD_NICKNAME: u8 = 0;
+const ERROR_SERVER_FULL: u8 = 1;
+const ERROR_DISABLED: u8 = 2;
+const ERROR_NICKNAME_TAKEN: u8 = 3;
+const ERROR_BAD_ROOM_NAME: u8 = 4;
+const ERROR_TOO_MANY_FROM_IP: u8 = 5;
+const ERROR_NO_SPACE: u8 = 6;
+const ERROR_TOO_MANY_SPECTATORS: u8 = 7;
+
+// The sections of a world dump, sent as the second byte of each world dump message
+const WORLD_DUMP_HEADER: u8 = 0;
+const WORLD_DUMP_SNAKE_PARTS: u8 = 1;
+const WORLD_DUMP_FOODS: u8 = 2;
+const WORLD_DUMP_END: u8 = 3;
+
+// The maximum size of a single world dump message, so it always fits in the 2 byte length prefix
+const WORLD_DUMP_MESSAGE_SIZE: usize = 60000;
+
+// How long a dead player waits for a new snake when auto respawn is on
+const RESPAWN_DELAY: Duration = Duration::from_secs(2);
+
+// The biggest first message a client can send, a request to play with the nickname being the longest
+const MAX_REQUEST_SIZE: usize = 64;
+// The biggest message a player or a spectator can send during the game, a pong being the longest
+const MAX_INPUT_SIZE: usize = 8;
+
+// How far (in snake fields) food is spilled from a full field, with `FoodOverflow::Nearby`
+const FOOD_SPILL_RADIUS: i32 = 2;
+// How far from the head food is pulled in by a snake with a food magnet
+const MAGNET_RADIUS: i32 = 3;
+// A food magnet spawns on average once in this many ticks
+const MAGNET_SPAWN_CHANCE: u32 = 200;
+// How many food magnets can be in the world at once
+const MAX_MAGNETS: usize = 3;
+// A phase power-up spawns on average once in this many ticks
+const PHASE_SPAWN_CHANCE: u32 = 600;
+// How many phase power-ups can be in the world at once
+const MAX_PHASES: usize = 1;
+
+// How often (in ticks) the food rots during sudden death
+const SUDDEN_DEATH_DECAY_INTERVAL: u32 = 20;
+// Every time the food rots, each food field loses 1 food with a chance of 1 in this many
+const SUDDEN_DEATH_DECAY_CHANCE: u32 = 10;
+
+// How many ticks a famine lasts
+const FAMINE_DURATION: u32 = 300;
+// A famine covers this part of the width and the height of the world
+const FAMINE_SIZE_DIVISOR: u16 = 3;
+
+// How many ticks a snake has to get the next kill in, for it to count towards its kill streak
+const KILL_STREAK_WINDOW: u32 = 30;
+
+// How many players are in each leaderboard
+const LEADERBOARD_LENGTH: usize = 9;
+// How often the leaderboards are written to the file, if they are
+const LEADERBOARD_WRITE_INTERVAL: Duration = Duration::from_secs(5);
+// How often the server checks whether it keeps up with the game speed
+const LOAD_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+// The server is falling behind if at least this share of the ticks took longer than they should
+const OVERRUN_THRESHOLD: f64 = 0.5;
+// When the game is slowed down, it's set to this share of the ticks per second the server can manage
+const SLOW_DOWN_HEADROOM: f64 = 0.9;
+// And it's only sped up again to this share of them, so that it doesn't flip back and forth
+const SPEED_UP_HEADROOM: f64 = 0.5;
+
+// The shortest time between two announcements of a new high score,
+// so that a snake that keeps on growing doesn't spam everyone
+const HIGH_SCORE_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(15);
+
+// How often the threads accepting connections check whether the server was shut down
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(100);
+
+// How many fields the spectator's camera moves at once
+const CAMERA_STEP: i32 = 5;
+
+// How many pings in a row a player can leave unanswered before the connection is considered dead
+const MAX_MISSED_PINGS: u8 = 5;
+// How many frames in a row a client can leave unread before the connection is considered dead.
+// Frames are skipped instead of queued while the client is behind
+const MAX_SKIPPED_FRAMES: u32 = 100;
+// How many direction changes a player can send in a single tick before it gets flagged.
+// An honest client sends at most a few, depending on how fast the player presses the keys
+const MAX_DIRECTION_CHANGES_PER_TICK: u32 = 8;
+
+// How many fields outside of the player's view snake parts are still sent,
+// so that snakes entering the view from the edge don't pop in abruptly
+const SNAKE_PARTS_MARGIN: i32 = 3;
+// The longest message the server can send, its length has to fit in the 2 byte prefix
+const MAX_MESSAGE_SIZE: usize = u16::MAX as usize;
+
+// The width and height of a scoring zone in fields
+const ZONE_SIZE: u16 = 6;
+
+// The ID of the snake parts that are walls of the map, no player ever gets it
+pub const WALL_ID: u16 = u16::MAX;
+
+// The biggest width and height of the world. There are 2 food fields per field in each direction,
+// and their coordinates are sent as 2 bytes each
+pub const MAX_WORLD_SIZE: u16 = 32767;
+
+// The length of the rules at the end of the confirmation that a player joined the game
+pub const RULES_LENGTH: usize = 32;
+// The biggest response to a request to join the game,
+// the confirmation with 255 scoring zones and the rules being the longest
+pub const MAX_JOIN_RESPONSE_SIZE: usize = 12 + 255 * 4 + RULES_LENGTH;
+
+// The least food a world starts with, so that a big food rate doesn't leave a small world
+// with barely anything to eat
+pub const MIN_INITIAL_FOOD: u32 = 20;
+
+/// The settings of a server. Rooms are created with a copy of them
+#[derive(Clone)]
+pub struct ServerConfig {
     /// Maximum limit of the players connected to this server
     pub max_players: u16,
-    /// A hash map mapping player IDs to their structures
-    pub players: Arc<Mutex<HashMap<u16, Player>>>,
-    /// A hash map mapping player IDs to their corresponding TCP streams
-    pub client_streams: Arc<Mutex<HashMap<u16, TcpStream>>>,
     /// The size of the world that the server hosts
     pub world_size: (u16, u16),
-    /// Holds data about the world: snake parts and foods.
-    pub world: Arc<Mutex<World>>,
     /// The amount of frames/ticks per second. Bigger number = faster gameplay
     pub game_speed: u8,
+    /// The game speed that the game ramps up to over `ramp_duration`, `None` if it doesn't change
+    pub max_game_speed: Option<u8>,
+    /// How long it takes for the game speed to reach `max_game_speed`
+    pub ramp_duration: Duration,
+    /// Whether the game slows down when the server can't keep up with the game speed
+    pub adapt_speed: bool,
     /// How much food should be constantly in the world in relation to the world size
     pub food_rate: u8,
     /// The port that the server binds to
     pub port: u16,
     /// The amount of bots playing in this server
     pub bots: u16,
+    /// Whether bots leave to make room for human players, and come back when they leave
+    pub bots_make_room: bool,
+    /// The path of the Unix domain socket that the server listens on instead of the port
+    pub socket: Option<PathBuf>,
+    /// The maximum length a snake can grow to, `None` if unlimited
+    pub max_length: Option<u16>,
+    /// Amounts of food that can spawn on a single field, paired with their weights
+    pub food_values: Vec<(u8, u16)>,
+    /// The amount of ticks a player can go without sending any input before getting kicked,
+    /// `None` if AFK players are never kicked. Bots on this machine are never kicked
+    pub afk_timeout: Option<u32>,
+    /// What happens at the edges of the world on the X and Y axes
+    pub edges: (Edge, Edge),
+    /// Whether anyone can request a dump of the whole world
+    pub world_dump: bool,
+    /// Whether dead players get a new snake automatically instead of being disconnected
+    pub auto_respawn: bool,
+    /// The maximum total amount of food in the world, `None` if unlimited
+    pub max_food: Option<u32>,
+    /// Where food goes when the field it's dropped on already has the most food it can hold
+    pub food_overflow: FoodOverflow,
+    /// How many ticks a food magnet lasts, `None` if food magnets are disabled
+    pub magnet_duration: Option<u32>,
+    /// How many ticks a phase power-up lasts, `None` if phasing is disabled
+    pub phase_duration: Option<u32>,
+    /// Whether snakes are allowed to turn 180 degrees (and most likely crash into themselves)
+    pub allow_reverse: bool,
+    /// What happens when snakes run into each other head-on
+    pub head_on: HeadOn,
+    /// How long to wait for data from a connection before giving up on it
+    pub read_timeout: Duration,
+    /// How many ticks without any deaths start sudden death, `None` if there's no sudden death
+    pub sudden_death: Option<u32>,
+    /// How many ticks pass between the end of a famine and the start of the next one,
+    /// `None` if there are no famines
+    pub famine: Option<u32>,
+    /// The share of the food (0-1) that a famine takes away from its area
+    pub famine_severity: f32,
+    /// How much the score multiplier of a food field grows with every food point beyond the first (0-1),
+    /// so that big piles of food give bonus score
+    pub food_bonus: f32,
+    /// The longest nickname allowed, after escaping
+    pub max_nickname_length: usize,
+    /// The port to serve the statistics on over HTTP, `None` if they're not served
+    #[cfg(feature = "http-stats")]
+    pub http_port: Option<u16>,
+    /// The file to keep writing the leaderboards to, `None` if they're not written
+    pub leaderboard_out: Option<PathBuf>,
+    /// How many rooms can be created besides the main game, 0 if rooms are disabled
+    pub max_rooms: u16,
+    /// For how many ticks new snakes can't crash, `None` if they're not protected
+    pub spawn_protection: Option<u32>,
+    /// Which fields of the world are walls, row by row. `None` if there's no map
+    pub wall_map: Option<Arc<Vec<bool>>>,
+    /// Bonus score for the 2nd, 3rd, ... kill in a kill streak, the last one is used for
+    /// all longer streaks. `None` if kill streaks aren't rewarded
+    pub kill_streak_bonus: Option<Vec<u16>>,
+    /// How much of the score of a dead snake is dropped as food (0-1)
+    pub death_food_fraction: f32,
+    /// Whether a nickname can be used by only one player at a time
+    pub unique_names: bool,
+    /// Nicknames starting with this are only for bots, `None` if humans can use any nickname
+    pub reserved_prefix: Option<String>,
+    /// Whether everyone is told when someone beats the high score of this session
+    pub announce_high_scores: bool,
+    /// Whether players connecting from the loopback address (like the bots) are logged too
+    pub log_loopback: bool,
+    /// Whether the game is played in rounds, each won by the last snake left alive.
+    /// Dead players wait for the next round
+    pub last_standing: bool,
+    /// Whether snakes paint territory: closing a loop back to their territory claims the area
+    /// inside, and the territory earns score
+    pub paint: bool,
+    /// How many players can play at once from a single IP address, not counting loopback.
+    /// `None` if unlimited
+    pub max_players_per_ip: Option<u16>,
+    /// How many spectators can watch at once, `None` if as many as there are IDs
+    pub max_spectators: Option<u16>,
+    /// Whether fast mode is disabled, so snakes only ever move 1 field per tick
+    pub fast_mode_disabled: bool,
+    /// The score a snake needs to be able to turn on fast mode
+    pub fast_min_score: u16,
+    /// The file the lifetime statistics of players are kept in, `None` if they're not kept
+    pub stats: Option<PathBuf>,
+    /// How many scoring zones the world has
+    pub scoring_zones: u8,
+    /// The Unix domain socket to accept admin commands on, `None` if there's none
+    pub admin_socket: Option<PathBuf>,
+    /// How many times in a row a bot can fail to join before it gives up, `None` if it never does
+    pub bot_retries: Option<u32>,
+    /// The host the bots connect to
+    pub bot_host: String,
+    /// How the bots play, paired with their weights
+    pub bot_personalities: Vec<(bot::Personality, u16)>,
+}
+
+/// The main structure, holds everything related to server together
+pub struct Server {
+    /// The settings the server was started with
+    pub config: Arc<ServerConfig>,
+    /// A hash map mapping player IDs to their structures
+    pub players: Arc<Mutex<HashMap<u16, Player>>>,
+    /// A hash map mapping player IDs to their corresponding TCP streams
+    pub client_streams: Arc<Mutex<HashMap<u16, Stream>>>,
+    /// A hash map mapping spectator IDs to their structures
+    pub spectators: Arc<Mutex<HashMap<u16, Spectator>>>,
+    /// Dead players waiting to get a new snake
+    pub respawning: Arc<Mutex<Vec<Respawn>>>,
+    /// Holds data about the world: snake parts and foods.
+    pub world: Arc<Mutex<World>>,
+    /// The game speed right now
+    pub current_game_speed: Arc<AtomicU8>,
+    /// How many of the bots are allowed to play now
+    pub active_bots: Arc<AtomicU16>,
+    /// Lifetime statistics of players, `None` if they're not kept
+    pub stats: Option<Arc<Mutex<Stats>>>,
+    /// Whether the game is paused by an admin: snakes don't move, but connections are kept alive
+    pub paused: Arc<AtomicBool>,
+    /// Set to stop the game loop and disconnect everyone, which makes `Server::start` return
+    pub shutdown: Arc<AtomicBool>,
+    /// When the server was started
+    pub started_at: Instant,
+    /// How many connections were accepted since the server started
+    pub connections: Arc<AtomicU64>,
+    /// The different addresses that connected since the server started
+    pub addresses: Arc<Mutex<HashSet<IpAddr>>>,
+    /// The most human players that were playing at once
+    pub peak_humans: Arc<AtomicU16>,
+    /// Rooms created on demand by the clients, each with its own world and game loop
+    pub rooms: Arc<Mutex<HashMap<String, Server>>>,
+    /// The threads running the game loops of the rooms, joined when the server is shut down
+    pub room_threads: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    /// The best score of this session and the nickname of the snake that got it
+    pub high_score: Arc<Mutex<(u16, String)>>,
+    /// The top left corners of the scoring zones, where snakes bank the food they carry
+    pub scoring_zones: Vec<SnakePartPos>,
+    /// Counters for the HTTP statistics
+    #[cfg(feature = "http-stats")]
+    pub metrics: Arc<http::Metrics>,
 }
 
 /// Holds snake parts and food data together
 pub struct World {
     pub snake_parts: Vec<SField>,
     pub foods: Vec<FField>,
+    /// The total amount of food in the world
+    pub food_total: u32,
+    /// Food that had nowhere to go because the world was full, placed as soon as there's room
+    pub food_owed: u32,
+    /// Positions of the food magnet power-ups
+    pub magnets: HashSet<SnakePartPos>,
+    /// Positions of the phase power-ups
+    pub phases: HashSet<SnakePartPos>,
+    /// The ID of the snake that owns each field as its territory, or 0 if nobody does.
+    /// Empty unless the snakes paint territory
+    pub owners: Vec<u16>,
+    /// How many fields of territory each snake owns
+    pub territory: HashMap<u16, u32>,
+    /// The area where no food grows right now, `None` if there's no famine
+    pub famine: Option<Famine>,
+}
+
+/// How long the ticks took since the last check, to notice when the server can't keep up
+/// with the game speed
+struct TickLoad {
+    checked_at: Instant,
+    ticks: u32,
+    /// How many of the ticks took longer than they should
+    overruns: u32,
+    /// The time spent working on the ticks, not counting the waiting between them
+    work_time: Duration,
+    /// Whether the server was falling behind at the last check
+    overloaded: bool,
+    /// The game speed that the game is slowed down to, `None` if it runs at full speed
+    cap: Option<u8>,
+}
+
+impl TickLoad {
+    fn new() -> Self {
+        TickLoad {
+            checked_at: Instant::now(),
+            ticks: 0,
+            overruns: 0,
+            work_time: Duration::from_secs(0),
+            overloaded: false,
+            cap: None,
+        }
+    }
+    /// Remembers how long a tick took
+    fn record(&mut self, work_time: Duration, tick_time: Duration) {
+        self.ticks += 1;
+        self.work_time += work_time;
+        if work_time > tick_time {
+            self.overruns += 1;
+        }
+    }
+    /// Every `LOAD_CHECK_INTERVAL` warns if the server is falling behind the given game speed,
+    /// and if the game adapts, slows it down to what the server can manage, or speeds it back up
+    fn check(&mut self, target_speed: u8, game_speed: u8, adapt: bool) {
+        let elapsed = self.checked_at.elapsed();
+        if elapsed < LOAD_CHECK_INTERVAL || self.ticks == 0 {
+            return;
+        }
+        let achieved = self.ticks as f64 / elapsed.as_secs_f64();
+        // How many ticks per second the server could manage, if it didn't wait between them
+        let capacity = (self.ticks as f64 / self.work_time.as_secs_f64().max(1e-6)).min(255.0);
+        let overloaded = self.overruns as f64 >= self.ticks as f64 * OVERRUN_THRESHOLD;
+        let was_overloaded = self.overloaded;
+        *self = TickLoad {
+            overloaded,
+            cap: self.cap,
+            ..TickLoad::new()
+        };
+
+        if overloaded {
+            // Only warn again if something is done about it
+            if !was_overloaded || adapt {
+                println!(
+                    "The server can't keep up with {} ticks per second, only {:.1} happened per second",
+                    game_speed, achieved
+                );
+            }
+            if adapt && game_speed > 1 {
+                let cap = ((capacity * SLOW_DOWN_HEADROOM) as u8)
+                    .max(1)
+                    .min(game_speed - 1);
+                println!("Slowing the game down to {} ticks per second", cap);
+                self.cap = Some(cap);
+            }
+        } else if let Some(cap) = self.cap {
+            let sustainable = (capacity * SPEED_UP_HEADROOM) as u8;
+            if sustainable >= target_speed {
+                println!("Back to {} ticks per second", target_speed);
+                self.cap = None;
+            } else if sustainable > cap {
+                println!("Speeding the game up to {} ticks per second", sustainable);
+                self.cap = Some(sustainable);
+            }
+        } else if was_overloaded {
+            println!(
+                "The server keeps up with {} ticks per second again",
+                game_speed
+            );
+        }
+    }
+}
+
+/// An area of the world where no food grows for a while
+#[derive(Copy, Clone, Debug)]
+pub struct Famine {
+    /// The top left corner of the area
+    pub corner: SnakePartPos,
+    /// The width and height of the area in fields
+    pub size: (u16, u16),
+    /// How many more ticks the famine lasts
+    pub ticks_left: u32,
+}
+
+impl Famine {
+    /// Returns `true` if the given position is in the area of the famine
+    pub fn contains(&self, pos: SnakePartPos) -> bool {
+        pos.0 >= self.corner.0
+            && pos.0 < self.corner.0 + self.size.0
+            && pos.1 >= self.corner.1
+            && pos.1 < self.corner.1 + self.size.1
+    }
 }
 
 /// Holds the ID of the owner-snake of the part that is on the field. If there's no snake, holds 0.
@@ -83,8 +485,107 @@ pub struct Player {
     pub kills: u16,
     /// The score of the player (how much food did it eat)
     pub score: u16,
+    /// Score gained after reaching the maximum length. It counts for the leaderboard,
+    /// but doesn't make the snake any longer
+    pub overflow_score: u16,
     /// When in fast mode, snakes move 2x faster but lose length and score
     pub fast_mode: bool,
+    /// The amount of ticks since the player last changed direction or toggled fast mode
+    pub idle_ticks: u32,
+    /// The sequence number of the last ping sent to the player and when it was sent,
+    /// `None` if it was already answered
+    pub ping: Option<(u32, Instant)>,
+    /// How many pings in a row the player didn't answer
+    pub missed_pings: u8,
+    /// The last measured round-trip time to the player
+    pub rtt: Option<Duration>,
+    /// Whether the player was already flagged for sending too many direction changes
+    pub flagged: bool,
+    /// How much food the snake ate
+    pub food_eaten: u32,
+    /// When the snake was spawned
+    pub spawned_at: Instant,
+    /// For how many more ticks the snake pulls in the food around its head
+    pub magnet_ticks: u32,
+    /// For how many more ticks the snake passes through the bodies of other snakes
+    pub phase_ticks: u32,
+    /// Whether the player is a bot, as told by the client when joining
+    pub bot: bool,
+    /// How many kills the snake got in a row, each within `KILL_STREAK_WINDOW` ticks of the last one
+    pub kill_streak: u16,
+    /// For how many more ticks the next kill continues the kill streak
+    pub kill_streak_ticks: u32,
+    /// How many frames in a row were skipped because the client didn't read the previous ones
+    pub skipped_frames: u32,
+    /// For how many more ticks the snake can't crash, until the player turns for the first time
+    pub protection_ticks: u32,
+    /// Score banked in the scoring zones, it's kept when the snake dies
+    pub banked: u16,
+    /// The IP address the player connected from, `None` if through the Unix domain socket
+    pub ip: Option<IpAddr>,
+    /// The fields the snake went through since it left its territory, when painting
+    pub trail: Vec<SnakePartPos>,
+    /// Territory counted towards the next score point, when painting
+    pub territory_progress: u32,
+}
+
+/// Someone who watches the game without playing
+pub struct Spectator {
+    /// The stream to the spectator's client
+    pub stream: Stream,
+    /// The position in the world that the spectator's view is centered on
+    pub camera: SnakePartPos,
+    /// The snake whose head the camera follows, `None` if the camera is moved by hand
+    pub following: Option<u16>,
+    /// How many frames in a row were skipped because the client didn't read the previous ones
+    pub skipped_frames: u32,
+}
+
+/// A dead player waiting to get a new snake
+pub struct Respawn {
+    /// The stream to the player's client
+    pub stream: Stream,
+    /// The nickname of the player
+    pub nickname: String,
+    /// Whether the player is a bot
+    pub bot: bool,
+    /// When the player died
+    pub died_at: Instant,
+    /// Score the player banked in the scoring zones
+    pub banked: u16,
+    /// The IP address the player connected from
+    pub ip: Option<IpAddr>,
+}
+
+/// What happens when a snake reaches the edge of the world on an axis
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Edge {
+    /// The snake comes out on the other side of the world
+    Wrap,
+    /// The snake crashes into a wall
+    Wall,
+}
+
+/// What happens to food dropped on a field that already has 255 food
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum FoodOverflow {
+    /// It's put in a random place
+    Anywhere,
+    /// It's put on the closest field that has room for it, or in a random place if there's none
+    Nearby,
+    /// It's lost
+    Discard,
+}
+
+/// What happens when the heads of several snakes move onto the same field
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum HeadOn {
+    /// They all die and nobody gets a kill
+    BothDie,
+    /// The longest one survives and gets a kill for each of the others, they all die if it's a tie
+    Longest,
+    /// They all die and each of them gets a kill for each of the others
+    BothCredited,
 }
 
 /// A simple enum used to express the direction a snake is facing
@@ -121,102 +622,397 @@ impl Direction {
     }
 }
 
+impl Player {
+    /// Returns the score that is shown to players, including the overflow score
+    pub fn total_score(&self) -> u16 {
+        self.score.saturating_add(self.overflow_score)
+    }
+    /// Returns `true` if the given position is occupied by the body of the snake,
+    /// not counting the given amount of newest parts (the heads added this tick)
+    pub fn body_contains(&self, pos: SnakePartPos, new_parts: usize) -> bool {
+        self.parts
+            .iter()
+            .rev()
+            .skip(new_parts)
+            .any(|&part| part == pos)
+    }
+}
+
 impl Server {
     /// Constructs a new Server instance and starts it
-    pub fn start(
-        max_players: u16,
-        game_speed: u8,
-        port: u16,
-        world_size: (u16, u16),
-        food_rate: u8,
-        bot_amount: u16,
-    ) {
+    pub fn start(config: ServerConfig, shutdown: Arc<AtomicBool>) {
+        Self::new(config, shutdown).run();
+    }
+    /// Constructs a new Server instance and fills its world with food, without starting it
+    pub fn new(config: ServerConfig, shutdown: Arc<AtomicBool>) -> Self {
+        let world_size = config.world_size;
         println!(
             "Reserving memory for world... ({} bytes)",
-            std::mem::size_of::<SField>() as u32 * world_size.0 as u32 * world_size.1 as u32
-                + std::mem::size_of::<FField>() as u32
-                    * world_size.0 as u32
-                    * world_size.1 as u32
-                    * 4u32
+            world_bytes(world_size, config.paint)
         );
         let now = Instant::now();
 
+        // Scoring zones stay where they are put for the whole game
+        let mut rng = thread_rng();
+        let scoring_zones = (0..config.scoring_zones)
+            .map(|_| {
+                SnakePartPos(
+                    rng.gen_range(0, world_size.0 - ZONE_SIZE + 1),
+                    rng.gen_range(0, world_size.1 - ZONE_SIZE + 1),
+                )
+            })
+            .collect();
+
         let server = Server {
-            max_players,
             players: Arc::new(Mutex::new(HashMap::new())),
             client_streams: Arc::new(Mutex::new(HashMap::new())),
-            world_size,
+            spectators: Arc::new(Mutex::new(HashMap::new())),
+            respawning: Arc::new(Mutex::new(Vec::new())),
             world: Arc::new(Mutex::new(World {
                 snake_parts: vec![SField { id: 0 }; world_size.0 as usize * world_size.1 as usize],
                 foods: vec![
                     FField { amount: 0 };
                     world_size.0 as usize * world_size.1 as usize * 4usize
                 ],
+                food_total: 0,
+                food_owed: 0,
+                magnets: HashSet::new(),
+                phases: HashSet::new(),
+                owners: if config.paint {
+                    vec![0; world_size.0 as usize * world_size.1 as usize]
+                } else {
+                    Vec::new()
+                },
+                territory: HashMap::new(),
+                famine: None,
             })),
-            game_speed,
-            food_rate,
-            port,
-            bots: bot_amount,
+            current_game_speed: Arc::new(AtomicU8::new(config.game_speed)),
+            active_bots: Arc::new(AtomicU16::new(config.bots)),
+            stats: config
+                .stats
+                .clone()
+                .map(|path| Arc::new(Mutex::new(Stats::load(path)))),
+            paused: Arc::new(AtomicBool::new(false)),
+            shutdown,
+            started_at: Instant::now(),
+            connections: Arc::new(AtomicU64::new(0)),
+            addresses: Arc::new(Mutex::new(HashSet::new())),
+            peak_humans: Arc::new(AtomicU16::new(0)),
+            rooms: Arc::new(Mutex::new(HashMap::new())),
+            room_threads: Arc::new(Mutex::new(Vec::new())),
+            high_score: Arc::new(Mutex::new((0, String::new()))),
+            scoring_zones,
+            #[cfg(feature = "http-stats")]
+            metrics: Arc::new(http::Metrics::default()),
+            config: Arc::new(config),
         };
 
         println!("Done! ({:.4} seconds)", now.elapsed().as_secs_f64());
 
+        // Build the walls of the map before anything else is put into the world
+        if let Some(wall_map) = &server.config.wall_map {
+            let mut world = server.world.lock().unwrap();
+            for (field, &wall) in world.snake_parts.iter_mut().zip(wall_map.iter()) {
+                if wall {
+                    field.id = WALL_ID;
+                }
+            }
+        }
+
         // Generate foods
-        let amount_of_foods =
-            ((world_size.0 as u32 * world_size.1 as u32 * 4) as f64 / food_rate as f64) as u32;
+        let amount_of_foods = server.initial_food();
         println!("Generating food... ({})", amount_of_foods);
         let now = Instant::now();
-        let mut rng = thread_rng();
         let mut world = server.world.lock().unwrap();
         for _ in 0..amount_of_foods {
-            server.add_food(&mut rng, &mut world);
+            server.spawn_food(&mut rng, &mut world);
         }
         drop(world);
 
         println!("Done! ({:.4} seconds)", now.elapsed().as_secs_f64());
 
+        server
+    }
+    /// Binds to the port (or the socket) and runs the server on it until it's shut down
+    pub fn run(self) {
+        let listener = match &self.config.socket {
+            Some(path) => {
+                println!("Binding to socket {}", path.display());
+                match Listener::bind(self.config.port, Some(path)) {
+                    Ok(listener) => listener,
+                    Err(e) => {
+                        println!("Can't bind to socket {}: {}", path.display(), e);
+                        exit(1);
+                    }
+                }
+            }
+            None => {
+                println!("Binding to port {}", self.config.port);
+                match Listener::bind(self.config.port, None) {
+                    Ok(listener) => listener,
+                    Err(_) => {
+                        println!("Can't bind to port {}!", self.config.port);
+                        exit(1);
+                    }
+                }
+            }
+        };
+        self.run_on(listener);
+    }
+    /// Spawns the threads for accepting connections on the listener, admin commands and the bots,
+    /// and runs the game until it's shut down. Returns once all the threads of the server stopped
+    pub fn run_on(self, listener: Listener) {
+        let mut threads = Vec::new();
+
         // Start the thread for accepting new connections
         println!("Spawning a thread for accepting new connections...");
         // Make a clone of the server structure for the connections acceptor thread
-        let server_clone = server.clone();
-        thread::Builder::new()
-            .name("connections_acceptor".to_string())
-            .spawn(move || server_clone.accept_connections())
-            .unwrap();
+        let server_clone = self.clone();
+        threads.push(
+            thread::Builder::new()
+                .name("connections_acceptor".to_string())
+                .spawn(move || server_clone.accept_connections(listener))
+                .unwrap(),
+        );
 
-        // Wait for the connection acceptor to bind to the port
-        sleep(Duration::from_secs(1));
+        // Serve the statistics for monitoring
+        #[cfg(feature = "http-stats")]
+        {
+            if let Some(http_port) = self.config.http_port {
+                threads.extend(http::spawn(self.clone(), http_port));
+            }
+        }
 
-        // Spawn the bots
-        if bot_amount > 0 {
-            println!("Spawning {} bots...", bot_amount);
+        // Accept admin commands
+        if let Some(path) = &self.config.admin_socket {
+            threads.extend(admin::spawn(self.clone(), path.clone()));
         }
-        for i in 0..bot_amount {
-            // Generate a nickname for the bot
-            let nickname = format!("bot_{}", i);
-            thread::Builder::new()
-                .name(nickname.clone())
-                .spawn(move || loop {
-                    bot::Bot::start(port, &nickname);
-                })
-                .unwrap();
+
+        // Save the statistics of the players every now and then, the rooms share them
+        let (stop_saving, saving_stopped) = mpsc::channel();
+        if let Some(stats) = &self.stats {
+            threads.push(stats::spawn_saver(stats.clone(), saving_stopped));
+        }
+
+        // Publish the standings every few seconds
+        let (stop_writing, writing_stopped) = mpsc::channel();
+        if let Some(path) = &self.config.leaderboard_out {
+            threads.push(leaderboard::spawn_writer(
+                self.clone(),
+                path.clone(),
+                writing_stopped,
+            ));
+        }
+
+        // Spawn the bots
+        if self.config.bots > 0 {
+            println!("Spawning {} bots...", self.config.bots);
         }
+        bot::spawn_bots(
+            self.config.bots,
+            self.config.bot_host.clone(),
+            self.config.port,
+            self.config.socket.clone(),
+            self.config.max_nickname_length,
+            Some(self.active_bots.clone()),
+            self.config.bot_retries,
+            self.config.bot_personalities.clone(),
+        );
         println!("Server initialized");
 
         // Start the game logic
-        let tick_time = Duration::from_micros((1_000_000f64 / server.game_speed as f64) as u64);
-        loop {
+        self.game_loop();
+
+        // Drop all connections, the other threads still have their clones of the server
+        self.close_rooms();
+        self.disconnect_everyone();
+        // The acceptors notice the shutdown too, and drop their listeners when they stop.
+        // The statistics are saved once more, after everyone left
+        drop(stop_saving);
+        drop(stop_writing);
+        threads.append(&mut self.room_threads.lock().unwrap());
+        for thread in threads {
+            let _ = thread.join();
+        }
+        println!("Server shut down");
+        print!("{}", self.usage_summary());
+    }
+    /// Runs the game until the server is shut down
+    pub fn game_loop(&self) {
+        let game_start = Instant::now();
+        let mut ticks_since_ping: u8 = 0;
+        let mut ping_sequence: u32 = 0;
+        let mut ticks_since_death: u32 = 0;
+        let mut ticks_since_decay: u32 = 0;
+        let mut ticks_since_famine: u32 = 0;
+        let mut was_paused = false;
+        // The most snakes that were alive at once in this round, if the game is played in rounds
+        let mut round_contenders: usize = 0;
+        // The last high score that was announced and when
+        let mut announced_high_score: u16 = 0;
+        let mut high_score_announced_at: Option<Instant> = None;
+        let mut tick_load = TickLoad::new();
+        // How many connections each room had and when it was last used, to close the idle ones
+        let mut room_activity: HashMap<String, (u64, Instant)> = HashMap::new();
+        while !self.shutdown.load(Ordering::Relaxed) {
             // Each loop is a 'tick'
             let tick_start = Instant::now();
 
-            // Read snakes input
-            server.read_players_input();
+            // The game might be getting faster, or slower if the server can't keep up
+            let target_speed = self.target_game_speed(game_start.elapsed());
+            let game_speed = tick_load
+                .cap
+                .map_or(target_speed, |cap| cap.min(target_speed));
+            self.current_game_speed.store(game_speed, Ordering::Relaxed);
+            let tick_time = tick_time(game_speed);
+
+            // Ping players about once a second
+            ticks_since_ping += 1;
+            if ticks_since_ping >= game_speed {
+                ticks_since_ping = 0;
+                ping_sequence = ping_sequence.wrapping_add(1);
+                self.ping_players(ping_sequence);
+                // Remind everyone that the game is paused, in case they joined during the pause
+                if was_paused {
+                    self.send_paused(true);
+                }
+                // And tell everyone how long until sudden death
+                if let Some(sudden_death) = self.config.sudden_death {
+                    let seconds =
+                        sudden_death.saturating_sub(ticks_since_death) / game_speed as u32;
+                    self.send_sudden_death_countdown(seconds.min(u16::MAX as u32) as u16);
+                }
+                // And where the famine is, if there's one
+                let famine = self.world.lock().unwrap().famine;
+                if let Some(famine) = famine {
+                    self.send_famine(Some(famine), game_speed);
+                }
+            }
+
+            // Let everyone know when an admin pauses or resumes the game
+            let paused = self.paused.load(Ordering::Relaxed);
+            if paused != was_paused {
+                was_paused = paused;
+                self.send_paused(paused);
+            }
+
+            // Remember the busiest moment, counting the players in the rooms too
+            let (_bots, humans) = self.count_players();
+            let humans_in_rooms: u16 = self
+                .rooms
+                .lock()
+                .unwrap()
+                .values()
+                .map(|room| room.count_players().1)
+                .sum();
+            self.peak_humans
+                .fetch_max(humans + humans_in_rooms, Ordering::Relaxed);
+
+            // Rooms that nobody plays in would keep running their game loops forever
+            if self.config.max_rooms > 0 {
+                self.close_idle_rooms(&mut room_activity);
+            }
+
+            // Make room for humans, or let the bots come back
+            if self.config.bots_make_room {
+                self.update_active_bots();
+            }
+
+            // Give new snakes to players that waited long enough
+            if !paused {
+                self.respawn_waiting_players(round_contenders);
+            }
+
+            // Read snakes input, even when paused so that pongs are still answered
+            self.read_players_input();
+            self.read_spectators_input();
 
             // Move snakes
-            server.move_snakes();
+            let deaths = if paused { 0 } else { self.move_snakes() };
+
+            // The food that didn't fit anywhere might fit now that the snakes moved
+            if !paused {
+                self.add_owed_food();
+            }
+
+            // Celebrate the new high score, once the last one was shown for long enough
+            if self.config.announce_high_scores {
+                let high_score = self.high_score.lock().unwrap().clone();
+                let can_announce = match high_score_announced_at {
+                    Some(at) => at.elapsed() >= HIGH_SCORE_ANNOUNCE_INTERVAL,
+                    None => true,
+                };
+                if high_score.0 > announced_high_score && can_announce {
+                    announced_high_score = high_score.0;
+                    high_score_announced_at = Some(Instant::now());
+                    self.send_announcement(&format!(
+                        "{} set a new high score: {}",
+                        high_score.1, high_score.0
+                    ));
+                }
+            }
+
+            if self.config.last_standing {
+                self.check_round(&mut round_contenders);
+            }
+
+            // If nobody dies for too long, the food starts rotting away to force some fights.
+            // Sudden death needs at least 2 snakes
+            if let Some(sudden_death) = self.config.sudden_death.filter(|_| !paused) {
+                if deaths > 0 || self.players.lock().unwrap().len() < 2 {
+                    ticks_since_death = 0;
+                    ticks_since_decay = 0;
+                } else {
+                    ticks_since_death = ticks_since_death.saturating_add(1);
+                }
+                if ticks_since_death >= sudden_death {
+                    ticks_since_decay += 1;
+                    if ticks_since_decay >= SUDDEN_DEATH_DECAY_INTERVAL {
+                        ticks_since_decay = 0;
+                        self.decay_food();
+                    }
+                }
+            }
+
+            // Every now and then a famine takes the food away from a part of the world for a while,
+            // so that the snakes have to move on
+            if let Some(famine_interval) = self.config.famine.filter(|_| !paused) {
+                let mut world = self.world.lock().unwrap();
+                match &mut world.famine {
+                    Some(famine) if famine.ticks_left > 1 => famine.ticks_left -= 1,
+                    Some(_) => {
+                        world.famine = None;
+                        drop(world);
+                        ticks_since_famine = 0;
+                        self.send_famine(None, game_speed);
+                    }
+                    None => {
+                        drop(world);
+                        ticks_since_famine += 1;
+                        if ticks_since_famine >= famine_interval {
+                            self.start_famine(game_speed);
+                        }
+                    }
+                }
+            }
 
             // Send players game data
-            server.send_data_to_players();
+            self.send_data_to_players();
+
+            #[cfg(feature = "http-stats")]
+            {
+                self.metrics.ticks.fetch_add(1, Ordering::Relaxed);
+                self.metrics
+                    .tick_time_total
+                    .fetch_add(tick_start.elapsed().as_micros() as u64, Ordering::Relaxed);
+                self.metrics
+                    .deaths
+                    .fetch_add(deaths as u64, Ordering::Relaxed);
+            }
+
+            // Notice if the server is falling behind
+            tick_load.record(tick_start.elapsed(), tick_time);
+            tick_load.check(target_speed, game_speed, self.config.adapt_speed);
 
             // Wait for next tick, if need to
             if let Some(x) = tick_time.checked_sub(tick_start.elapsed()) {
@@ -224,141 +1020,578 @@ impl Server {
             }
         }
     }
-    /// Adds a single food object to a random place
-    pub fn add_food(&self, rng: &mut ThreadRng, world_lock: &mut MutexGuard<World>) {
-        let mut pos = FoodPos(
-            rng.gen::<u32>() % (self.world_size.0 as u32 * 2),
-            rng.gen::<u32>() % (self.world_size.1 as u32 * 2),
-        );
+    /// Gives new snakes to the players that waited long enough. In last standing games they
+    /// wait for the next round instead, unless no round is being played yet
+    pub fn respawn_waiting_players(&self, round_contenders: usize) {
+        if !self.config.last_standing || round_contenders < 2 {
+            self.respawn_players(RESPAWN_DELAY);
+        }
+    }
+    /// Ends the round of a last standing game when at most one of at least two snakes
+    /// is left alive, and otherwise remembers the most snakes that were alive at once
+    pub fn check_round(&self, round_contenders: &mut usize) {
+        let alive = self.players.lock().unwrap().len();
+        if *round_contenders >= 2 && alive <= 1 {
+            self.end_round();
+            *round_contenders = 0;
+        } else {
+            *round_contenders = (*round_contenders).max(alive);
+        }
+    }
+    /// Lets everyone know who won the round of a last standing game, then clears the world
+    /// and gives everyone a new snake for the next round
+    pub fn end_round(&self) {
+        let mut players = self.players.lock().unwrap();
+        let mut world = self.world.lock().unwrap();
+        let mut client_streams = self.client_streams.lock().unwrap();
 
-        for _ in 0..(self.world_size.0 as u32 * self.world_size.1 as u32) {
-            // Make sure there's no snake on the generated position
-            if world_lock.snake_parts[self.ff_to_sf_index(pos)].id == 0
-                && world_lock.foods[self.ffield_index(pos)].amount < 255
-            {
-                // Good position
-                break;
+        // Nobody wins if the last snakes crashed at the same time
+        let winner = players
+            .values()
+            .next()
+            .map(|player| player.nickname.clone())
+            .unwrap_or_default();
+        if winner.is_empty() {
+            println!("The round ended without a winner");
+        } else {
+            println!("\"{}\" won the round", winner);
+        }
+        let mut bytes: Vec<u8> = vec![MAGIC_NET_VICTORY];
+        bytes.extend_from_slice(winner.as_bytes()); // the winner's nickname, empty if nobody won
+        for stream in client_streams.values_mut() {
+            send_to_stream(stream, &bytes);
+        }
+        for respawn in self.respawning.lock().unwrap().iter_mut() {
+            send_to_stream(&mut respawn.stream, &bytes);
+        }
+        for spectator in self.spectators.lock().unwrap().values_mut() {
+            send_to_stream(&mut spectator.stream, &bytes);
+        }
+
+        // The winner starts the next round together with everyone else
+        let ids: Vec<u16> = players.keys().copied().collect();
+        for id in ids {
+            if let Some(stream) = client_streams.remove(&id) {
+                self.respawning.lock().unwrap().push(Respawn {
+                    stream,
+                    nickname: players[&id].nickname.clone(),
+                    bot: players[&id].bot,
+                    died_at: Instant::now(),
+                    banked: players[&id].banked,
+                    ip: players[&id].ip,
+                });
             }
+            self.remove_snake(id, &mut players, &mut world);
+        }
+        drop(client_streams);
 
-            // Choose a new neighbor position and try again
-            pos.0 += 1;
-            if pos.0 / (self.world_size.0 as u32 * 2) == 1 {
-                pos.0 = 0;
-                pos.1 = (pos.1 + 1) % (self.world_size.1 as u32 * 2);
+        // Start over in a fresh world, only the walls of the map stay
+        for field in world.snake_parts.iter_mut() {
+            if field.id != WALL_ID {
+                field.id = 0;
             }
         }
+        for field in world.foods.iter_mut() {
+            field.amount = 0;
+        }
+        world.food_total = 0;
+        world.food_owed = 0;
+        world.magnets.clear();
+        world.phases.clear();
+        let mut rng = thread_rng();
+        for _ in 0..self.initial_food() {
+            self.spawn_food(&mut rng, &mut world);
+        }
+        drop(world);
+        drop(players);
 
-        // Add the food to the generated position
-        world_lock.foods[self.ffield_index(pos)].amount += 1;
+        self.respawn_players(Duration::from_secs(0));
+    }
+    /// Drops the connections of all players and spectators
+    pub fn disconnect_everyone(&self) {
+        self.client_streams.lock().unwrap().clear();
+        self.spectators.lock().unwrap().clear();
+        self.respawning.lock().unwrap().clear();
+    }
+    /// Adds a single food object, with an amount picked from the food values, to a random place
+    pub fn spawn_food(&self, rng: &mut ThreadRng, world_lock: &mut MutexGuard<World>) {
+        let total_weight: u32 = self
+            .config
+            .food_values
+            .iter()
+            .map(|&(_amount, weight)| weight as u32)
+            .sum();
+        let mut roll = rng.gen_range(0, total_weight);
+        for &(amount, weight) in &self.config.food_values {
+            if roll < weight as u32 {
+                self.add_food(rng, world_lock, amount);
+                return;
+            }
+            roll -= weight as u32;
+        }
+    }
+    /// Calculates the game speed for the given time since the game started,
+    /// ramping it linearly up to the maximum game speed if there is one
+    pub fn target_game_speed(&self, elapsed: Duration) -> u8 {
+        match self.config.max_game_speed {
+            Some(max_game_speed) => {
+                let progress = if self.config.ramp_duration.as_secs_f64() > 0.0 {
+                    (elapsed.as_secs_f64() / self.config.ramp_duration.as_secs_f64()).min(1.0)
+                } else {
+                    1.0
+                };
+                (self.config.game_speed as f64
+                    + (max_game_speed as f64 - self.config.game_speed as f64) * progress)
+                    .round() as u8
+            }
+            None => self.config.game_speed,
+        }
+    }
+    /// Returns `true` if the given amount of food can be added without going over the maximum
+    pub fn food_fits(&self, world_lock: &World, amount: u8) -> bool {
+        match self.config.max_food {
+            Some(max_food) => {
+                world_lock.food_total + world_lock.food_owed + amount as u32 <= max_food
+            }
+            None => true,
+        }
+    }
+    /// Adds the given amount of food to a random place. If there's no room for all of it,
+    /// the rest is owed and placed later by `add_owed_food`
+    pub fn add_food(&self, rng: &mut ThreadRng, world_lock: &mut MutexGuard<World>, amount: u8) {
+        // Discard the food that would go over the maximum
+        if !self.food_fits(world_lock, amount) {
+            return;
+        }
+        let placed = self.place_food(rng, world_lock, amount);
+        world_lock.food_owed += (amount - placed) as u32;
+    }
+    /// Tries to place the given amount of food in random places, preferring a single field
+    /// with room for all of it. Returns how much food was placed
+    fn place_food(&self, rng: &mut ThreadRng, world_lock: &mut World, amount: u8) -> u8 {
+        let ff_width = self.config.world_size.0 as u32 * 2;
+        let ff_height = self.config.world_size.1 as u32 * 2;
+        let mut placed = 0;
+        while placed < amount {
+            let left = amount - placed;
+            let mut pos = FoodPos(rng.gen::<u32>() % ff_width, rng.gen::<u32>() % ff_height);
+
+            // Look for a field with room for all the food that's left, settling for any
+            // field with some room if there's none
+            let mut found = None;
+            for _ in 0..(ff_width * ff_height) {
+                // Make sure there's no snake or famine on the position
+                if world_lock.snake_parts[self.ff_to_sf_index(pos)].id == 0
+                    && !self.in_famine(world_lock, pos)
+                {
+                    let room = 255 - world_lock.foods[self.ffield_index(pos)].amount;
+                    if room >= left {
+                        found = Some(pos);
+                        break;
+                    } else if room > 0 && found.is_none() {
+                        found = Some(pos);
+                    }
+                }
+
+                // Choose a new neighbor position and try again
+                pos.0 += 1;
+                if pos.0 == ff_width {
+                    pos.0 = 0;
+                    pos.1 = (pos.1 + 1) % ff_height;
+                }
+            }
+
+            // The whole world is full
+            let pos = match found {
+                Some(pos) => pos,
+                None => break,
+            };
+
+            let field = &mut world_lock.foods[self.ffield_index(pos)];
+            let added = left.min(255 - field.amount);
+            field.amount += added;
+            world_lock.food_total += added as u32;
+            placed += added;
+        }
+        placed
+    }
+    /// Places the food that didn't fit in the world before, as much as there's room for now
+    pub fn add_owed_food(&self) {
+        let mut world = self.world.lock().unwrap();
+        if world.food_owed == 0 {
+            return;
+        }
+        let mut rng = thread_rng();
+        while world.food_owed > 0 {
+            let amount = world.food_owed.min(255) as u8;
+            let placed = self.place_food(&mut rng, &mut world, amount);
+            world.food_owed -= placed as u32;
+            if placed < amount {
+                break;
+            }
+        }
+    }
+    /// Returns `true` if the given food field is in the area of the famine
+    fn in_famine(&self, world_lock: &World, pos: FoodPos) -> bool {
+        let field = SnakePartPos((pos.0 / 2) as u16, (pos.1 / 2) as u16);
+        matches!(world_lock.famine, Some(famine) if famine.contains(field))
+    }
+    /// Starts a famine in a random part of the world. Its share of the food there grows back
+    /// somewhere else, and no new food grows there until the famine ends
+    pub fn start_famine(&self, game_speed: u8) {
+        let mut world = self.world.lock().unwrap();
+        let mut rng = thread_rng();
+        let size = (
+            (self.config.world_size.0 / FAMINE_SIZE_DIVISOR).max(1),
+            (self.config.world_size.1 / FAMINE_SIZE_DIVISOR).max(1),
+        );
+        let famine = Famine {
+            corner: SnakePartPos(
+                rng.gen_range(0, self.config.world_size.0 - size.0 + 1),
+                rng.gen_range(0, self.config.world_size.1 - size.1 + 1),
+            ),
+            size,
+            ticks_left: FAMINE_DURATION,
+        };
+        world.famine = Some(famine);
+
+        let mut taken = Vec::new();
+        for y in famine.corner.1..famine.corner.1 + size.1 {
+            for x in famine.corner.0..famine.corner.0 + size.0 {
+                for &index in &self.sf_to_ff_index(SnakePartPos(x, y)) {
+                    let amount = world.foods[index].amount;
+                    if amount > 0 && rng.gen::<f32>() < self.config.famine_severity {
+                        world.foods[index].amount = 0;
+                        world.food_total -= amount as u32;
+                        taken.push(amount);
+                    }
+                }
+            }
+        }
+        for amount in taken {
+            self.add_food(&mut rng, &mut world, amount);
+        }
+        drop(world);
+
+        self.send_famine(Some(famine), game_speed);
+    }
+    /// Adds 1 food to the given field, or somewhere else depending on `food_overflow`
+    /// if the field is full
+    pub fn add_food_at(
+        &self,
+        rng: &mut ThreadRng,
+        world_lock: &mut MutexGuard<World>,
+        ff_index: usize,
+    ) {
+        if world_lock.foods[ff_index].amount < 255 {
+            if self.food_fits(world_lock, 1) {
+                world_lock.foods[ff_index].amount += 1;
+                world_lock.food_total += 1;
+            }
+            return;
+        }
+        match self.config.food_overflow {
+            FoodOverflow::Anywhere => self.add_food(rng, world_lock, 1),
+            FoodOverflow::Nearby => match self.spill_field(world_lock, ff_index) {
+                Some(spill_index) => self.add_food_at(rng, world_lock, spill_index),
+                None => self.add_food(rng, world_lock, 1),
+            },
+            FoodOverflow::Discard => {}
+        }
+    }
+    /// Finds the closest food field to the given one that isn't full or behind a wall,
+    /// no further than `FOOD_SPILL_RADIUS` snake fields away
+    fn spill_field(&self, world_lock: &World, ff_index: usize) -> Option<usize> {
+        let width = self.config.world_size.0 as usize * 2;
+        let center = SnakePartPos((ff_index % width / 2) as u16, (ff_index / width / 2) as u16);
+        for radius in 0..=FOOD_SPILL_RADIUS {
+            for dy in -radius..=radius {
+                for dx in -radius..=radius {
+                    // Only the ring at this distance, the inside was checked already
+                    if dx.abs() != radius && dy.abs() != radius {
+                        continue;
+                    }
+                    let field = match self.offset_position(center, dx, dy) {
+                        Some(field) => field,
+                        None => continue,
+                    };
+                    if world_lock.snake_parts[self.sfield_index(field)].id == WALL_ID {
+                        continue;
+                    }
+                    if let Some(&spill_index) = self
+                        .sf_to_ff_index(field)
+                        .iter()
+                        .find(|&&index| world_lock.foods[index].amount < 255)
+                    {
+                        return Some(spill_index);
+                    }
+                }
+            }
+        }
+        None
+    }
+    /// Moves the given position by the given offset, wrapping around the edges of the world.
+    /// Returns `None` if the new position would be behind a wall
+    pub fn offset_position(&self, pos: SnakePartPos, dx: i32, dy: i32) -> Option<SnakePartPos> {
+        Some(SnakePartPos(
+            offset_coordinate(pos.0, dx, self.config.world_size.0, self.config.edges.0)?,
+            offset_coordinate(pos.1, dy, self.config.world_size.1, self.config.edges.1)?,
+        ))
+    }
+    /// Returns a byte telling which edges of the world are walls,
+    /// the lowest bit is for the X axis and the second lowest for the Y axis
+    pub fn walls_byte(&self) -> u8 {
+        (self.config.edges.0 == Edge::Wall) as u8 | ((self.config.edges.1 == Edge::Wall) as u8) << 1
+    }
+    /// Returns `true` if the given position is in any of the scoring zones
+    pub fn in_scoring_zone(&self, pos: SnakePartPos) -> bool {
+        self.scoring_zones.iter().any(|zone| {
+            pos.0 >= zone.0
+                && pos.0 < zone.0 + ZONE_SIZE
+                && pos.1 >= zone.1
+                && pos.1 < zone.1 + ZONE_SIZE
+        })
+    }
+    /// Builds the message telling a client that they joined the game with the given ID
+    pub fn joined_message(&self, id: u16) -> Vec<u8> {
+        let mut bytes: Vec<u8> = vec![MAGIC_NET_JOINED_GAME];
+        bytes.extend_from_slice(&id.to_be_bytes()[..]); // the id -> 2 bytes
+        bytes.extend_from_slice(&(self.config.world_size.0).to_be_bytes()[..]); // world width -> 2 bytes
+        bytes.extend_from_slice(&(self.config.world_size.1).to_be_bytes()[..]); // world height -> 2 bytes
+
+        // walled edges (bits 0 and 1), whether fast mode is disabled (bit 2)
+        // and whether the game is played in last standing rounds (bit 3) -> 1 byte
+        bytes.push(
+            self.walls_byte()
+                | (self.config.fast_mode_disabled as u8) << 2
+                | (self.config.last_standing as u8) << 3,
+        );
+        bytes.extend_from_slice(&self.config.fast_min_score.to_be_bytes()[..]); // score needed for fast mode -> 2 bytes
+        bytes.push(ZONE_SIZE as u8); // size of the scoring zones -> 1 byte
+        bytes.push(self.scoring_zones.len() as u8); // amount of scoring zones -> 1 byte
+        for zone in &self.scoring_zones {
+            bytes.extend_from_slice(&zone.0.to_be_bytes()[..]); // X of the top left corner -> 2 bytes
+            bytes.extend_from_slice(&zone.1.to_be_bytes()[..]); // Y of the top left corner -> 2 bytes
+        }
+        bytes.extend_from_slice(&self.rules_bytes()); // the rules of the game -> 32 bytes
+        bytes
+    }
+    /// Describes the rules of the game for the clients, so the players can see what kind of
+    /// server they joined. Durations are in ticks, and 0 means that the rule is off
+    pub fn rules_bytes(&self) -> Vec<u8> {
+        let ticks = |ticks: Option<u32>| ticks.unwrap_or(0).to_be_bytes();
+        let percent = |fraction: f32| (fraction * 100.0).round() as u8;
+        // whether snakes can turn back (bit 0), dead players respawn automatically (bit 1),
+        // snakes paint territory (bit 2), kill streaks are rewarded (bit 3),
+        // nicknames are unique (bit 4) and anyone can dump the world (bit 5) -> 1 byte
+        let mut bytes = vec![
+            self.config.allow_reverse as u8
+                | (self.config.auto_respawn as u8) << 1
+                | (self.config.paint as u8) << 2
+                | (self.config.kill_streak_bonus.is_some() as u8) << 3
+                | (self.config.unique_names as u8) << 4
+                | (self.config.world_dump as u8) << 5,
+        ];
+        // what happens on head-on crashes -> 1 byte
+        bytes.push(match self.config.head_on {
+            HeadOn::BothDie => 0,
+            HeadOn::Longest => 1,
+            HeadOn::BothCredited => 2,
+        });
+        bytes.push(self.config.game_speed); // the starting game speed -> 1 byte
+        bytes.push(self.config.max_game_speed.unwrap_or(0)); // the game speed it ramps up to -> 1 byte
+        bytes.extend_from_slice(&self.config.max_length.unwrap_or(0).to_be_bytes()[..]); // max length -> 2 bytes
+        bytes.extend_from_slice(&ticks(self.config.spawn_protection)[..]); // spawn protection -> 4 bytes
+        bytes.extend_from_slice(&ticks(self.config.magnet_duration)[..]); // food magnet duration -> 4 bytes
+        bytes.extend_from_slice(&ticks(self.config.phase_duration)[..]); // phase power-up duration -> 4 bytes
+        bytes.extend_from_slice(&ticks(self.config.sudden_death)[..]); // ticks until sudden death -> 4 bytes
+        bytes.extend_from_slice(&ticks(self.config.famine)[..]); // ticks between famines -> 4 bytes
+        bytes.extend_from_slice(&ticks(self.config.afk_timeout)[..]); // ticks until AFK players are kicked -> 4 bytes
+        bytes.push(percent(self.config.death_food_fraction)); // how much of a dead snake drops as food -> 1 byte
+        bytes.push(percent(self.config.food_bonus)); // bonus for big piles of food -> 1 byte
+        bytes
+    }
+    /// Returns how many foods the world starts with, according to the food rate
+    /// but at least `MIN_INITIAL_FOOD`
+    pub fn initial_food(&self) -> u32 {
+        food_for_rate(self.config.world_size, self.config.food_rate).max(MIN_INITIAL_FOOD)
+    }
+    /// Returns `true` if the snake has grown enough for fast mode, which costs score every tick
+    pub fn can_go_fast(&self, player: &Player) -> bool {
+        player.score > 0 && player.total_score() >= self.config.fast_min_score
     }
     /// Takes coordinates and returns an usize integer for indexing snake_parts of world
     pub fn sfield_index(&self, coordinates: SnakePartPos) -> usize {
-        ((coordinates.1 as usize) * self.world_size.0 as usize) + coordinates.0 as usize
+        ((coordinates.1 as usize) * self.config.world_size.0 as usize) + coordinates.0 as usize
     }
     /// Takes coordinates and returns an usize integer for indexing foods of world
     pub fn ffield_index(&self, coordinates: FoodPos) -> usize {
-        ((coordinates.1 as usize) * self.world_size.0 as usize * 2) + coordinates.0 as usize
+        ((coordinates.1 as usize) * self.config.world_size.0 as usize * 2) + coordinates.0 as usize
     }
     /// Takes snake parts coordinates, converts them to foods coordinates and returns an array of usize integers for indexing
     pub fn sf_to_ff_index(&self, coordinates: SnakePartPos) -> [usize; 4] {
         [
             0 + 2 * coordinates.0 as usize
-                + (4 * coordinates.1 as usize + 0) * self.world_size.0 as usize,
+                + (4 * coordinates.1 as usize + 0) * self.config.world_size.0 as usize,
             1 + 2 * coordinates.0 as usize
-                + (4 * coordinates.1 as usize + 0) * self.world_size.0 as usize,
+                + (4 * coordinates.1 as usize + 0) * self.config.world_size.0 as usize,
             0 + 2 * coordinates.0 as usize
-                + (4 * coordinates.1 as usize + 2) * self.world_size.0 as usize,
+                + (4 * coordinates.1 as usize + 2) * self.config.world_size.0 as usize,
             1 + 2 * coordinates.0 as usize
-                + (4 * coordinates.1 as usize + 2) * self.world_size.0 as usize,
+                + (4 * coordinates.1 as usize + 2) * self.config.world_size.0 as usize,
         ]
     }
     /// Takes foods coordinates, converts them to snake parts coordinates and returns an usize integer for indexing
     pub fn ff_to_sf_index(&self, coordinates: FoodPos) -> usize {
-        coordinates.0 as usize / 2 + (coordinates.1 as usize / 2) * self.world_size.0 as usize
-    }
-    /// Accepts and handles new connections
-    pub fn accept_connections(self) {
-        // First bind to the port and start listening
-        println!("Binding to port {}", self.port);
-        let listener = match TcpListener::bind(format!("0.0.0.0:{}", self.port)) {
-            Ok(listener) => listener,
-            Err(_) => {
-                println!("Can't bind to port {}!", self.port);
-                exit(1);
-            }
-        };
-        loop {
+        coordinates.0 as usize / 2
+            + (coordinates.1 as usize / 2) * self.config.world_size.0 as usize
+    }
+    /// Accepts and handles new connections until the server is shut down
+    pub fn accept_connections(self, listener: Listener) {
+        // Don't wait for a connection forever, so that the shutdown is noticed
+        if let Err(e) = listener.set_nonblocking(true) {
+            println!("Can't accept connections: {}", e);
+            exit(1);
+        }
+        while !self.shutdown.load(Ordering::Relaxed) {
             // Accept a new connection
-            if let Ok((stream, addr)) = listener.accept() {
-                // Set timeout to 60 seconds
-                stream
-                    .set_read_timeout(Some(Duration::from_secs(60)))
-                    .expect("set_read_timeout call failed");
-                // Spawn a new thread for handling this new connection
-                let server_clone = self.clone();
-                thread::Builder::new()
-                    .name("new_connection_handler".to_string())
-                    .spawn(move || {
-                        server_clone.handle_new_connection(stream, addr);
-                    })
-                    .unwrap();
+            let (stream, addr) = match listener.accept() {
+                Ok(connection) => connection,
+                Err(_) => {
+                    sleep(ACCEPT_INTERVAL);
+                    continue;
+                }
+            };
+            self.connections.fetch_add(1, Ordering::Relaxed);
+            if let Some(addr) = addr {
+                self.addresses.lock().unwrap().insert(addr.ip());
             }
+            // Set the timeout, the connection doesn't inherit the nonblocking mode of the listener
+            // on every platform
+            stream
+                .set_nonblocking(false)
+                .expect("set_nonblocking call failed");
+            stream
+                .set_read_timeout(Some(self.config.read_timeout))
+                .expect("set_read_timeout call failed");
+            // Spawn a new thread for handling this new connection
+            let server_clone = self.clone();
+            thread::Builder::new()
+                .name("new_connection_handler".to_string())
+                .spawn(move || {
+                    server_clone.handle_new_connection(stream, addr);
+                })
+                .unwrap();
         }
     }
     /// Handles a new connection, idk what else to say.
-    pub fn handle_new_connection(self, mut stream: TcpStream, address: SocketAddr) {
+    /// The address is `None` if the client connected through the Unix domain socket
+    pub fn handle_new_connection(self, mut stream: Stream, address: Option<SocketAddr>) {
         // Determine what the client wants
-        let bytes = match read_from_stream(&mut stream) {
+        let bytes = match read_from_stream(&mut stream, MAX_REQUEST_SIZE) {
             Ok(bytes) => bytes,
             Err(_) => {
                 // Conection lost already :O
+                // Or the client refuses to tell what he's here for
                 return;
             }
         };
 
-        if bytes.is_empty() {
-            // Client refuses to tell what he's here for
-            // Drop connection
-            return;
+        if bytes[0] == MAGIC_NET_JOIN_ROOM {
+            // They want a room other than the main game, the actual request follows
+            let room = match self.find_room(&mut stream, &bytes[1..]) {
+                Some(room) => room,
+                None => return,
+            };
+            let bytes = match read_from_stream(&mut stream, MAX_REQUEST_SIZE) {
+                Ok(bytes) => bytes,
+                Err(_) => return,
+            };
+            room.handle_request(stream, address, bytes);
+        } else {
+            self.handle_request(stream, address, bytes);
         }
-
-        if bytes[0] == MAGIC_NET_REQUEST_TO_PLAY {
-            // They're here to play
+    }
+    /// Handles the first request of a new connection, already routed to the right room
+    pub fn handle_request(self, mut stream: Stream, address: Option<SocketAddr>, bytes: Vec<u8>) {
+        if bytes[0] == MAGIC_NET_REQUEST_TO_PLAY || bytes[0] == MAGIC_NET_REQUEST_TO_PLAY_AS_BOT {
+            // They're here to play, bots say so, so they're not counted as humans.
+            // Only the bots of this machine are trusted, anyone else saying so joins as a human
+            let local = address.map_or(true, |address| address.ip().is_loopback());
+            let bot = bytes[0] == MAGIC_NET_REQUEST_TO_PLAY_AS_BOT && local;
             // Get the nickname
-            let mut nickname: String = match std::str::from_utf8(&bytes[1..]) {
-                Ok(string) => string.to_owned(),
-                Err(_) => {
-                    // Can't read username
+            let nickname = match self.check_nickname(&bytes[1..]) {
+                Ok(nickname) => nickname,
+                Err(e) => {
                     // Send message and drop the connection
-                    let mut message = vec![MAGIC_NET_ERROR];
-                    message.extend_from_slice(b"can't read nickname");
-                    send_to_stream(&mut stream, &message);
+                    send_error(&mut stream, ERROR_BAD_NICKNAME, &e);
                     return;
                 }
             };
-            // Escape the username to make it consist only of visible ascii characters
-            nickname = nickname.escape_default().to_string();
-            // Make sure that the nickname is not too long and not too short
-            if nickname.is_empty() || nickname.len() > 10 {
-                // Send message and drop the connection
-                let mut message = vec![MAGIC_NET_ERROR];
-                message.extend_from_slice(b"nickname too short/long");
-                send_to_stream(&mut stream, &message);
-                return;
+            // Humans can't pass as bots, whatever the case of the letters
+            if let Some(prefix) = &self.config.reserved_prefix {
+                if !bot && nickname.to_lowercase().starts_with(&prefix.to_lowercase()) {
+                    send_error(
+                        &mut stream,
+                        ERROR_BAD_NICKNAME,
+                        &format!("nicknames starting with {} are reserved for bots", prefix),
+                    );
+                    return;
+                }
             }
             let mut players = self.players.lock().unwrap();
-            // Make sure the server is not full yet
-            let playing_now = players.len() as u16;
-            if playing_now >= self.max_players {
+            // Make sure the server is not full yet.
+            // If bots make room for humans, only humans count, and a bot leaves soon after
+            let playing_now = if self.config.bots_make_room && !bot {
+                players.values().filter(|player| !player.bot).count() as u16
+            } else {
+                players.len() as u16
+            };
+            if playing_now >= self.config.max_players {
                 // Send error and drop connection
-                let mut message = vec![MAGIC_NET_ERROR];
-                message.extend_from_slice(b"server full");
-                send_to_stream(&mut stream, &message);
+                send_error(&mut stream, ERROR_SERVER_FULL, "server full");
+                return;
+            }
+            // Make sure nobody else is using the nickname, including dead players waiting for a new snake.
+            // The players lock is held until the new player is added, so two players can't take it at once
+            if self.config.unique_names
+                && (players.values().any(|player| player.nickname == nickname)
+                    || self
+                        .respawning
+                        .lock()
+                        .unwrap()
+                        .iter()
+                        .any(|respawn| respawn.nickname == nickname))
+            {
+                send_error(&mut stream, ERROR_NICKNAME_TAKEN, "nickname already taken");
                 return;
             }
+            // Make sure one person can't take all the slots, bots usually run on the same machine
+            let ip = address.map(|address| address.ip());
+            if let (Some(max_players_per_ip), Some(ip)) = (self.config.max_players_per_ip, ip) {
+                let from_ip = players
+                    .values()
+                    .filter(|player| player.ip == Some(ip))
+                    .count()
+                    + self
+                        .respawning
+                        .lock()
+                        .unwrap()
+                        .iter()
+                        .filter(|respawn| respawn.ip == Some(ip))
+                        .count();
+                if !ip.is_loopback() && from_ip >= max_players_per_ip as usize {
+                    send_error(
+                        &mut stream,
+                        ERROR_TOO_MANY_FROM_IP,
+                        "too many players from your address",
+                    );
+                    return;
+                }
+            }
             // generate an ID for this new player
             let mut id: u16 = 1;
-            for i in 1..=u16::max_value() as u32 {
+            for i in 1..WALL_ID as u32 {
                 if !players.contains_key(&(i as u16)) {
                     id = i as u16;
                     break;
@@ -370,43 +1603,121 @@ impl Server {
                 .expect("set_nonblocking failed");
 
             // Add a new player instance to the game
-            if self.add_player(&mut players, &nickname, id).is_err() {
+            if self.add_player(&mut players, &nickname, id, bot).is_err() {
                 println!("Failed to spawn a player because there's not enough space on world");
-                let mut message = vec![MAGIC_NET_ERROR];
-                message.extend_from_slice(b"not enough space in world. try again");
-                send_to_stream(&mut stream, &message);
+                send_error(&mut stream, ERROR_NO_SPACE, "not enough space in world");
                 return;
             }
+            players.get_mut(&id).unwrap().ip = ip;
+            // Make sure there's a record for them
+            if let Some(stats) = &self.stats {
+                stats.lock().unwrap().add_player(&nickname);
+            }
 
-            self.client_streams
-                .lock()
-                .unwrap()
-                .insert(id, stream.try_clone().expect("try_clone failed!"));
+            // Send the id to them, before any game data can be sent
+            send_to_stream(&mut stream, &self.joined_message(id));
+            self.client_streams.lock().unwrap().insert(id, stream);
             // drop the players lock
             drop(players);
-
-            // Send the id to them
-            let mut bytes: Vec<u8> = Vec::new();
-            bytes.extend_from_slice(&[MAGIC_NET_JOINED_GAME]);
-            bytes.extend_from_slice(&id.to_be_bytes()[..]); // the id -> 2 bytes
-            bytes.extend_from_slice(&(self.world_size.0).to_be_bytes()[..]); // world width -> 2 bytes
-            bytes.extend_from_slice(&(self.world_size.1).to_be_bytes()[..]); // world height -> 2 bytes
-            send_to_stream(&mut stream, &bytes);
             // Display a message
-            if !address.ip().is_loopback() {
-                println!("{} connected with nickname {}", address, nickname);
+            if let Some(address) = address {
+                if self.config.log_loopback || !address.ip().is_loopback() {
+                    println!("{} connected with nickname {}", address, nickname);
+                }
             }
+        } else if bytes[0] == MAGIC_NET_REQUEST_TO_SPECTATE {
+            // They're here to watch
+            let mut spectators = self.spectators.lock().unwrap();
+            // generate an ID for this new spectator, if there's room for them
+            let full = matches!(self.config.max_spectators,
+                Some(max_spectators) if spectators.len() >= max_spectators as usize);
+            let id = match (1..=u16::MAX).find(|i| !spectators.contains_key(i)) {
+                Some(id) if !full => id,
+                _ => {
+                    send_error(
+                        &mut stream,
+                        ERROR_TOO_MANY_SPECTATORS,
+                        "too many spectators",
+                    );
+                    return;
+                }
+            };
+            // Make the stream nonblocking
+            stream
+                .set_nonblocking(true)
+                .expect("set_nonblocking failed");
+
+            // Send them the world size, spectators don't have a snake so the ID is 0
+            send_to_stream(&mut stream, &self.joined_message(0));
+
+            // Start in the middle of the world
+            spectators.insert(
+                id,
+                Spectator {
+                    stream,
+                    camera: SnakePartPos(
+                        self.config.world_size.0 / 2,
+                        self.config.world_size.1 / 2,
+                    ),
+                    following: None,
+                    skipped_frames: 0,
+                },
+            );
         } else if bytes[0] == MAGIC_NET_SERVER_STATUS {
             // Send the server status and drop connection
             self.send_server_data_to_stream(stream);
+        } else if bytes[0] == MAGIC_NET_WORLD_DUMP {
+            if !self.config.world_dump {
+                send_error(&mut stream, ERROR_DISABLED, "world dumps are disabled");
+                return;
+            }
+            // Send the whole world and drop connection
+            self.send_world_dump_to_stream(stream);
+        } else if bytes[0] == MAGIC_NET_PLAYER_STATS {
+            // Send the statistics of the player with the given nickname and drop connection
+            match std::str::from_utf8(&bytes[1..]) {
+                Ok(nickname) => {
+                    // Nicknames are escaped when joining, so escape this one too
+                    let nickname = nickname.escape_default().to_string();
+                    self.send_player_stats_to_stream(stream, &nickname);
+                }
+                Err(_) => {
+                    send_error(&mut stream, ERROR_BAD_NICKNAME, "can't read nickname");
+                }
+            }
         }
     }
+    /// Returns the nickname escaped to visible ASCII characters, or why it can't be used.
+    /// The length limit applies to the escaped nickname, which is what everyone sees
+    pub fn check_nickname(&self, raw_nickname: &[u8]) -> Result<String, String> {
+        // Letting them know the limit of this server
+        let too_long = format!(
+            "nickname too short/long (1-{} characters, special characters count as several)",
+            self.config.max_nickname_length
+        );
+        // Escaping never makes a nickname shorter, so reject the ones that are
+        // already too long before escaping them into something even longer
+        if raw_nickname.is_empty() || raw_nickname.len() > self.config.max_nickname_length {
+            return Err(too_long);
+        }
+        // Escape the username to make it consist only of visible ascii characters
+        let nickname = match std::str::from_utf8(raw_nickname) {
+            Ok(string) => string.escape_default().to_string(),
+            Err(_) => return Err("can't read nickname".to_string()),
+        };
+        // Make sure that the escaped nickname is not too long too
+        if nickname.len() > self.config.max_nickname_length {
+            return Err(too_long);
+        }
+        Ok(nickname)
+    }
     /// Adds a player to the world
     pub fn add_player(
         &self,
         players_lock: &mut MutexGuard<HashMap<u16, Player>>,
         nickname: &str,
         id: u16,
+        bot: bool,
     ) -> Result<(), ()> {
         // Generate a Player object for our new player :)
         // Generate random direction
@@ -414,20 +1725,79 @@ impl Server {
         // Generate parts positions
         let (parts, eaten) = self.generate_snake_parts(direction, id)?;
 
-        let player = Player {
+        let mut player = Player {
             nickname: nickname.to_owned(),
             direction,
             last_direction: direction,
             parts,
             kills: 0,
             score: eaten,
+            overflow_score: 0,
             fast_mode: false,
+            idle_ticks: 0,
+            ping: None,
+            missed_pings: 0,
+            rtt: None,
+            flagged: false,
+            food_eaten: 0,
+            spawned_at: Instant::now(),
+            magnet_ticks: 0,
+            phase_ticks: 0,
+            bot,
+            kill_streak: 0,
+            kill_streak_ticks: 0,
+            skipped_frames: 0,
+            protection_ticks: self.config.spawn_protection.unwrap_or(0),
+            banked: 0,
+            ip: None,
+            trail: Vec::new(),
+            territory_progress: 0,
         };
+        self.cap_length(&mut player);
 
         // Add the player object to the hashmap
         players_lock.insert(id, player);
         Ok(())
     }
+    /// Lets only as many bots play as there are slots left for them after all the humans,
+    /// including the dead ones waiting for a new snake
+    pub fn update_active_bots(&self) {
+        let (_bots, humans) = self.count_players();
+        let respawning_humans = self
+            .respawning
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|respawn| !respawn.bot)
+            .count() as u16;
+        let free_slots = self
+            .config
+            .max_players
+            .saturating_sub(humans)
+            .saturating_sub(respawning_humans);
+        self.active_bots
+            .store(self.config.bots.min(free_slots), Ordering::Relaxed);
+    }
+    /// Returns how many bots and how many humans are playing now
+    pub fn count_players(&self) -> (u16, u16) {
+        let players = self.players.lock().unwrap();
+        let bots = players.values().filter(|player| player.bot).count() as u16;
+        (bots, players.len() as u16 - bots)
+    }
+    /// Moves the part of the score that would make the snake longer than the maximum length
+    /// to the overflow score
+    pub fn cap_length(&self, player: &mut Player) {
+        if let Some(max_length) = self.config.max_length {
+            // calc_length(score) <= max_length as long as score <= max_length²
+            let max_score = (max_length as u32 * max_length as u32).min(u16::MAX as u32) as u16;
+            if player.score > max_score {
+                player.overflow_score = player
+                    .overflow_score
+                    .saturating_add(player.score - max_score);
+                player.score = max_score;
+            }
+        }
+    }
     /// Generate a random position for a new snake to spawn to, without overlapping
     /// with other snakes or foods
     /// Returns `None` if no position to spawn the snake on was found
@@ -439,39 +1809,34 @@ impl Server {
         let mut parts = VecDeque::with_capacity(3);
         let direction_vector = direction.to_vector();
         let mut head_pos = SnakePartPos(
-            thread_rng().gen_range(0, self.world_size.0) as u16,
-            thread_rng().gen_range(0, self.world_size.1) as u16,
+            thread_rng().gen_range(0, self.config.world_size.0) as u16,
+            thread_rng().gen_range(0, self.config.world_size.1) as u16,
         );
-        'field: for _ in 0..(self.world_size.0 as u32 * self.world_size.1 as u32) {
+        'field: for _ in 0..(self.config.world_size.0 as u32 * self.config.world_size.1 as u32) {
             head_pos.0 += 1;
-            if head_pos.0 == self.world_size.0 {
+            if head_pos.0 == self.config.world_size.0 {
                 head_pos.0 = 0;
-                head_pos.1 = (head_pos.1 + 1) % self.world_size.1;
+                head_pos.1 = (head_pos.1 + 1) % self.config.world_size.1;
             }
-            let part2_pos = SnakePartPos(
-                (((head_pos.0 as i32) - direction_vector.0 + self.world_size.0 as i32) as u32
-                    % self.world_size.0 as u32) as u16,
-                (((head_pos.1 as i32) - direction_vector.1 + self.world_size.1 as i32) as u32
-                    % self.world_size.1 as u32) as u16,
-            );
-            let part3_pos = SnakePartPos(
-                (((head_pos.0 as i32) - 2 * direction_vector.0 + self.world_size.0 as i32) as u32
-                    % self.world_size.0 as u32) as u16,
-                (((head_pos.1 as i32) - 2 * direction_vector.1 + self.world_size.1 as i32) as u32
-                    % self.world_size.1 as u32) as u16,
-            );
+            let (part2_pos, part3_pos) = match (
+                self.offset_position(head_pos, -direction_vector.0, -direction_vector.1),
+                self.offset_position(head_pos, -2 * direction_vector.0, -2 * direction_vector.1),
+            ) {
+                (Some(part2_pos), Some(part3_pos)) => (part2_pos, part3_pos),
+                // The snake would go through a wall
+                _ => continue 'field,
+            };
             // If there's another snake part already there, generate another position,
             let mut world = self.world.lock().unwrap();
             for part in &[head_pos, part2_pos, part3_pos] {
                 // Check all fields in a 7 field radius
                 for x in -7..=7 {
                     for y in -7..=7 {
-                        let field_pos = SnakePartPos(
-                            ((part.0 as i32 + x + self.world_size.0 as i32)
-                                % self.world_size.0 as i32) as u16,
-                            ((part.1 as i32 + y + self.world_size.1 as i32)
-                                % self.world_size.1 as i32) as u16,
-                        );
+                        // Don't spawn too close to walls either
+                        let field_pos = match self.offset_position(*part, x, y) {
+                            Some(field_pos) => field_pos,
+                            None => continue 'field,
+                        };
                         if world.snake_parts[self.sfield_index(field_pos)].id != 0 {
                             // There's another snake here, try another position
                             continue 'field;
@@ -487,10 +1852,14 @@ impl Server {
             for part in &[head_pos, part2_pos, part3_pos] {
                 for foodfield in self.sf_to_ff_index(*part).iter() {
                     eaten += world.foods[*foodfield].amount as u16;
+                    world.food_total -= world.foods[*foodfield].amount as u32;
                     world.foods[*foodfield].amount = 0;
                 }
                 world.snake_parts[self.sfield_index(*part)].id = id;
             }
+            if self.config.paint {
+                self.claim_start_territory(&mut world, &[head_pos, part2_pos, part3_pos], id);
+            }
 
             parts.push_front(head_pos);
             parts.push_front(part2_pos);
@@ -501,63 +1870,242 @@ impl Server {
         Err(())
     }
     /// Send server status to stream which requested it
-    pub fn send_server_data_to_stream(&self, mut stream: TcpStream) {
+    pub fn send_server_data_to_stream(&self, mut stream: Stream) {
         let mut bytes: Vec<u8> = Vec::new();
         // max players -> 2 bytes
-        bytes.extend_from_slice(&self.max_players.to_be_bytes()[..]);
-        // amount of bots -> 2 bytes
-        bytes.extend_from_slice(&self.bots.to_be_bytes()[..]);
-        // players playing now -> 2 bytes
-        let playing_now = self.players.lock().unwrap().len() as u16;
-        bytes.extend_from_slice(&playing_now.to_be_bytes()[..]);
+        bytes.extend_from_slice(&self.config.max_players.to_be_bytes()[..]);
+        let (bots_now, humans_now) = self.count_players();
+        // bots playing now -> 2 bytes
+        bytes.extend_from_slice(&bots_now.to_be_bytes()[..]);
+        // human players playing now -> 2 bytes
+        bytes.extend_from_slice(&humans_now.to_be_bytes()[..]);
         // world size -> 4 bytes
-        bytes.extend_from_slice(&self.world_size.0.to_be_bytes()[..]);
-        bytes.extend_from_slice(&self.world_size.1.to_be_bytes()[..]);
+        bytes.extend_from_slice(&self.config.world_size.0.to_be_bytes()[..]);
+        bytes.extend_from_slice(&self.config.world_size.1.to_be_bytes()[..]);
         // food rate -> 1 byte
-        bytes.push(self.food_rate);
+        bytes.push(self.config.food_rate);
         // game speed -> 1 byte
-        bytes.push(self.game_speed);
+        bytes.push(self.current_game_speed.load(Ordering::Relaxed));
+
+        // The top 9 or less players sorted by score, kills and length
+        for board in &self.leaderboards() {
+            // Amount of players in this list -> 1 byte
+            bytes.push(board.len() as u8);
+            for (value, nickname) in board {
+                // Nickname length -> 1 byte
+                bytes.push(nickname.len() as u8);
+                // Nickname -> 0-10 bytes
+                bytes.extend_from_slice(nickname.as_bytes());
+                // Score, kills or length -> 2 bytes
+                bytes.extend_from_slice(&value.to_be_bytes()[..]);
+            }
+        }
 
+        // uptime in seconds -> 4 bytes
+        let uptime = self.started_at.elapsed().as_secs().min(u32::MAX as u64) as u32;
+        bytes.extend_from_slice(&uptime.to_be_bytes()[..]);
+        // most human players at once -> 2 bytes
+        bytes.extend_from_slice(&self.peak_humans.load(Ordering::Relaxed).to_be_bytes()[..]);
+        // connections since the server started -> 4 bytes
+        let connections = self
+            .connections
+            .load(Ordering::Relaxed)
+            .min(u32::MAX as u64) as u32;
+        bytes.extend_from_slice(&connections.to_be_bytes()[..]);
+        // unique connections, by address -> 4 bytes
+        let addresses = self.addresses.lock().unwrap().len().min(u32::MAX as usize) as u32;
+        bytes.extend_from_slice(&addresses.to_be_bytes()[..]);
+
+        // The rooms besides the main game
+        let rooms = self.rooms.lock().unwrap();
+        // Amount of rooms -> 1 byte
+        bytes.push(rooms.len().min(u8::MAX as usize) as u8);
+        for (name, room) in rooms.iter().take(u8::MAX as usize) {
+            // Room name length -> 1 byte
+            bytes.push(name.len() as u8);
+            // Room name -> 1-16 bytes
+            bytes.extend_from_slice(name.as_bytes());
+            // Players in the room -> 2 bytes
+            bytes.extend_from_slice(&(room.players.lock().unwrap().len() as u16).to_be_bytes()[..]);
+        }
+        drop(rooms);
+
+        // The longest nickname allowed, after escaping -> 1 byte
+        bytes.push(self.config.max_nickname_length as u8);
+
+        send_to_stream(&mut stream, &bytes);
+    }
+    /// Returns the top `LEADERBOARD_LENGTH` or less players sorted by score, kills and length,
+    /// each with the value it's sorted by
+    pub fn leaderboards(&self) -> [Vec<(u16, String)>; 3] {
         let players = self.players.lock().unwrap();
+        let top = |key: &dyn Fn(&Player) -> u16| {
+            let mut board: Vec<(u16, String)> = players
+                .values()
+                .map(|player| (key(player), player.nickname.clone()))
+                .collect();
+            board.sort_unstable();
+            board.reverse();
+            board.truncate(LEADERBOARD_LENGTH);
+            board
+        };
+        [
+            top(&|player| player.total_score()),
+            top(&|player| player.kills),
+            top(&|player| player.parts.len() as u16),
+        ]
+    }
+    /// Returns a few lines about how the server was used since it started
+    pub fn usage_summary(&self) -> String {
+        let uptime = self.started_at.elapsed().as_secs();
+        let (bots, humans) = self.count_players();
+        format!(
+            "uptime: {}h {}m {}s\nplayers now: {} (and {} bots)\npeak players: {}\nconnections: {} ({} unique)\n",
+            uptime / 3600,
+            uptime / 60 % 60,
+            uptime % 60,
+            humans,
+            bots,
+            self.peak_humans.load(Ordering::Relaxed),
+            self.connections.load(Ordering::Relaxed),
+            self.addresses.lock().unwrap().len()
+        )
+    }
+    /// Send the whole world to stream which requested it, for external map viewers.
+    ///
+    /// The dump is split into several messages, each starting with `MAGIC_NET_WORLD_DUMP`
+    /// and a section byte, in this order:
+    ///
+    /// * `WORLD_DUMP_HEADER` - world width (2 bytes), world height (2 bytes), walled edges (1 byte)
+    /// * `WORLD_DUMP_SNAKE_PARTS` (one or more) - runs of snake parts fields, going row by row
+    ///   from the top left corner. Each run is the snake ID (2 bytes, 0 if empty) and
+    ///   the amount of fields in a row with that ID (4 bytes)
+    /// * `WORLD_DUMP_FOODS` (one or more, or none if there's no food) - all fields with food.
+    ///   Each is the X (2 bytes) and Y (2 bytes) food coordinates and the amount of food (1 byte).
+    ///   Food coordinates are 2x the snake parts coordinates on both axes
+    /// * `WORLD_DUMP_END` - nothing else
+    pub fn send_world_dump_to_stream(&self, mut stream: Stream) {
+        let mut messages: Vec<Vec<u8>> = Vec::new();
+
+        let mut header = vec![MAGIC_NET_WORLD_DUMP, WORLD_DUMP_HEADER];
+        header.extend_from_slice(&self.config.world_size.0.to_be_bytes()[..]); // world width -> 2 bytes
+        header.extend_from_slice(&self.config.world_size.1.to_be_bytes()[..]); // world height -> 2 bytes
+        header.push(self.walls_byte()); // walled edges -> 1 byte
+        messages.push(header);
 
-        // Top 9 or less players sorted by score
-        let mut scores: Vec<(u16, &String)> = players
-            .values()
-            .map(|player| (player.score, &player.nickname))
-            .collect();
-        scores.sort_unstable();
-        scores.reverse();
-        scores.truncate(9);
-        // Amount of players in this list -> 1 byte
-        bytes.push(scores.len() as u8);
-        for (score, nickname) in scores {
-            // Nickname length -> 1 byte
-            bytes.push(nickname.len() as u8);
-            // Nickname -> 0-10 bytes
-            bytes.extend_from_slice(nickname.as_bytes());
-            // Score -> 2 bytes
-            bytes.extend_from_slice(&score.to_be_bytes()[..]);
-        }
-        // Top 9 or less players sorted by kills
-        let mut scores: Vec<(u16, &String)> = players
-            .values()
-            .map(|player| (player.kills, &player.nickname))
-            .collect();
-        scores.sort_unstable();
-        scores.reverse();
-        scores.truncate(9);
-        // Amount of players in this list -> 1 byte
-        bytes.push(scores.len() as u8);
-        for (kills, nickname) in scores {
-            // Nickname length -> 1 byte
-            bytes.push(nickname.len() as u8);
-            // Nickname -> 0-10 bytes
-            bytes.extend_from_slice(nickname.as_bytes());
-            // Score -> 2 bytes
-            bytes.extend_from_slice(&kills.to_be_bytes()[..]);
+        let world = self.world.lock().unwrap();
+
+        // Snake parts, run-length encoded since most of the fields are empty
+        let mut runs: Vec<(u16, u32)> = Vec::new();
+        for field in &world.snake_parts {
+            match runs.last_mut() {
+                Some((id, length)) if *id == field.id => *length += 1,
+                _ => runs.push((field.id, 1)),
+            }
+        }
+        let mut message = vec![MAGIC_NET_WORLD_DUMP, WORLD_DUMP_SNAKE_PARTS];
+        for (id, length) in runs {
+            if message.len() + 6 > WORLD_DUMP_MESSAGE_SIZE {
+                messages.push(message);
+                message = vec![MAGIC_NET_WORLD_DUMP, WORLD_DUMP_SNAKE_PARTS];
+            }
+            message.extend_from_slice(&id.to_be_bytes()[..]); // snake id -> 2 bytes
+            message.extend_from_slice(&length.to_be_bytes()[..]); // run length -> 4 bytes
+        }
+        messages.push(message);
+
+        // Foods, only the fields that have any
+        let foods_width = self.config.world_size.0 as usize * 2;
+        let mut message = vec![MAGIC_NET_WORLD_DUMP, WORLD_DUMP_FOODS];
+        for (i, field) in world.foods.iter().enumerate() {
+            if field.amount == 0 {
+                continue;
+            }
+            if message.len() + 5 > WORLD_DUMP_MESSAGE_SIZE {
+                messages.push(message);
+                message = vec![MAGIC_NET_WORLD_DUMP, WORLD_DUMP_FOODS];
+            }
+            message.extend_from_slice(&((i % foods_width) as u16).to_be_bytes()[..]); // x -> 2 bytes
+            message.extend_from_slice(&((i / foods_width) as u16).to_be_bytes()[..]); // y -> 2 bytes
+            message.push(field.amount); // amount of food -> 1 byte
+        }
+        if message.len() > 2 {
+            messages.push(message);
+        }
+
+        // Don't hold the world while sending
+        drop(world);
+
+        messages.push(vec![MAGIC_NET_WORLD_DUMP, WORLD_DUMP_END]);
+        for message in messages {
+            send_to_stream(&mut stream, &message);
+        }
+    }
+    /// Send the lifetime statistics of a player to stream which requested it.
+    /// Only the magic byte is sent if the player has no statistics yet
+    pub fn send_player_stats_to_stream(&self, mut stream: Stream, nickname: &str) {
+        let stats = match &self.stats {
+            Some(stats) => stats.lock().unwrap().get(nickname),
+            None => {
+                send_error(
+                    &mut stream,
+                    ERROR_DISABLED,
+                    "player statistics are disabled",
+                );
+                return;
+            }
+        };
+        let mut bytes: Vec<u8> = vec![MAGIC_NET_PLAYER_STATS];
+        if let Some(stats) = stats {
+            bytes.extend_from_slice(&stats.games_played.to_be_bytes()[..]); // games played -> 4 bytes
+            bytes.extend_from_slice(&stats.food_eaten.to_be_bytes()[..]); // food eaten -> 4 bytes
+            bytes.extend_from_slice(&stats.kills.to_be_bytes()[..]); // kills -> 4 bytes
+            bytes.extend_from_slice(&stats.best_score.to_be_bytes()[..]); // best score -> 2 bytes
+            bytes.extend_from_slice(&stats.longest_life.to_be_bytes()[..]); // longest life in seconds -> 4 bytes
+        }
+        send_to_stream(&mut stream, &bytes);
+    }
+    /// Gives new snakes to the dead players that waited for at least the given delay,
+    /// and lets them know their new ID
+    pub fn respawn_players(&self, delay: Duration) {
+        let mut players = self.players.lock().unwrap();
+        let mut respawning = self.respawning.lock().unwrap();
+        let mut i = 0;
+        while i < respawning.len() {
+            if respawning[i].died_at.elapsed() < delay
+                || players.len() as u16 >= self.config.max_players
+            {
+                i += 1;
+                continue;
+            }
+            // generate an ID for the new snake
+            let id = match (1..WALL_ID).find(|id| !players.contains_key(id)) {
+                Some(id) => id,
+                None => break,
+            };
+            if self
+                .add_player(&mut players, &respawning[i].nickname, id, respawning[i].bot)
+                .is_err()
+            {
+                // Not enough space in world right now, try again next tick
+                i += 1;
+                continue;
+            }
+            let mut respawn = respawning.swap_remove(i);
+            let player = players.get_mut(&id).unwrap();
+            player.banked = respawn.banked;
+            player.ip = respawn.ip;
+
+            // Send the new id to them
+            let mut bytes: Vec<u8> = vec![MAGIC_NET_RESPAWNED];
+            bytes.extend_from_slice(&id.to_be_bytes()[..]); // the id -> 2 bytes
+            send_to_stream(&mut respawn.stream, &bytes);
+
+            self.client_streams
+                .lock()
+                .unwrap()
+                .insert(id, respawn.stream);
         }
-
-        send_to_stream(&mut stream, &bytes);
     }
     /// Iterates over all connected players and reads their inputs
     pub fn read_players_input(&self) {
@@ -565,10 +2113,15 @@ impl Server {
         let mut client_streams = self.client_streams.lock().unwrap();
         let ids: Vec<u16> = client_streams.iter().map(|(&id, _)| id).collect();
         for id in ids {
+            // Only the last valid direction change of this tick matters,
+            // so remember it and apply it after all messages are read
+            let mut new_direction = None;
+            let mut direction_changes: u32 = 0;
             loop {
-                let bytes = match read_from_stream(client_streams.get_mut(&id).unwrap()) {
+                let stream = client_streams.get_mut(&id).unwrap();
+                let bytes = match read_from_stream(stream, MAX_INPUT_SIZE) {
                     Ok(bytes) => bytes,
-                    Err(io::ErrorKind::WouldBlock) => {
+                    Err(NetError::WouldBlock) => {
                         // This means that we have the beginning of a message but not the end,
                         // So we will have to wait a little longer before parsing it
                         // But for now just carry on, so we don't block the thread
@@ -577,10 +2130,15 @@ impl Server {
                     Err(e) => {
                         // Conection was lost
                         // Clean everything up and move on
-                        println!(
-                            "connection to player \"{}\" was lost: {:?}",
-                            players[&id].nickname, e
-                        );
+                        match e {
+                            NetError::ConnectionClosed => {
+                                println!("\"{}\" closed the connection", players[&id].nickname)
+                            }
+                            e => println!(
+                                "connection to player \"{}\" was lost: {}",
+                                players[&id].nickname, e
+                            ),
+                        }
                         // Remove the snake
                         self.remove_snake(id, &mut players, &mut self.world.lock().unwrap());
                         // Remove the stream object
@@ -600,24 +2158,114 @@ impl Server {
                 }
 
                 if bytes.len() == 2 && bytes[0] == MAGIC_NET_CHANGE_DIRECTION {
-                    let new_direction = Direction::from_byte(bytes[1]);
+                    // Snakes can't turn while the game is paused
+                    if self.paused.load(Ordering::Relaxed) {
+                        continue;
+                    }
+                    direction_changes += 1;
+                    let direction = Direction::from_byte(bytes[1]);
                     // Make sure that the snake isn't doing a 180 degree turn, 'cause that shit illegal
-                    if new_direction.is_opposite_of(players[&id].last_direction) {
+                    // (unless it's allowed, then the snake just crashes into its own neck)
+                    if !self.config.allow_reverse
+                        && direction.is_opposite_of(players[&id].last_direction)
+                    {
                         continue;
                     }
-                    // Otherwise save the new direction
-                    players.get_mut(&id).unwrap().direction = new_direction;
+                    // Otherwise remember the new direction
+                    new_direction = Some(direction);
                 }
 
                 if bytes.len() == 1 && bytes[0] == MAGIC_NET_TOGGLE_FAST {
-                    // Make sure the snake has at least 1 score
-                    if players[&id].score == 0 {
+                    // Make sure fast mode is enabled, the snake has grown enough to afford it
+                    // and the game isn't paused
+                    if self.config.fast_mode_disabled
+                        || !self.can_go_fast(&players[&id])
+                        || self.paused.load(Ordering::Relaxed)
+                    {
                         continue;
                     }
                     // Ok, toggle it
-                    players.get_mut(&id).unwrap().fast_mode = !players[&id].fast_mode;
+                    let player = players.get_mut(&id).unwrap();
+                    player.fast_mode = !player.fast_mode;
+                    player.idle_ticks = 0;
+                }
+
+                if bytes.len() == 5 && bytes[0] == MAGIC_NET_PONG {
+                    let sequence = u32::from_be_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]);
+                    let player = players.get_mut(&id).unwrap();
+                    // Ignore answers to old pings
+                    if let Some((ping_sequence, sent_at)) = player.ping {
+                        if ping_sequence == sequence {
+                            player.rtt = Some(sent_at.elapsed());
+                            player.ping = None;
+                            player.missed_pings = 0;
+                        }
+                    }
+                }
+            }
+
+            // The player might have disconnected while reading
+            let player = match players.get_mut(&id) {
+                Some(player) => player,
+                None => continue,
+            };
+            if direction_changes > MAX_DIRECTION_CHANGES_PER_TICK && !player.flagged {
+                println!(
+                    "player \"{}\" sent {} direction changes in a single tick",
+                    player.nickname, direction_changes
+                );
+                player.flagged = true;
+            }
+            if let Some(direction) = new_direction {
+                player.direction = direction;
+                player.idle_ticks = 0;
+                // Protection is only for getting away from the spawn
+                player.protection_ticks = 0;
+            }
+        }
+    }
+    /// Sends a ping with the given sequence number to all connected players, along with
+    /// their last measured round-trip time. Drops the players that stopped answering
+    pub fn ping_players(&self, sequence: u32) {
+        let mut players = self.players.lock().unwrap();
+        let mut client_streams = self.client_streams.lock().unwrap();
+        let ids: Vec<u16> = client_streams.keys().copied().collect();
+        for id in ids {
+            let player = players.get_mut(&id).unwrap();
+            if player.ping.is_some() {
+                player.missed_pings += 1;
+                if player.missed_pings > MAX_MISSED_PINGS {
+                    println!(
+                        "connection to player \"{}\" was lost: not answering pings",
+                        player.nickname
+                    );
+                    // Remove the snake
+                    self.remove_snake(id, &mut players, &mut self.world.lock().unwrap());
+                    // Remove the stream object
+                    client_streams.remove(&id);
+
+                    continue;
                 }
             }
+            player.ping = Some((sequence, Instant::now()));
+
+            let mut bytes: Vec<u8> = vec![MAGIC_NET_PING];
+            bytes.extend_from_slice(&sequence.to_be_bytes()[..]); // sequence number -> 4 bytes
+            let rtt = match player.rtt {
+                Some(rtt) => rtt.as_millis().min(u16::MAX as u128 - 1) as u16,
+                None => u16::MAX,
+            };
+            bytes.extend_from_slice(&rtt.to_be_bytes()[..]); // round-trip time in ms, or 65535 if unknown -> 2 bytes
+            send_to_stream(client_streams.get_mut(&id).unwrap(), &bytes);
+        }
+
+        // Dead players waiting for a new snake get no frames, so without the pings their clients
+        // would give up on the server during a long round. Their answers are ignored
+        let mut bytes: Vec<u8> = vec![MAGIC_NET_PING];
+        bytes.extend_from_slice(&sequence.to_be_bytes()[..]); // sequence number -> 4 bytes
+        bytes.extend_from_slice(&u16::MAX.to_be_bytes()[..]); // round-trip time unknown -> 2 bytes
+        for respawn in self.respawning.lock().unwrap().iter_mut() {
+            send_to_stream(&mut respawn.stream, &bytes);
         }
     }
     /// Removes the Snake structure from players hashmap, and removes snake's parts from world, adds food instead
@@ -626,26 +2274,28 @@ impl Server {
         &self,
         id: u16,
         players_lock: &mut MutexGuard<HashMap<u16, Player>>,
-        mut world_lock: &mut MutexGuard<World>,
+        world_lock: &mut MutexGuard<World>,
     ) {
-        // Generate food where the snake was
-        let mut food_iterator = score_to_foods(players_lock[&id].score).into_iter();
+        // Remember how the game went
+        if let Some(stats) = &self.stats {
+            stats.lock().unwrap().record_game(&players_lock[&id]);
+        }
+
+        // Generate food where the snake was, the rest of the score is lost
+        let mut food_iterator =
+            score_to_foods(players_lock[&id].score, self.config.death_food_fraction).into_iter();
         let snake_length = calc_length(players_lock[&id].score);
         let mut rng = thread_rng();
         for i in 0..snake_length {
             match players_lock[&id].parts.get(i) {
                 Some(coordinates) => {
-                    let to_add = food_iterator
-                        .next()
-                        .expect("food_iterator unexpectedly ended");
-                    let available_indexes = self.sf_to_ff_index(*coordinates);
-                    // Add the food randomly there
-                    for _ in 0..to_add {
-                        let ff_index = available_indexes[rng.gen::<usize>() % 4];
-                        if world_lock.foods[ff_index].amount < 255 {
-                            world_lock.foods[ff_index].amount += 1;
-                        } else {
-                            self.add_food(&mut rng, &mut world_lock);
+                    // Every food field of the part gets its own amount
+                    for ff_index in self.sf_to_ff_index(*coordinates).iter() {
+                        let to_add = food_iterator
+                            .next()
+                            .expect("food_iterator unexpectedly ended");
+                        for _ in 0..to_add {
+                            self.add_food_at(&mut rng, world_lock, *ff_index);
                         }
                     }
                 }
@@ -655,9 +2305,14 @@ impl Server {
             }
         }
         // Calculate how much food is left to drop, and then drop it randomly in world
-        let to_drop_randomly = food_iterator.fold(0u16, |sum, x| sum + x as u16);
+        // (the overflow score didn't make the snake any longer, so it's dropped randomly too)
+        let overflow_food = (players_lock[&id].overflow_score as f32
+            * self.config.death_food_fraction)
+            .round() as u16;
+        let to_drop_randomly =
+            food_iterator.fold(overflow_food, |sum, x| sum.saturating_add(x as u16));
         for _ in 0..to_drop_randomly {
-            self.add_food(&mut rng, &mut world_lock);
+            self.add_food(&mut rng, world_lock, 1);
         }
 
         // Remove all snake parts from world
@@ -671,251 +2326,863 @@ impl Server {
             }
         }
 
+        // And its territory
+        if self.config.paint {
+            self.clear_territory(world_lock, id);
+        }
+
         // Remove the player object from the players list
         players_lock.remove(&id);
     }
     /// Moves all the snakes 1 field ahead to their facing direction, eating food along the way
     /// (if there's any), or killing them if they crash into other snakes
-    /// Also checks if any snakes are AFK and kicks them
-    pub fn move_snakes(&self) {
+    /// Also checks if any snakes are AFK and kicks them.
+    /// Returns how many snakes crashed
+    pub fn move_snakes(&self) -> usize {
         // Move each snake to it's facing direction
         let mut players = self.players.lock().unwrap();
         let mut world = self.world.lock().unwrap();
 
-        let ids: Vec<u16> = players.keys().copied().collect();
+        // Kick all the snakes that didn't send any input for too long.
+        // Bots running on this machine are never kicked, connections without an IP are local too
+        if let Some(afk_timeout) = self.config.afk_timeout {
+            let afk_snakes: Vec<u16> = players
+                .iter_mut()
+                .filter_map(|(&id, player)| {
+                    let local = match player.ip {
+                        Some(ip) => ip.is_loopback(),
+                        None => true,
+                    };
+                    if player.bot && local {
+                        return None;
+                    }
+                    player.idle_ticks += 1;
+                    if player.idle_ticks > afk_timeout {
+                        Some(id)
+                    } else {
+                        None
+                    }
+                })
+                .collect();
+            for id in afk_snakes {
+                println!("\"{}\" was kicked for being AFK", players[&id].nickname);
+                send_to_stream(
+                    self.client_streams.lock().unwrap().get_mut(&id).unwrap(),
+                    &[MAGIC_NET_DEATH],
+                );
+                self.remove_snake(id, &mut players, &mut world);
+                self.client_streams.lock().unwrap().remove(&id);
+            }
+        }
+
         // This vector contains all snake's head positions, 1 for each snake, or 2 if the snake is in fast mode
         // After moving all the snakes, all positions in this vector will be checked for crashes
         // And if no crashes will be detected, all food on those fields will be eaten
         let mut headposition_to_check: HashMap<SnakePartPos, Vec<u16>> =
             HashMap::with_capacity(players.len());
-        for snake_id in ids {
+        // Snakes that crashed into walls
+        let mut walled_snakes: Vec<u16> = Vec::new();
+        // How many new heads each snake got this tick
+        let mut moves_made: HashMap<u16, usize> = HashMap::with_capacity(players.len());
+        let mut rng = thread_rng();
+        for (&snake_id, snake) in players.iter_mut() {
             // If snake not long enough anymore, turn off fast mode
-            let snake = players.get_mut(&snake_id).unwrap();
-            if snake.fast_mode && snake.score < 1 {
+            if snake.fast_mode && !self.can_go_fast(snake) {
                 snake.fast_mode = false;
             }
 
-            if players[&snake_id].direction != players[&snake_id].last_direction {
-                // Change the last_direction
-                players.get_mut(&snake_id).unwrap().last_direction = players[&snake_id].direction;
-            }
+            // Change the last_direction
+            snake.last_direction = snake.direction;
 
             // If snake in fast mode make it move twice
-            let moves = if players[&snake_id].fast_mode { 2 } else { 1 };
+            let moves = if snake.fast_mode { 2 } else { 1 };
 
             // move it
+            let (dx, dy) = snake.direction.to_vector();
+            let mut moved = 0;
             for _ in 1..=moves {
                 // Calculate the new head position
-                let mut new_head_pos = *players[&snake_id].parts.back().unwrap();
-
-                let (dx, dy) = players[&snake_id].direction.to_vector();
+                let head_pos = *snake.parts.back().unwrap();
+
+                let new_head_pos = match self.offset_position(head_pos, dx, dy) {
+                    Some(new_head_pos) => new_head_pos,
+                    None => {
+                        // Crashed into a wall, a protected snake just stops in front of it
+                        if snake.protection_ticks == 0 {
+                            walled_snakes.push(snake_id);
+                        }
+                        break;
+                    }
+                };
 
-                let width = self.world_size.0 as i32;
-                let height = self.world_size.1 as i32;
-                new_head_pos.0 = (((new_head_pos.0 as i32 + dx) + width) % width) as u16;
-                new_head_pos.1 = (((new_head_pos.1 as i32 + dy) + height) % height) as u16;
+                // Walls of the map work just like the edges of the world
+                if world.snake_parts[self.sfield_index(new_head_pos)].id == WALL_ID {
+                    if snake.protection_ticks == 0 {
+                        walled_snakes.push(snake_id);
+                    }
+                    break;
+                }
 
                 // Add to snake (but not to world yet)
-                players
-                    .get_mut(&snake_id)
-                    .unwrap()
-                    .parts
-                    .push_back(new_head_pos);
+                snake.parts.push_back(new_head_pos);
 
                 headposition_to_check
                     .entry(new_head_pos)
                     .or_insert_with(Vec::new)
                     .push(snake_id);
+                moved += 1;
+            }
+            if moved > 0 {
+                moves_made.insert(snake_id, moved);
             }
 
             // If in fast mode, remove score
-            let to_remove = if players[&snake_id].score <= 100 {
+            let to_remove = if snake.total_score() <= 100 {
                 1
             } else {
-                (players[&snake_id].score as f32 * 0.01) as u16
+                (snake.total_score() as f32 * 0.01) as u16
             };
-            if players[&snake_id].fast_mode {
-                players.get_mut(&snake_id).unwrap().score -= to_remove;
+            if snake.fast_mode {
+                // Spend the overflow score first, so the snake doesn't shrink needlessly
+                let from_overflow = to_remove.min(snake.overflow_score);
+                snake.overflow_score -= from_overflow;
+                snake.score = snake.score.saturating_sub(to_remove - from_overflow);
             }
 
             let mut tail_pos = Vec::new();
             // If needed, remove parts from tail
-            for _ in 0..((players[&snake_id].parts.len() - 3) as u16)
-                .saturating_sub(calc_length(players[&snake_id].score) as u16)
+            for _ in
+                0..((snake.parts.len() - 3) as u16).saturating_sub(calc_length(snake.score) as u16)
             {
-                tail_pos.push(
-                    players
-                        .get_mut(&snake_id)
-                        .unwrap()
-                        .parts
-                        .pop_front()
-                        .unwrap(),
-                );
-                world.snake_parts[self.sfield_index(*tail_pos.last().unwrap())].id = 0;
+                let pos = snake.parts.pop_front().unwrap();
+                tail_pos.push(pos);
+                // A protected or phasing snake might have passed through another one here
+                let field = &mut world.snake_parts[self.sfield_index(pos)];
+                if field.id == snake_id {
+                    field.id = 0;
+                }
             }
 
             // If was in fast mode, add food on tail
-            if players[&snake_id].fast_mode {
+            if snake.fast_mode {
                 if tail_pos.is_empty() {
                     for _ in 0..to_remove {
-                        self.add_food(&mut thread_rng(), &mut world);
+                        self.add_food(&mut rng, &mut world, 1);
                     }
                 } else {
                     for _ in 0..to_remove {
                         let ff_index = self
-                            .sf_to_ff_index(tail_pos[thread_rng().gen::<usize>() % tail_pos.len()])
-                            [thread_rng().gen::<usize>() % 4];
-                        if world.foods[ff_index].amount < 255 {
-                            world.foods[ff_index].amount += 1;
-                        } else {
-                            self.add_food(&mut thread_rng(), &mut world);
-                        }
+                            .sf_to_ff_index(tail_pos[rng.gen::<usize>() % tail_pos.len()])
+                            [rng.gen::<usize>() % 4];
+                        self.add_food_at(&mut rng, &mut world, ff_index);
                     }
                 }
             }
         }
 
         // Now check all the head positions
-        let mut crashed_snakes: Vec<u16> = Vec::new();
-        for (field, ids) in headposition_to_check {
+        let mut crashed_snakes: Vec<u16> = walled_snakes;
+        // (killer, victim) nicknames of all kills that happened this tick
+        let mut kills: Vec<(String, String)> = Vec::new();
+        // (killer ID, kill streak, bonus score) of all kill streaks that went on this tick
+        let mut kill_streaks: Vec<(u16, u16, u16)> = Vec::new();
+        for (field, mut ids) in headposition_to_check {
             // Check if crashed
-            // If there's more than one, they all crash
+            // If there's more than one, they crash into each other, except the protected ones
             if ids.len() > 1 {
-                for id in ids {
+                let (protected, unprotected): (Vec<u16>, Vec<u16>) =
+                    ids.iter().partition(|&id| players[id].protection_ticks > 0);
+                // The policy only decides between the unprotected ones, if there are several
+                let survivor = match self.config.head_on {
+                    _ if unprotected.len() == 1 => Some(unprotected[0]),
+                    HeadOn::Longest => longest_snake(&players, &unprotected),
+                    HeadOn::BothDie | HeadOn::BothCredited => None,
+                };
+                for &id in &unprotected {
+                    if Some(id) == survivor {
+                        continue;
+                    }
                     crashed_snakes.push(id);
-                    // No need to add kills to anyone,
-                    // because all the other snakes
-                    // who might be responsible for this
-                    // death are also dead.
+                    if let Some(survivor) = survivor {
+                        let killer = players.get_mut(&survivor).unwrap();
+                        killer.kills += 1;
+                        if let Some((streak, bonus)) = self.continue_kill_streak(killer) {
+                            kill_streaks.push((survivor, streak, bonus));
+                        }
+                        kills.push((
+                            players[&survivor].nickname.clone(),
+                            players[&id].nickname.clone(),
+                        ));
+                    } else if self.config.head_on == HeadOn::BothCredited {
+                        // They're all dead, so there are no kill streaks to continue
+                        for &other in unprotected.iter().filter(|&&other| other != id) {
+                            players.get_mut(&other).unwrap().kills += 1;
+                            kills.push((
+                                players[&other].nickname.clone(),
+                                players[&id].nickname.clone(),
+                            ));
+                        }
+                    }
+                }
+                match (protected.first(), survivor) {
+                    // A protected head takes the field, and an unprotected one that's left runs
+                    // into it like into the body of the protected snake
+                    (Some(&protected), Some(survivor)) => {
+                        crashed_snakes.push(survivor);
+                        let killer = players.get_mut(&protected).unwrap();
+                        killer.kills += 1;
+                        if let Some((streak, bonus)) = self.continue_kill_streak(killer) {
+                            kill_streaks.push((protected, streak, bonus));
+                        }
+                        kills.push((
+                            players[&protected].nickname.clone(),
+                            players[&survivor].nickname.clone(),
+                        ));
+                        ids = vec![protected];
+                    }
+                    // It goes on as if it got here alone
+                    (Some(&id), None) | (None, Some(id)) => ids = vec![id],
+                    (None, None) => continue,
                 }
+            }
+            // A protected snake passes through everything, but only eats on free fields
+            let protected = players[&ids[0]].protection_ticks > 0;
+            let occupied = world.snake_parts[self.sfield_index(field)].id != 0;
+            if protected && (occupied || players[&ids[0]].body_contains(field, moves_made[&ids[0]]))
+            {
+                continue;
+            }
+            // A snake dies if its head enters a field occupied by its own body.
+            // The tail is already moved by now, so following it closely is fine,
+            // unless the snake grew this tick and the tail stayed in place
+            if players[&ids[0]].body_contains(field, moves_made[&ids[0]]) {
+                // No kill for anyone, it was a suicide
+                crashed_snakes.push(ids[0]);
                 continue;
             }
             if world.snake_parts[self.sfield_index(field)].id != 0 {
+                // A phasing snake passes through the bodies of other snakes, without eating there.
+                // Running into another head still kills it, that's handled above
+                if players[&ids[0]].phase_ticks > 0 {
+                    continue;
+                }
                 // Crash
                 crashed_snakes.push(ids[0]);
                 // Add a kill for the snake that killed it, unless it was a suicide
                 let foreign_id = world.snake_parts[self.sfield_index(field)].id;
                 if foreign_id != ids[0] {
-                    players.get_mut(&foreign_id).unwrap().kills += 1;
+                    let killer = players.get_mut(&foreign_id).unwrap();
+                    killer.kills += 1;
+                    if let Some((streak, bonus)) = self.continue_kill_streak(killer) {
+                        kill_streaks.push((foreign_id, streak, bonus));
+                    }
+                    kills.push((
+                        players[&foreign_id].nickname.clone(),
+                        players[&ids[0]].nickname.clone(),
+                    ));
                 }
                 continue;
             }
             // Otherwise, if there are no crashes:
             // Eat all the food on the head position
+            let snake = players.get_mut(&ids[0]).unwrap();
             for foodfield in self.sf_to_ff_index(field).iter() {
-                players.get_mut(&ids[0]).unwrap().score += world.foods[*foodfield].amount as u16;
+                snake.score = snake
+                    .score
+                    .saturating_add(self.food_score(world.foods[*foodfield].amount));
+                snake.food_eaten = snake
+                    .food_eaten
+                    .saturating_add(world.foods[*foodfield].amount as u32);
+                world.food_total -= world.foods[*foodfield].amount as u32;
                 world.foods[*foodfield].amount = 0;
             }
+            // Pick up the food magnet if there's one
+            if world.magnets.remove(&field) {
+                if let Some(magnet_duration) = self.config.magnet_duration {
+                    snake.magnet_ticks = magnet_duration;
+                }
+            }
+            // And the phase power-up
+            if world.phases.remove(&field) {
+                if let Some(phase_duration) = self.config.phase_duration {
+                    snake.phase_ticks = phase_duration;
+                }
+            }
+            // Don't let it grow past the maximum length
+            self.cap_length(snake);
             // And add the new part to the world
             world.snake_parts[self.sfield_index(field)].id = ids[0];
+            if self.config.paint {
+                self.paint(snake, ids[0], &mut world, field);
+            }
         }
 
         // Remove all duplicates from crashed_snakes (its possible to crash twice, when in fast mode)
         crashed_snakes.sort_unstable();
         crashed_snakes.dedup();
+        let deaths = crashed_snakes.len();
 
         // Now kill all the snakes that crashed
+        let mut client_streams = self.client_streams.lock().unwrap();
         for id in crashed_snakes {
-            // Send a message to them telling them that they're dead
-            send_to_stream(
-                &mut self.client_streams.lock().unwrap().get_mut(&id).unwrap(),
-                &[MAGIC_NET_DEATH],
-            );
+            let mut stream = client_streams.remove(&id).unwrap();
+            if self.config.auto_respawn || self.config.last_standing {
+                // Tell them that they're dead, but will get a new snake soon
+                send_to_stream(&mut stream, &[MAGIC_NET_DEATH, 1]);
+                self.respawning.lock().unwrap().push(Respawn {
+                    stream,
+                    nickname: players[&id].nickname.clone(),
+                    bot: players[&id].bot,
+                    died_at: Instant::now(),
+                    banked: players[&id].banked,
+                    ip: players[&id].ip,
+                });
+            } else {
+                // Send a message to them telling them that they're dead
+                send_to_stream(&mut stream, &[MAGIC_NET_DEATH]);
+            }
 
             // Kill it
             self.remove_snake(id, &mut players, &mut world);
-            self.client_streams.lock().unwrap().remove(&id);
+        }
+        drop(client_streams);
+
+        if self.config.paint {
+            self.score_territory(&mut players, &world);
+        }
+
+        // Snakes with food magnets pull in the food around them
+        for snake in players.values_mut() {
+            // Protection and phasing run out
+            snake.protection_ticks = snake.protection_ticks.saturating_sub(1);
+            snake.phase_ticks = snake.phase_ticks.saturating_sub(1);
+            // And kill streaks run out, if there was no kill for too long
+            if snake.kill_streak_ticks > 0 {
+                snake.kill_streak_ticks -= 1;
+                if snake.kill_streak_ticks == 0 {
+                    snake.kill_streak = 0;
+                }
+            }
+            if snake.magnet_ticks > 0 {
+                snake.magnet_ticks -= 1;
+                self.pull_food(snake, &mut world);
+            }
+            // Snakes that reach a scoring zone bank all the score they carry, and shrink back
+            if snake.total_score() > 0 && self.in_scoring_zone(*snake.parts.back().unwrap()) {
+                snake.banked = snake.banked.saturating_add(snake.total_score());
+                snake.score = 0;
+                snake.overflow_score = 0;
+                snake.fast_mode = false;
+            }
+        }
+        // And sometimes a new food magnet appears
+        if self.config.magnet_duration.is_some()
+            && world.magnets.len() < MAX_MAGNETS
+            && thread_rng().gen_range(0, MAGNET_SPAWN_CHANCE) == 0
+        {
+            self.spawn_magnet(&mut thread_rng(), &mut world);
+        }
+        // And, more rarely, a phase power-up
+        if self.config.phase_duration.is_some()
+            && world.phases.len() < MAX_PHASES
+            && thread_rng().gen_range(0, PHASE_SPAWN_CHANCE) == 0
+        {
+            self.spawn_phase(&mut thread_rng(), &mut world);
+        }
+
+        // Let everyone know who killed who
+        for (killer, victim) in kills {
+            let mut bytes: Vec<u8> = vec![MAGIC_NET_KILLFEED];
+            bytes.push(killer.len() as u8); // killer's nickname length -> 1 byte
+            bytes.extend_from_slice(killer.as_bytes()); // killer's nickname -> 1-10 bytes
+            bytes.push(victim.len() as u8); // victim's nickname length -> 1 byte
+            bytes.extend_from_slice(victim.as_bytes()); // victim's nickname -> 1-10 bytes
+            for stream in self.client_streams.lock().unwrap().values_mut() {
+                send_to_stream(stream, &bytes);
+            }
+        }
+        // And tell the killers about their kill streaks
+        for (id, streak, bonus) in kill_streaks {
+            // The killer might have crashed in the same tick
+            if let Some(stream) = self.client_streams.lock().unwrap().get_mut(&id) {
+                let mut bytes: Vec<u8> = vec![MAGIC_NET_KILL_STREAK];
+                bytes.extend_from_slice(&streak.to_be_bytes()[..]); // kill streak -> 2 bytes
+                bytes.extend_from_slice(&bonus.to_be_bytes()[..]); // bonus score -> 2 bytes
+                send_to_stream(stream, &bytes);
+            }
+        }
+
+        // Remember the best score of the session
+        let mut high_score = self.high_score.lock().unwrap();
+        if let Some(best) = players.values().max_by_key(|player| player.total_score()) {
+            if best.total_score() > high_score.0 {
+                *high_score = (best.total_score(), best.nickname.clone());
+            }
+        }
+        drop(high_score);
+
+        deaths
+    }
+    /// Counts a new kill towards the kill streak of the killer and gives it the bonus score.
+    /// Returns the kill streak and the bonus score, if it's long enough to be rewarded
+    pub fn continue_kill_streak(&self, killer: &mut Player) -> Option<(u16, u16)> {
+        let bonuses = self.config.kill_streak_bonus.as_ref()?;
+        killer.kill_streak = if killer.kill_streak_ticks > 0 {
+            killer.kill_streak.saturating_add(1)
+        } else {
+            1
+        };
+        killer.kill_streak_ticks = KILL_STREAK_WINDOW;
+        if killer.kill_streak < 2 {
+            return None;
+        }
+        let bonus = bonuses[(killer.kill_streak as usize - 2).min(bonuses.len() - 1)];
+        killer.score = killer.score.saturating_add(bonus);
+        self.cap_length(killer);
+        Some((killer.kill_streak, bonus))
+    }
+    /// Randomly removes 1 food from about every `SUDDEN_DEATH_DECAY_CHANCE`th food field
+    pub fn decay_food(&self) {
+        let mut world = self.world.lock().unwrap();
+        let mut rng = thread_rng();
+        let mut decayed = 0;
+        for field in world.foods.iter_mut() {
+            if field.amount > 0 && rng.gen_range(0, SUDDEN_DEATH_DECAY_CHANCE) == 0 {
+                field.amount -= 1;
+                decayed += 1;
+            }
+        }
+        world.food_total -= decayed;
+    }
+    /// Tells all players and spectators whether the game is paused
+    pub fn send_paused(&self, paused: bool) {
+        let bytes = [MAGIC_NET_PAUSED, paused as u8];
+        for stream in self.client_streams.lock().unwrap().values_mut() {
+            send_to_stream(stream, &bytes);
+        }
+        for spectator in self.spectators.lock().unwrap().values_mut() {
+            send_to_stream(&mut spectator.stream, &bytes);
+        }
+    }
+    /// Sends the given text to all players and spectators, to show it for a while
+    pub fn send_announcement(&self, text: &str) {
+        let mut bytes: Vec<u8> = vec![MAGIC_NET_ANNOUNCE];
+        bytes.extend_from_slice(text.as_bytes()); // the text -> the rest of the message
+        for stream in self.client_streams.lock().unwrap().values_mut() {
+            send_to_stream(stream, &bytes);
+        }
+        for spectator in self.spectators.lock().unwrap().values_mut() {
+            send_to_stream(&mut spectator.stream, &bytes);
+        }
+    }
+    /// Sends all players and spectators the area of the famine and how many seconds it lasts,
+    /// or that it's over
+    pub fn send_famine(&self, famine: Option<Famine>, game_speed: u8) {
+        let mut bytes: Vec<u8> = vec![MAGIC_NET_FAMINE];
+        if let Some(famine) = famine {
+            let seconds = (famine.ticks_left / game_speed as u32).min(u16::MAX as u32) as u16;
+            bytes.extend_from_slice(&famine.corner.0.to_be_bytes()[..]); // X of the top left corner -> 2 bytes
+            bytes.extend_from_slice(&famine.corner.1.to_be_bytes()[..]); // Y of the top left corner -> 2 bytes
+            bytes.extend_from_slice(&famine.size.0.to_be_bytes()[..]); // width -> 2 bytes
+            bytes.extend_from_slice(&famine.size.1.to_be_bytes()[..]); // height -> 2 bytes
+            bytes.extend_from_slice(&seconds.to_be_bytes()[..]); // seconds left -> 2 bytes
+        }
+        for stream in self.client_streams.lock().unwrap().values_mut() {
+            send_to_stream(stream, &bytes);
+        }
+        for spectator in self.spectators.lock().unwrap().values_mut() {
+            send_to_stream(&mut spectator.stream, &bytes);
+        }
+    }
+    /// Sends all players and spectators how many seconds are left until sudden death,
+    /// 0 meaning that it's already on
+    pub fn send_sudden_death_countdown(&self, seconds: u16) {
+        let mut bytes: Vec<u8> = vec![MAGIC_NET_SUDDEN_DEATH];
+        bytes.extend_from_slice(&seconds.to_be_bytes()[..]); // seconds left -> 2 bytes
+        for stream in self.client_streams.lock().unwrap().values_mut() {
+            send_to_stream(stream, &bytes);
+        }
+        for spectator in self.spectators.lock().unwrap().values_mut() {
+            send_to_stream(&mut spectator.stream, &bytes);
+        }
+    }
+    /// Eats all the food within `MAGNET_RADIUS` fields from the head of the snake
+    pub fn pull_food(&self, snake: &mut Player, world: &mut World) {
+        let head_pos = *snake.parts.back().unwrap();
+        for dy in -MAGNET_RADIUS..=MAGNET_RADIUS {
+            for dx in -MAGNET_RADIUS..=MAGNET_RADIUS {
+                let field = match self.offset_position(head_pos, dx, dy) {
+                    Some(field) => field,
+                    // Nothing behind walls
+                    None => continue,
+                };
+                for foodfield in self.sf_to_ff_index(field).iter() {
+                    let amount = world.foods[*foodfield].amount;
+                    snake.score = snake.score.saturating_add(self.food_score(amount));
+                    snake.food_eaten = snake.food_eaten.saturating_add(amount as u32);
+                    world.food_total -= amount as u32;
+                    world.foods[*foodfield].amount = 0;
+                }
+            }
+        }
+        // Don't let it grow past the maximum length
+        self.cap_length(snake);
+    }
+    /// The score for eating a food field with the given amount of food,
+    /// multiplied by `1 + food_bonus * (amount - 1)`
+    pub fn food_score(&self, amount: u8) -> u16 {
+        let multiplier = 1.0 + self.config.food_bonus * amount.saturating_sub(1) as f32;
+        (amount as f32 * multiplier).round().min(u16::MAX as f32) as u16
+    }
+    /// Puts a food magnet on a random field without a snake
+    pub fn spawn_magnet(&self, rng: &mut ThreadRng, world: &mut World) {
+        if let Some(pos) = self.power_up_field(rng, world) {
+            world.magnets.insert(pos);
         }
     }
+    /// Puts a phase power-up on a random field without a snake
+    pub fn spawn_phase(&self, rng: &mut ThreadRng, world: &mut World) {
+        if let Some(pos) = self.power_up_field(rng, world) {
+            world.phases.insert(pos);
+        }
+    }
+    /// Picks a random field without a snake or another power-up,
+    /// `None` if there wasn't one after a few tries because the world is crowded
+    fn power_up_field(&self, rng: &mut ThreadRng, world: &World) -> Option<SnakePartPos> {
+        for _ in 0..10 {
+            let pos = SnakePartPos(
+                rng.gen_range(0, self.config.world_size.0),
+                rng.gen_range(0, self.config.world_size.1),
+            );
+            if world.snake_parts[self.sfield_index(pos)].id == 0
+                && !world.magnets.contains(&pos)
+                && !world.phases.contains(&pos)
+            {
+                return Some(pos);
+            }
+        }
+        None
+    }
     /// Send game data to all connected players
     pub fn send_data_to_players(&self) {
-        let players = self.players.lock().unwrap();
-        let world = self.world.lock().unwrap();
+        let mut players = self.players.lock().unwrap();
+        let mut world = self.world.lock().unwrap();
         // First generate the general/shared part of the
         // buffer that's going to be sent to all players
         let mut bytes: Vec<u8> = Vec::new();
 
         bytes.push(MAGIC_NET_GAME_DATA);
 
+        // game speed right now -> 1 byte
+        bytes.push(self.current_game_speed.load(Ordering::Relaxed));
+
         // amount of snakes in total -> 2 bytes
         bytes.extend_from_slice(&(players.len() as u16).to_be_bytes()[..]);
 
         let snake_ids: Vec<u16> = players.keys().cloned().collect();
         for snake_id in &snake_ids {
-            let snake = &players[&snake_id];
-            bytes.extend_from_slice(&snake_id.to_be_bytes()[..]); // id -> 2 bytes
-            bytes.push(snake.nickname.len() as u8); // nickname length -> 1 byte
-            bytes.extend_from_slice(snake.nickname.as_bytes()); // nickname -> 1-10 bytes
-            bytes.extend_from_slice(&snake.score.to_be_bytes()[..]); // score -> 2 bytes
-            bytes.extend_from_slice(&snake.kills.to_be_bytes()[..]); // kills -> 2 bytes
-            bytes.extend_from_slice(&snake.parts.back().unwrap().0.to_be_bytes()[..]); // head position X -> 2 bytes
-            bytes.extend_from_slice(&snake.parts.back().unwrap().1.to_be_bytes()[..]); // head position Y -> 2 bytes
-            bytes.push(snake.fast_mode as u8); // fast mode -> 1 byte
+            bytes.extend_from_slice(&snake_data(*snake_id, &players[snake_id]));
         }
 
-        let world_size = (self.world_size.0 as i32, self.world_size.1 as i32);
+        // How far each snake part in the world is from the head, so clients can shade the bodies
+        let part_depths = self.part_depths(&players, &world);
 
         // Now individual data for each player
+        let mut client_streams = self.client_streams.lock().unwrap();
+        let mut lagging = Vec::new();
         for id in snake_ids {
-            let mut individual_bytes = bytes.clone();
+            let stream = client_streams.get_mut(&id).unwrap();
+            let player = players.get_mut(&id).unwrap();
+            // Skip the frame for a client that didn't read the previous ones yet,
+            // so that they don't pile up
+            if is_behind(stream) {
+                player.skipped_frames += 1;
+                if player.skipped_frames > MAX_SKIPPED_FRAMES {
+                    lagging.push(id);
+                }
+                continue;
+            }
+            player.skipped_frames = 0;
+
+            // The view is centered on the player's head
+            let player_head_pos = *player.parts.back().unwrap();
+            let view = self.view_data(&world, &part_depths, player_head_pos);
+            let individual_bytes =
+                self.frame_data(&bytes, &players, &world, Some(id), player_head_pos, &view);
+
+            // Send it
+            send_to_stream(stream, &individual_bytes[..]);
+        }
+        for id in lagging {
+            println!(
+                "connection to player \"{}\" was lost: not reading the game",
+                players[&id].nickname
+            );
+            self.remove_snake(id, &mut players, &mut world);
+            client_streams.remove(&id);
+        }
+        drop(client_streams);
+
+        // And for each spectator
+        let mut spectators = self.spectators.lock().unwrap();
+        let mut lagging = Vec::new();
+        for (&id, spectator) in spectators.iter_mut() {
+            if is_behind(&mut spectator.stream) {
+                spectator.skipped_frames += 1;
+                if spectator.skipped_frames > MAX_SKIPPED_FRAMES {
+                    lagging.push(id);
+                }
+                continue;
+            }
+            spectator.skipped_frames = 0;
 
-            let player_head_pos = *players[&id].parts.back().unwrap();
+            // Keep up with the followed snake, or stop following it once it's dead
+            if let Some(following) = spectator.following {
+                match players.get(&following) {
+                    Some(player) => spectator.camera = *player.parts.back().unwrap(),
+                    None => spectator.following = None,
+                }
+            }
 
-            let mut temp_snakes: Vec<u8> = Vec::new();
-            let mut temp_foods: Vec<u8> = Vec::new();
+            // The view is centered on the spectator's camera
+            let view = self.view_data(&world, &part_depths, spectator.camera);
+            let individual_bytes =
+                self.frame_data(&bytes, &players, &world, None, spectator.camera, &view);
 
-            // Iterate through every field in the view of the player
-            for y in -14i32..15i32 {
-                for x in -24i32..25i32 {
-                    let field = SnakePartPos(
-                        ((player_head_pos.0 as i32 + x + world_size.0 * 2) % world_size.0) as u16,
-                        ((player_head_pos.1 as i32 + y + world_size.1 * 2) % world_size.1) as u16,
-                    );
+            send_to_stream(&mut spectator.stream, &individual_bytes[..]);
+        }
+        for id in lagging {
+            spectators.remove(&id);
+        }
+    }
+    /// Puts together the game data for a single client from the part shared by everyone
+    /// and the view of the client. If there are too many snakes for all of them to fit in one
+    /// message, only the client's own snake, the snakes in its view and as many others as fit
+    /// are listed
+    pub fn frame_data(
+        &self,
+        shared: &[u8],
+        players: &HashMap<u16, Player>,
+        world: &World,
+        me: Option<u16>,
+        center: SnakePartPos,
+        view: &[u8],
+    ) -> Vec<u8> {
+        if shared.len() + view.len() <= MAX_MESSAGE_SIZE {
+            return [shared, view].concat();
+        }
 
-                    // Check if there's any snake here
-                    if world.snake_parts[self.sfield_index(field)].id != 0 {
-                        // There is
-                        temp_snakes.push((x as i8).to_be_bytes()[0]); // x pos (relative to player's head) of snake part -> 1 byte
-                        temp_snakes.push((y as i8).to_be_bytes()[0]); // y pos (relative to player's head) of snake part -> 1 byte
-                                                                      // id of the snake that the part belongs to -> 2 bytes
-                        temp_snakes.extend_from_slice(
-                            &world.snake_parts[self.sfield_index(field)].id.to_be_bytes()[..],
-                        );
-                    } else {
-                        // Check if there's any food here
-                        for (i, &foodfield) in self.sf_to_ff_index(field).iter().enumerate() {
-                            if world.foods[foodfield].amount > 0 {
-                                // There is
-                                temp_foods.push(
-                                    (x as i8 * 2 + if i == 1 || i == 3 { 1 } else { 0 })
-                                        .to_be_bytes()[0],
-                                ); // x pos (relative to player's head) of food -> 1 byte
-                                temp_foods.push(
-                                    (y as i8 * 2 + if i == 2 || i == 3 { 1 } else { 0 })
-                                        .to_be_bytes()[0],
-                                ); // y pos (relative to player's head) of food -> 1 byte
-                                   // amount of food here -> 1 byte
-                                temp_foods.push(world.foods[foodfield].amount.to_be_bytes()[0]);
-                            }
+        // The magic byte, the game speed and the amount of snakes stay in front
+        let budget = MAX_MESSAGE_SIZE - 4 - view.len();
+        let mut in_view: Vec<u16> = self.snakes_in_view(world, center).into_iter().collect();
+        in_view.sort_unstable();
+        let mut listed = HashSet::new();
+        let mut snakes: Vec<u8> = Vec::new();
+        for &id in me.iter().chain(&in_view).chain(players.keys()) {
+            let snake = match players.get(&id) {
+                Some(snake) if !listed.contains(&id) => snake,
+                _ => continue,
+            };
+            let data = snake_data(id, snake);
+            if snakes.len() + data.len() > budget {
+                continue;
+            }
+            snakes.extend_from_slice(&data);
+            listed.insert(id);
+        }
+
+        let mut bytes = shared[..2].to_vec();
+        bytes.extend_from_slice(&(listed.len() as u16).to_be_bytes()[..]); // amount of snakes listed -> 2 bytes
+        bytes.extend_from_slice(&snakes);
+        bytes.extend_from_slice(view);
+        bytes
+    }
+    /// Returns the IDs of the snakes with parts in the view around the given center,
+    /// including the margin around it
+    pub fn snakes_in_view(&self, world: &World, center: SnakePartPos) -> HashSet<u16> {
+        let mut ids = HashSet::new();
+        for y in (-14i32 - SNAKE_PARTS_MARGIN)..(15i32 + SNAKE_PARTS_MARGIN) {
+            for x in (-24i32 - SNAKE_PARTS_MARGIN)..(25i32 + SNAKE_PARTS_MARGIN) {
+                if let Some(field) = self.offset_position(center, x, y) {
+                    let id = world.snake_parts[self.sfield_index(field)].id;
+                    if id != 0 {
+                        ids.insert(id);
+                    }
+                }
+            }
+        }
+        ids
+    }
+    /// Returns how many parts away from the head of its snake every snake part is, the head being 0.
+    /// Walls and the parts other snakes pass through while phasing are left out
+    pub fn part_depths(
+        &self,
+        players: &HashMap<u16, Player>,
+        world: &World,
+    ) -> HashMap<SnakePartPos, u16> {
+        let mut depths = HashMap::new();
+        for (&id, player) in players {
+            for (depth, &part) in player.parts.iter().rev().enumerate() {
+                if world.snake_parts[self.sfield_index(part)].id == id {
+                    depths.entry(part).or_insert(depth as u16);
+                }
+            }
+        }
+        depths
+    }
+    /// Generates the part of the game data that depends on the view:
+    /// foods and snake parts around the given center, and the center itself
+    pub fn view_data(
+        &self,
+        world: &World,
+        part_depths: &HashMap<SnakePartPos, u16>,
+        center: SnakePartPos,
+    ) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::new();
+
+        let mut temp_snakes: Vec<u8> = Vec::new();
+        let mut temp_foods: Vec<u8> = Vec::new();
+        let mut temp_magnets: Vec<u8> = Vec::new();
+        let mut temp_phases: Vec<u8> = Vec::new();
+        let mut temp_territory: Vec<u8> = Vec::new();
+
+        // Iterate through every field in the view (and the margin around it)
+        for y in (-14i32 - SNAKE_PARTS_MARGIN)..(15i32 + SNAKE_PARTS_MARGIN) {
+            for x in (-24i32 - SNAKE_PARTS_MARGIN)..(25i32 + SNAKE_PARTS_MARGIN) {
+                let in_view = (-24..25).contains(&x) && (-14..15).contains(&y);
+                let field = match self.offset_position(center, x, y) {
+                    Some(field) => field,
+                    // Nothing behind walls
+                    None => continue,
+                };
+
+                // Check if there's a food magnet here
+                if in_view && world.magnets.contains(&field) {
+                    temp_magnets.push((x as i8).to_be_bytes()[0]); // x pos (relative to the center) of food magnet -> 1 byte
+                    temp_magnets.push((y as i8).to_be_bytes()[0]); // y pos (relative to the center) of food magnet -> 1 byte
+                }
+
+                // Check if there's a phase power-up here
+                if in_view && world.phases.contains(&field) {
+                    temp_phases.push((x as i8).to_be_bytes()[0]); // x pos (relative to the center) of phase power-up -> 1 byte
+                    temp_phases.push((y as i8).to_be_bytes()[0]); // y pos (relative to the center) of phase power-up -> 1 byte
+                }
+
+                // Check if someone owns the field, when painting
+                if in_view && self.config.paint && world.owners[self.sfield_index(field)] != 0 {
+                    temp_territory.push((x as i8).to_be_bytes()[0]); // x pos (relative to the center) of the field -> 1 byte
+                    temp_territory.push((y as i8).to_be_bytes()[0]); // y pos (relative to the center) of the field -> 1 byte
+                    let owner = world.owners[self.sfield_index(field)];
+                    temp_territory.extend_from_slice(&owner.to_be_bytes()[..]); // id of the snake that owns it -> 2 bytes
+                }
+
+                // Check if there's any snake here
+                if world.snake_parts[self.sfield_index(field)].id != 0 {
+                    // There is
+                    temp_snakes.push((x as i8).to_be_bytes()[0]); // x pos (relative to the center) of snake part -> 1 byte
+                    temp_snakes.push((y as i8).to_be_bytes()[0]); // y pos (relative to the center) of snake part -> 1 byte
+                    let snake_id = world.snake_parts[self.sfield_index(field)].id;
+                    temp_snakes.extend_from_slice(&snake_id.to_be_bytes()[..]); // id of the snake that the part belongs to -> 2 bytes
+                    let depth = part_depths.get(&field).copied().unwrap_or(0);
+                    temp_snakes.extend_from_slice(&depth.to_be_bytes()[..]); // how far the part is from the head of its snake -> 2 bytes
+                } else if in_view {
+                    let foodfields = self.sf_to_ff_index(field);
+                    let tier = food_tier(
+                        foodfields
+                            .iter()
+                            .map(|&foodfield| world.foods[foodfield].amount as u16)
+                            .sum(),
+                    );
+                    // Check if there's any food here
+                    for (i, &foodfield) in foodfields.iter().enumerate() {
+                        if world.foods[foodfield].amount > 0 {
+                            // There is
+                            temp_foods.push(
+                                (x as i8 * 2 + if i == 1 || i == 3 { 1 } else { 0 }).to_be_bytes()
+                                    [0],
+                            ); // x pos (relative to the center) of food -> 1 byte
+                            temp_foods.push(
+                                (y as i8 * 2 + if i == 2 || i == 3 { 1 } else { 0 }).to_be_bytes()
+                                    [0],
+                            ); // y pos (relative to the center) of food -> 1 byte
+                               // amount of food here -> 1 byte
+                            temp_foods.push(world.foods[foodfield].amount.to_be_bytes()[0]);
+                            temp_foods.push(tier); // tier of the whole field -> 1 byte
                         }
                     }
                 }
             }
-            individual_bytes.extend_from_slice(&((temp_foods.len() / 3) as u16).to_be_bytes()[..]); // Count of foods -> 2 bytes
-            individual_bytes.extend_from_slice(&temp_foods[..]); // Foods -> 0-2842 bytes
+        }
+        bytes.extend_from_slice(&((temp_foods.len() / 4) as u16).to_be_bytes()[..]); // Count of foods -> 2 bytes
+        bytes.extend_from_slice(&temp_foods[..]); // Foods -> 4 bytes each
 
-            individual_bytes.extend_from_slice(&((temp_snakes.len() / 4) as u16).to_be_bytes()[..]); // Count of snake parts -> 2 bytes
-            individual_bytes.extend_from_slice(&temp_snakes[..]); // Snake parts -> 0-5684 bytes
+        bytes.extend_from_slice(&((temp_snakes.len() / 6) as u16).to_be_bytes()[..]); // Count of snake parts -> 2 bytes
+        bytes.extend_from_slice(&temp_snakes[..]); // Snake parts -> 0-11550 bytes
 
-            // Snake's head position relative to world -> 4 bytes
-            individual_bytes.extend_from_slice(&(player_head_pos.0).to_be_bytes()[..]);
-            individual_bytes.extend_from_slice(&(player_head_pos.1).to_be_bytes()[..]);
+        // The center's position relative to world -> 4 bytes
+        bytes.extend_from_slice(&(center.0).to_be_bytes()[..]);
+        bytes.extend_from_slice(&(center.1).to_be_bytes()[..]);
 
-            // Send it
-            send_to_stream(
-                &mut self.client_streams.lock().unwrap().get_mut(&id).unwrap(),
-                &individual_bytes[..],
-            );
+        bytes.extend_from_slice(&((temp_magnets.len() / 2) as u16).to_be_bytes()[..]); // Count of food magnets -> 2 bytes
+        bytes.extend_from_slice(&temp_magnets[..]); // Food magnets -> 0-2842 bytes
+
+        bytes.extend_from_slice(&((temp_territory.len() / 4) as u16).to_be_bytes()[..]); // Count of owned fields -> 2 bytes
+        bytes.extend_from_slice(&temp_territory[..]); // Owned fields -> 0-5684 bytes
+
+        bytes.extend_from_slice(&((temp_phases.len() / 2) as u16).to_be_bytes()[..]); // Count of phase power-ups -> 2 bytes
+        bytes.extend_from_slice(&temp_phases[..]); // Phase power-ups -> 0-2842 bytes
+
+        bytes
+    }
+    /// Iterates over all spectators and reads their inputs
+    pub fn read_spectators_input(&self) {
+        let players = self.players.lock().unwrap();
+        let mut spectators = self.spectators.lock().unwrap();
+        let ids: Vec<u16> = spectators.keys().copied().collect();
+        for id in ids {
+            loop {
+                let spectator = spectators.get_mut(&id).unwrap();
+                let bytes = match read_from_stream(&mut spectator.stream, MAX_INPUT_SIZE) {
+                    Ok(bytes) => bytes,
+                    Err(NetError::WouldBlock) => break,
+                    Err(_) => {
+                        // Conection was lost
+                        spectators.remove(&id);
+                        break;
+                    }
+                };
+                if bytes.len() == 1 && bytes[0] == MAGIC_NET_EXIT {
+                    spectators.remove(&id);
+                    break;
+                }
+
+                if bytes.len() == 2 && bytes[0] == MAGIC_NET_MOVE_CAMERA {
+                    // Move the camera, stopping at the edges of the world.
+                    // It stays where the followed snake was
+                    spectator.following = None;
+                    let (dx, dy) = Direction::from_byte(bytes[1]).to_vector();
+                    let width = self.config.world_size.0 as i32;
+                    let height = self.config.world_size.1 as i32;
+                    spectator.camera.0 = (spectator.camera.0 as i32 + dx * CAMERA_STEP)
+                        .max(0)
+                        .min(width - 1) as u16;
+                    spectator.camera.1 = (spectator.camera.1 as i32 + dy * CAMERA_STEP)
+                        .max(0)
+                        .min(height - 1) as u16;
+                } else if bytes.len() == 1 && bytes[0] == MAGIC_NET_FOLLOW_NEXT_SNAKE {
+                    // Follow the living snake with the next ID, going back to the first after the last
+                    let current = spectator.following.unwrap_or(0);
+                    let next = players
+                        .keys()
+                        .filter(|&&id| id > current)
+                        .min()
+                        .or_else(|| players.keys().min());
+                    if let Some(&next) = next {
+                        spectator.following = Some(next);
+                        spectator.camera = *players[&next].parts.back().unwrap();
+                    }
+                }
+            }
         }
     }
 }
@@ -923,64 +3190,237 @@ impl Server {
 impl Clone for Server {
     fn clone(&self) -> Self {
         Server {
-            max_players: self.max_players,
+            config: self.config.clone(),
             players: self.players.clone(),
             client_streams: self.client_streams.clone(),
-            world_size: self.world_size,
+            spectators: self.spectators.clone(),
+            respawning: self.respawning.clone(),
             world: self.world.clone(),
-            game_speed: self.game_speed,
-            food_rate: self.food_rate,
-            port: self.port,
-            bots: self.bots,
+            current_game_speed: self.current_game_speed.clone(),
+            active_bots: self.active_bots.clone(),
+            stats: self.stats.clone(),
+            paused: self.paused.clone(),
+            shutdown: self.shutdown.clone(),
+            started_at: self.started_at,
+            connections: self.connections.clone(),
+            addresses: self.addresses.clone(),
+            peak_humans: self.peak_humans.clone(),
+            rooms: self.rooms.clone(),
+            room_threads: self.room_threads.clone(),
+            high_score: self.high_score.clone(),
+            scoring_zones: self.scoring_zones.clone(),
+            #[cfg(feature = "http-stats")]
+            metrics: self.metrics.clone(),
         }
     }
 }
 
-/// Reads 1 message from stream
+/// Reads 1 message, no bigger than `max_size` bytes, from stream
 /// Returns `Ok(bytes)` if the reading was successful
 /// and `Err(e)` if an error was encountered while reading
-pub fn read_from_stream(stream: &mut TcpStream) -> Result<Vec<u8>, io::ErrorKind> {
-    // Figure out the size of the incoming message
-    let mut size = [0u8];
-    if let Err(e) = stream.read_exact(&mut size) {
-        return Err(e.kind());
+pub fn read_from_stream(stream: &mut Stream, max_size: usize) -> Result<Vec<u8>, NetError> {
+    // Figure out the size of the incoming message.
+    // Nothing is consumed until the whole message arrives, it can come in several parts
+    let size = u8::from_be_bytes([stream.peek_exact(1)?[0]]);
+    // Every message has at least the magic byte,
+    // and don't even allocate the memory for messages that are too big
+    if size == 0 || size as usize > max_size {
+        return Err(NetError::Malformed);
     }
-    let size = u8::from_be_bytes(size);
 
     // Get the actual message
-    let mut bytes = vec![0u8; size as usize];
-    if let Err(e) = stream.read_exact(&mut bytes) {
-        return Err(e.kind());
-    }
+    let bytes = stream.peek_exact(1 + size as usize)?[1..].to_vec();
+    stream.consume(1 + size as usize);
     Ok(bytes)
 }
 
 /// Sends bytes to stream, with the buffer length appended to the beginning as an u16 integer
-pub fn send_to_stream(stream: &mut TcpStream, data: &[u8]) {
+pub fn send_to_stream(stream: &mut Stream, data: &[u8]) {
+    // A longer message can't be sent whole, and the client would read the rest as more messages
+    if data.len() > MAX_MESSAGE_SIZE {
+        println!("a message of {} bytes is too long to be sent", data.len());
+        return;
+    }
     let size: [u8; 2] = u16::to_be_bytes(data.len() as u16);
     let mut message: Vec<u8> = Vec::new();
     message.extend_from_slice(&size);
     message.extend_from_slice(data);
 
-    let _ = stream.write_all(&message);
+    // Errors are noticed when reading, or when the client stops answering pings
+    let _ = stream.write_queued(&message);
 }
 
-/// Takes a score as an argument and returns a vector of foods that they snake should drop
-pub fn score_to_foods(score: u16) -> Vec<u8> {
-    // The count of separate fields that the food will be dropped to
-    let count = calc_length(score);
+/// Writes what's left of the earlier messages to the stream, and returns `true` if some of it
+/// still doesn't fit because the client isn't reading fast enough
+pub fn is_behind(stream: &mut Stream) -> bool {
+    let _ = stream.write_unsent();
+    stream.is_behind()
+}
+
+/// Sends an error of the given kind to stream, the connection should be dropped afterwards
+pub fn send_error(stream: &mut Stream, kind: u8, text: &str) {
+    let mut message = vec![MAGIC_NET_ERROR, kind];
+    message.extend_from_slice(text.as_bytes());
+    send_to_stream(stream, &message);
+}
+
+/// Returns what the game data says about a single snake
+pub fn snake_data(id: u16, snake: &Player) -> Vec<u8> {
+    let mut bytes: Vec<u8> = Vec::new();
+    bytes.extend_from_slice(&id.to_be_bytes()[..]); // id -> 2 bytes
+    bytes.push(snake.nickname.len() as u8); // nickname length -> 1 byte
+    bytes.extend_from_slice(snake.nickname.as_bytes()); // nickname -> 1-10 bytes
+    bytes.extend_from_slice(&snake.total_score().to_be_bytes()[..]); // score -> 2 bytes
+    bytes.extend_from_slice(&snake.kills.to_be_bytes()[..]); // kills -> 2 bytes
+    bytes.extend_from_slice(&snake.parts.back().unwrap().0.to_be_bytes()[..]); // head position X -> 2 bytes
+    bytes.extend_from_slice(&snake.parts.back().unwrap().1.to_be_bytes()[..]); // head position Y -> 2 bytes
+
+    // fast mode (bit 0), food magnet (bit 1), spawn protection (bit 2), bot (bit 3)
+    // and phasing (bit 4) -> 1 byte
+    bytes.push(
+        snake.fast_mode as u8
+            | ((snake.magnet_ticks > 0) as u8) << 1
+            | ((snake.protection_ticks > 0) as u8) << 2
+            | (snake.bot as u8) << 3
+            | ((snake.phase_ticks > 0) as u8) << 4,
+    );
+    bytes.extend_from_slice(&(snake.parts.len() as u16).to_be_bytes()[..]); // length -> 2 bytes
+    bytes.extend_from_slice(&snake.banked.to_be_bytes()[..]); // banked score -> 2 bytes
+    bytes
+}
+
+/// Returns the tier of a field with the given amount of food in total, from 0 to 3.
+/// A snake eats the whole field at once, so clients color all of its food by this
+pub fn food_tier(amount: u16) -> u8 {
+    if amount < 2 {
+        0
+    } else if amount < 5 {
+        1
+    } else if amount < 11 {
+        2
+    } else {
+        3
+    }
+}
+
+/// Moves a single coordinate by the given offset, according to what's at the edge of the axis.
+/// Returns `None` if the new coordinate would be behind a wall
+pub fn offset_coordinate(coordinate: u16, offset: i32, size: u16, edge: Edge) -> Option<u16> {
+    let new = coordinate as i32 + offset;
+    match edge {
+        Edge::Wrap => Some(new.rem_euclid(size as i32) as u16),
+        Edge::Wall if new >= 0 && new < size as i32 => Some(new as u16),
+        Edge::Wall => None,
+    }
+}
+
+/// Returns the longest of the given snakes, or `None` if several are the longest
+fn longest_snake(players: &HashMap<u16, Player>, ids: &[u16]) -> Option<u16> {
+    let longest = ids.iter().map(|id| players[id].parts.len()).max()?;
+    let mut longest_ids = ids.iter().filter(|id| players[id].parts.len() == longest);
+    match (longest_ids.next(), longest_ids.next()) {
+        (Some(&id), None) => Some(id),
+        _ => None,
+    }
+}
+
+/// Takes a score as an argument and returns a vector of foods that they snake should drop,
+/// 4 food fields for every snake part. The amounts differ by at most 1 and add up to
+/// the given fraction of the score
+pub fn score_to_foods(score: u16, fraction: f32) -> Vec<u8> {
+    // The count of separate food fields that the food will be dropped to
+    let count = calc_length(score) * 4;
     let mut foods = Vec::with_capacity(count);
 
-    let mut n = score as i32;
+    // Spread the remainder evenly instead of putting it all in the first fields.
+    // Every field gets at most ceil(score / (4 * sqrt(score))) <= 64 food, so it fits in u8
+    let dropped = (score as f32 * fraction).round() as usize;
     for i in 0..count {
-        let amount = (n as f32 / (count - i) as f32).ceil() as u8;
-        n -= amount as i32;
-        foods.push(amount);
+        foods.push((dropped * (i + 1) / count - dropped * i / count) as u8);
     }
     foods
 }
 
+/// Returns how long a tick takes at the given game speed
+pub fn tick_time(game_speed: u8) -> Duration {
+    Duration::from_micros((1_000_000f64 / game_speed as f64) as u64)
+}
+
+/// Returns how many foods a world of the given size gets for the food rate,
+/// 1 for every `food_rate` food fields
+pub fn food_for_rate(world_size: (u16, u16), food_rate: u8) -> u32 {
+    ((world_size.0 as u32 * world_size.1 as u32 * 4) as f64 / food_rate as f64) as u32
+}
+
+/// Returns how many bytes of memory a world of the given size takes,
+/// 1 snake field and 4 food fields for every position, and the owner of it when painting
+pub fn world_bytes(world_size: (u16, u16), paint: bool) -> u64 {
+    let fields = world_size.0 as u64 * world_size.1 as u64;
+    fields * std::mem::size_of::<SField>() as u64
+        + fields * 4 * std::mem::size_of::<FField>() as u64
+        + if paint { fields * 2 } else { 0 }
+}
+
+/// Makes sure that the given amount of worlds of the given size don't take more than
+/// the given amount of bytes together
+pub fn check_world_bytes(
+    world_size: (u16, u16),
+    paint: bool,
+    worlds: u64,
+    max_world_bytes: u64,
+) -> Result<(), String> {
+    let bytes = world_bytes(world_size, paint).saturating_mul(worlds);
+    if bytes > max_world_bytes {
+        let what = if worlds == 1 {
+            format!("a {}x{} world needs", world_size.0, world_size.1)
+        } else {
+            format!(
+                "{} worlds of {}x{} (the main one and --max-rooms) need",
+                worlds, world_size.0, world_size.1
+            )
+        };
+        return Err(format!(
+            "{} {} bytes of memory, more than --max-world-bytes ({})",
+            what, bytes, max_world_bytes
+        ));
+    }
+    Ok(())
+}
+
+/// Writes the data to a temporary file first and then moves it over the given file,
+/// so neither a reader nor a crash ever sees it half written
+pub fn write_atomically(path: &Path, writer: &str, data: &str) -> io::Result<()> {
+    let temporary_path = temporary_file(path, writer);
+    fs::write(&temporary_path, data)?;
+    fs::rename(&temporary_path, path)
+}
+
+/// Returns the temporary file `write_atomically` uses for the given file.
+/// It's named after the writer too, so two writers never write through the same one
+pub fn temporary_file(path: &Path, writer: &str) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(format!(".{}.tmp", writer));
+    path.with_file_name(file_name)
+}
+
 /// Takes a score as an argument and returns the length of snake
 pub fn calc_length(score: u16) -> usize {
-    (score as f32).sqrt().ceil() as usize
+    // The ceiling of the square root, with integers only so it's exact for every score
+    let score = score as u32;
+    if score == 0 {
+        return 0;
+    }
+    // Newton's method gives the square root rounded down
+    let mut root = score;
+    let mut next = (root + score / root) / 2;
+    while next < root {
+        root = next;
+        next = (root + score / root) / 2;
+    }
+    // Round it up, unless the score is a perfect square
+    if root * root < score {
+        root as usize + 1
+    } else {
+        root as usize
+    }
 }
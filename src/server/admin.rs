@@ -0,0 +1,142 @@
+use super::{Server, ACCEPT_INTERVAL};
+use crate::stream::{Listener, Stream};
+#[cfg(unix)]
+use std::fs;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::thread::{self, sleep, JoinHandle};
+
+/// Starts a thread accepting admin connections on the Unix domain socket at the given path,
+/// until the server is shut down. Admins send one command per line and get a response to each
+pub fn spawn(server: Server, path: PathBuf) -> Option<JoinHandle<()>> {
+    let listener = match Listener::bind(0, Some(&path)) {
+        Ok(listener) => listener,
+        Err(e) => {
+            println!("Can't bind the admin socket {}: {}", path.display(), e);
+            return None;
+        }
+    };
+    // Only the user running the server can give it commands
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if let Err(e) = fs::set_permissions(&path, fs::Permissions::from_mode(0o600)) {
+            println!(
+                "Can't restrict the permissions of the admin socket {}: {}",
+                path.display(),
+                e
+            );
+            return None;
+        }
+    }
+    // Don't wait for an admin forever, so that the shutdown is noticed
+    if let Err(e) = listener.set_nonblocking(true) {
+        println!("Can't accept admin commands: {}", e);
+        return None;
+    }
+    println!("Admin commands are accepted on {}", path.display());
+    let handle = thread::Builder::new()
+        .name("admin_acceptor".to_string())
+        .spawn(move || {
+            while !server.shutdown.load(Ordering::Relaxed) {
+                match listener.accept() {
+                    Ok((stream, _address)) => {
+                        if stream.set_nonblocking(false).is_err() {
+                            continue;
+                        }
+                        let server = server.clone();
+                        thread::Builder::new()
+                            .name("admin_handler".to_string())
+                            .spawn(move || server.handle_admin_connection(stream))
+                            .unwrap();
+                    }
+                    Err(_) => sleep(ACCEPT_INTERVAL),
+                }
+            }
+        })
+        .unwrap();
+    Some(handle)
+}
+
+impl Server {
+    /// Answers the commands of an admin until they disconnect
+    pub fn handle_admin_connection(&self, stream: Stream) {
+        let mut writer = match stream.try_clone() {
+            Ok(writer) => writer,
+            Err(_) => return,
+        };
+        for line in BufReader::new(stream).lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => return,
+            };
+            let command: Vec<&str> = line.split_whitespace().collect();
+            if command.is_empty() {
+                continue;
+            }
+            let response = self.handle_admin_command(&command);
+            if writer.write_all(response.as_bytes()).is_err() {
+                return;
+            }
+        }
+    }
+    /// Executes a single admin command and returns the response, ending with a newline.
+    ///
+    /// This runs on the admin thread, so it only changes the atomics shared with the game loop
+    /// and holds the locks just long enough to look at the players
+    pub fn handle_admin_command(&self, command: &[&str]) -> String {
+        match command {
+            ["pause"] => {
+                if self.paused.swap(true, Ordering::Relaxed) {
+                    "the game is already paused\n".to_string()
+                } else {
+                    "paused\n".to_string()
+                }
+            }
+            ["resume"] => {
+                if self.paused.swap(false, Ordering::Relaxed) {
+                    "resumed\n".to_string()
+                } else {
+                    "the game is not paused\n".to_string()
+                }
+            }
+            ["shutdown"] => {
+                self.shutdown.store(true, Ordering::Relaxed);
+                "shutting down\n".to_string()
+            }
+            ["stats"] => self.usage_summary(),
+            ["players"] => self.players_summary(),
+            ["help"] => "commands: pause, resume, stats, players, shutdown, help\n".to_string(),
+            _ => format!("unknown command \"{}\", try help\n", command.join(" ")),
+        }
+    }
+    /// Lists the players with how many bytes were sent to and received from each of them
+    pub fn players_summary(&self) -> String {
+        let players = self.players.lock().unwrap();
+        let client_streams = self.client_streams.lock().unwrap();
+        let mut ids: Vec<&u16> = client_streams.keys().collect();
+        ids.sort();
+        if ids.is_empty() {
+            return "nobody is playing\n".to_string();
+        }
+        let mut summary = String::new();
+        for id in ids {
+            let stream = &client_streams[id];
+            let (nickname, bot) = match players.get(id) {
+                Some(player) => (player.nickname.as_str(), player.bot),
+                None => ("", false),
+            };
+            summary += &format!(
+                "{} \"{}\"{}: {} bytes sent, {} bytes received\n",
+                id,
+                nickname,
+                if bot { " (bot)" } else { "" },
+                stream.bytes_sent(),
+                stream.bytes_received()
+            );
+        }
+        summary
+    }
+}
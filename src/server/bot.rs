@@ -1,36 +1,168 @@
+use super::{MAX_JOIN_RESPONSE_SIZE, WALL_ID};
+use crate::stream::{NetError, Stream};
+use crate::wrap::wrap_delta;
 use rand::prelude::*;
 use std::collections::HashMap;
 use std::io::{Read, Write};
-use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// The biggest message the server can send during the game, a frame being the longest
+const MAX_MESSAGE_SIZE: usize = u16::MAX as usize;
+// How often a bot that isn't allowed to play checks if it can join again
+const BOT_WAIT_INTERVAL: Duration = Duration::from_secs(1);
+// How long a bot waits after failing to join, doubled after each failure in a row
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+// The longest a bot waits between attempts to join
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(30);
+// How close another snake has to be for a cowardly bot to run away from it
+const FLEE_DISTANCE: i8 = 5;
+// How close the head of another snake has to be for an aggressive bot to go after it
+const CHASE_DISTANCE: i8 = 10;
+// How many fields in front of the head of another snake an aggressive bot aims, to cut it off
+const CUT_OFF_LEAD: i8 = 3;
+
+/// How a bot plays
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Personality {
+    /// Goes for the most and closest food
+    Greedy,
+    /// Keeps away from other snakes, and only goes fast to escape them
+    Cowardly,
+    /// Tries to cut off other snakes by getting in front of their heads
+    Aggressive,
+}
+
+/// What a bot sees of a single frame
+pub struct GameData {
+    /// The amount of food on the food fields around my head
+    foods: HashMap<(i8, i8), u8>,
+    /// The IDs of the snakes that the parts around my head belong to
+    snake_parts: HashMap<(i8, i8), u16>,
+    /// The heads of the other snakes, relative to my head
+    heads: HashMap<u16, (i8, i8)>,
+    in_fast_mode: bool,
+}
 
 /// A bot structure, holds everything together
 pub struct Bot {
-    stream: TcpStream,
+    stream: Stream,
     my_id: u16,
     nickname: String,
+    /// The size of the world, to tell where the other snakes are relative to me
+    world_size: (u16, u16),
+    /// Whether the edges of the world are walls, horizontally and vertically
+    walls: (bool, bool),
+    personality: Personality,
+    /// The number of the bot, starting from 0
+    index: u16,
+    /// How many bots are allowed to play now, the bots with bigger numbers leave the game
+    active_bots: Option<Arc<AtomicU16>>,
+}
+
+/// Spawns the given amount of bots, each in its own thread, that keep rejoining the game
+/// every time they die. The nicknames of the bots are cut to the given length.
+/// If `active_bots` is given, only that many bots play at a time.
+/// A bot that fails to join waits longer after each failure, and gives up after `retries` failures
+/// in a row, if it's given. Each bot gets one of the personalities, picked by their weights
+#[allow(clippy::too_many_arguments)]
+pub fn spawn_bots(
+    amount: u16,
+    host: String,
+    port: u16,
+    socket: Option<PathBuf>,
+    max_nickname_length: usize,
+    active_bots: Option<Arc<AtomicU16>>,
+    retries: Option<u32>,
+    personalities: Vec<(Personality, u16)>,
+) -> Vec<JoinHandle<()>> {
+    (0..amount)
+        .map(|i| {
+            // Generate a nickname for the bot
+            let thread_name = format!("bot_{}", i);
+            let nickname = bot_nickname(i, max_nickname_length);
+            let host = host.clone();
+            let socket = socket.clone();
+            let active_bots = active_bots.clone();
+            let personality = pick_personality(&personalities);
+            thread::Builder::new()
+                .name(thread_name)
+                .spawn(move || {
+                    let mut failures = 0;
+                    let mut delay = RETRY_BASE_DELAY;
+                    loop {
+                        if let Some(active_bots) = &active_bots {
+                            if i >= active_bots.load(Ordering::Relaxed) {
+                                thread::sleep(BOT_WAIT_INTERVAL);
+                                continue;
+                            }
+                        }
+                        if Bot::start(
+                            &host,
+                            port,
+                            socket.as_deref(),
+                            &nickname,
+                            personality,
+                            i,
+                            active_bots.clone(),
+                        ) {
+                            // It played, so rejoin right away
+                            failures = 0;
+                            delay = RETRY_BASE_DELAY;
+                            continue;
+                        }
+                        failures += 1;
+                        if matches!(retries, Some(retries) if failures > retries) {
+                            println!(
+                                "Bot {} gave up after failing to join {} times in a row",
+                                nickname, failures
+                            );
+                            return;
+                        }
+                        thread::sleep(delay);
+                        delay = (delay * 2).min(RETRY_MAX_DELAY);
+                    }
+                })
+                .unwrap()
+        })
+        .collect()
 }
 
 impl Bot {
-    pub fn start(port: u16, nickname: &str) {
-        let mut stream = match TcpStream::connect(("127.0.0.1", port)) {
+    /// Connects to the server, through the Unix domain socket if there is one, and plays until
+    /// the snake dies. Returns `false` if the bot couldn't join the game
+    pub fn start(
+        host: &str,
+        port: u16,
+        socket: Option<&Path>,
+        nickname: &str,
+        personality: Personality,
+        index: u16,
+        active_bots: Option<Arc<AtomicU16>>,
+    ) -> bool {
+        let mut stream = match Stream::connect(host, port, socket) {
             Ok(stream) => stream,
             Err(e) => {
                 println!("Bot {} couldn't connect to server: {}", nickname, e);
-                return;
+                return false;
             }
         };
-        // Send my nickname as a request to connect to the game
-        let mut bytes: Vec<u8> = vec![0x00];
+        // Send my nickname as a request to connect to the game as a bot
+        let mut bytes: Vec<u8> = vec![0x14];
         bytes.extend_from_slice(nickname.as_bytes());
         send_to_stream(&mut stream, &bytes);
         // Read the response
-        let my_id = match read_from_stream(&mut stream) {
+        let (my_id, world_size, walls) = match read_from_stream(&mut stream, MAX_JOIN_RESPONSE_SIZE)
+        {
             Err(_) => {
                 println!(
                     "Bot {} lost connection after requesting to join game",
                     nickname
                 );
-                return;
+                return false;
             }
             Ok(bytes) => {
                 if bytes[0] == 0x05 {
@@ -38,18 +170,27 @@ impl Bot {
                     println!(
                         "Bot {} received an error from server: {}",
                         nickname,
-                        std::str::from_utf8(&bytes[1..]).unwrap_or("{corrupted error}")
+                        std::str::from_utf8(bytes.get(2..).unwrap_or(&[]))
+                            .unwrap_or("{corrupted error}")
                     );
-                    return;
-                } else if bytes[0] == 0x06 && bytes.len() == 7 {
-                    // It's a confirmation that I joined the game, with my ID and the world size
-                    u16::from_be_bytes([bytes[1], bytes[2]])
+                    return false;
+                } else if bytes[0] == 0x06 && bytes.len() >= 12 {
+                    // It's a confirmation that I joined the game, with my ID, the world size
+                    // and which edges of the world are walls
+                    (
+                        u16::from_be_bytes([bytes[1], bytes[2]]),
+                        (
+                            u16::from_be_bytes([bytes[3], bytes[4]]),
+                            u16::from_be_bytes([bytes[5], bytes[6]]),
+                        ),
+                        (bytes[7] & 0b1 != 0, bytes[7] & 0b10 != 0),
+                    )
                 } else {
                     println!(
                         "Bot {} received a corrupted message from server: disconnecting.",
                         nickname
                     );
-                    return;
+                    return false;
                 }
             }
         };
@@ -58,12 +199,24 @@ impl Bot {
             stream,
             my_id,
             nickname: nickname.to_string(),
+            world_size,
+            walls,
+            personality,
+            index,
+            active_bots,
         };
 
         // Then just read from server, and respond to each frame with a direction
         loop {
+            // Leave if there are too many bots now
+            if let Some(active_bots) = &bot.active_bots {
+                if bot.index >= active_bots.load(Ordering::Relaxed) {
+                    send_to_stream(&mut bot.stream, &[0x09]);
+                    break;
+                }
+            }
             // Read from stream
-            match read_from_stream(&mut bot.stream) {
+            match read_from_stream(&mut bot.stream, MAX_MESSAGE_SIZE) {
                 Ok(data) => {
                     // Handle the data
                     if let Some(()) = bot.handle_server_data(data) {
@@ -71,11 +224,12 @@ impl Bot {
                     }
                 }
                 Err(e) => {
-                    println!("Bot {} lost connection to server: {:?}", bot.nickname, e);
+                    println!("Bot {} lost connection to server: {}", bot.nickname, e);
                     break;
                 }
             }
         }
+        true
     }
     /// Handles the data sent by server and acts accordingly
     pub fn handle_server_data(self: &mut Self, data: Vec<u8>) -> Option<()> {
@@ -84,27 +238,41 @@ impl Bot {
             return None;
         }
         if data[0] == 0x03 {
+            if data.len() == 2 && data[1] == 1 {
+                // We died, but the server will give us a new snake soon
+                return None;
+            }
             // We died
             return Some(());
         }
+        if data[0] == 0x0F && data.len() == 3 {
+            // We got a new snake, with a new ID
+            self.my_id = u16::from_be_bytes([data[1], data[2]]);
+            return None;
+        }
+        if data[0] == 0x0A && data.len() == 7 {
+            // Answer the ping with \x0B and the same sequence number
+            send_to_stream(
+                &mut self.stream,
+                &[0x0B, data[1], data[2], data[3], data[4]],
+            );
+            return None;
+        }
         // We only care about the game data: \x04
         if data[0] != 0x04 {
             // Break the loop and spawn a new bot
             return None;
         }
 
-        // Parse the data
-        let data = self.parse_game_data(&data[1..]);
+        // Parse the data, skipping the game speed
+        let data = self.parse_game_data(&data[2..]);
 
         // Decide the new direction based on that data
         self.turn(data);
         None
     }
     /// Parses the game data sent by server
-    pub fn parse_game_data(
-        self: &mut Self,
-        data: &[u8],
-    ) -> (HashMap<(i8, i8), u8>, HashMap<(i8, i8), u16>, bool) {
+    pub fn parse_game_data(&mut self, data: &[u8]) -> GameData {
         // Parse the data
         let mut i = 0; // next byte to read
 
@@ -112,18 +280,26 @@ impl Bot {
         let snake_amount = u16::from_be_bytes([data[i], data[i + 1]]);
         i += 2;
         // Then a lot of snake data that we don't need follows
-        // We need to know if we're in fast mode though
+        // We need to know if we're in fast mode though, and where the heads of the snakes are
         let mut in_fast_mode = false;
+        let mut head_positions: HashMap<u16, (u16, u16)> = HashMap::new();
         for _snake in 0..snake_amount {
             // Check if it's our ID
             let id = u16::from_be_bytes([data[i], data[i + 1]]);
             for _character in 0..u8::from_be_bytes([data[i + 2]]) {
                 i += 1;
             }
+            head_positions.insert(
+                id,
+                (
+                    u16::from_be_bytes([data[i + 7], data[i + 8]]),
+                    u16::from_be_bytes([data[i + 9], data[i + 10]]),
+                ),
+            );
             if id == self.my_id {
-                in_fast_mode = u8::from_be_bytes([data[i + 7]]) == 1;
+                in_fast_mode = u8::from_be_bytes([data[i + 11]]) & 1 != 0;
             }
-            i += 12;
+            i += 16;
         }
 
         // Foods
@@ -138,7 +314,8 @@ impl Bot {
                 ),
                 u8::from_be_bytes([data[i + 2]]), // amount of food there
             );
-            i += 3;
+            // The tier of the field only matters for drawing
+            i += 4;
         }
 
         // Snake parts
@@ -154,50 +331,112 @@ impl Bot {
                 ),
                 u16::from_be_bytes([data[i + 2], data[i + 3]]), // snake id
             );
-            i += 4;
+            // How far the part is from the head doesn't matter for steering
+            i += 6;
         }
 
-        (foods, snake_parts, in_fast_mode)
-    }
-    /// Decides what direction to move and sends that direction to server
-    pub fn turn(self: &mut Self, data: (HashMap<(i8, i8), u8>, HashMap<(i8, i8), u16>, bool)) {
-        // if there's another snake within 3 fields, try to get into fast mode
-        let mut other_snakes_nearby = false;
-        'x: for x in -3i8..=3i8 {
-            for y in -3i8..=3i8 {
-                if let Some(id) = data.1.get(&(x, y)) {
-                    if *id != self.my_id {
-                        other_snakes_nearby = true;
-                        break 'x;
-                    }
-                }
+        // My head's position in the world, to find the other heads relative to it
+        let my_position = (
+            u16::from_be_bytes([data[i], data[i + 1]]),
+            u16::from_be_bytes([data[i + 2], data[i + 3]]),
+        );
+
+        // Running into the edge of the world is as deadly as running into a snake,
+        // so the fields right behind the walls are marked as walls too
+        for &step in &[(-1i8, 0i8), (1, 0), (0, -1), (0, 1)] {
+            let x = my_position.0 as i32 + step.0 as i32;
+            let y = my_position.1 as i32 + step.1 as i32;
+            if (self.walls.0 && (x < 0 || x >= self.world_size.0 as i32))
+                || (self.walls.1 && (y < 0 || y >= self.world_size.1 as i32))
+            {
+                snake_parts.insert(step, WALL_ID);
             }
         }
+        let heads = head_positions
+            .into_iter()
+            .filter(|&(id, _position)| id != self.my_id)
+            .map(|(id, position)| {
+                (
+                    id,
+                    (
+                        wrap_delta(position.0, my_position.0, self.world_size.0),
+                        wrap_delta(position.1, my_position.1, self.world_size.1),
+                    ),
+                )
+            })
+            // Only the ones around me matter
+            .filter(|(_id, (x, y))| {
+                x.abs() <= CHASE_DISTANCE as i32 && y.abs() <= CHASE_DISTANCE as i32
+            })
+            .map(|(id, (x, y))| (id, (x as i8, y as i8)))
+            .collect();
+
+        GameData {
+            foods,
+            snake_parts,
+            heads,
+            in_fast_mode,
+        }
+    }
+    /// Decides what direction to move and sends that direction to server
+    pub fn turn(&mut self, data: GameData) {
+        // How many fields away the closest part of another snake is, walls don't chase anyone
+        let closest_snake = data
+            .snake_parts
+            .iter()
+            .filter(|&(_position, id)| *id != self.my_id && *id != WALL_ID)
+            .map(|(&(x, y), _id)| x.abs().max(y.abs()))
+            .min();
+        // Greedy bots go fast when another snake is within 3 fields, aggressive bots when they're
+        // after one, and cowardly bots only when another snake is right next to them
+        let want_fast_mode = match self.personality {
+            Personality::Greedy => matches!(closest_snake, Some(distance) if distance <= 3),
+            Personality::Cowardly => matches!(closest_snake, Some(distance) if distance <= 1),
+            Personality::Aggressive => !data.heads.is_empty(),
+        };
         // do I need to be in fast mode XOR am I in fast mode
         // So basically, if they're different, randomly toggle fast mode.
-        if other_snakes_nearby ^ data.2 && thread_rng().gen_range(0, 5) == 0 {
+        if want_fast_mode ^ data.in_fast_mode && thread_rng().gen_range(0, 5) == 0 {
             // Ask server to toggle fast-mode
             self.toggle_fast_mode();
         }
 
+        let in_danger = matches!(closest_snake, Some(distance) if distance <= FLEE_DISTANCE);
+        match self.personality {
+            Personality::Cowardly if in_danger => {
+                if let Some(direction) = self.flee_direction(&data.snake_parts) {
+                    self.send_direction(direction);
+                    return;
+                }
+            }
+            Personality::Aggressive => {
+                if let Some(target) = cut_off_target(&data) {
+                    self.steer(target, &data.snake_parts);
+                    return;
+                }
+            }
+            _ => {}
+        }
+
         // Find the best food to try to eat
         // It must give as much score as possible
         // And be as close as possible
         //				 ((x  , y  ), score)
         let mut target = ((0i8, 0i8), 0f32);
-        for (food_pos, amount) in &data.0 {
+        for (food_pos, amount) in &data.foods {
             let s = (*amount as f32).powf(2.0)
                 / ((food_pos.0 as i16).pow(2) + (food_pos.1 as i16).pow(2)) as f32;
             if s > target.1 {
                 target = (*food_pos, s);
             }
         }
-
+        self.steer(target.0, &data.snake_parts);
+    }
+    /// Heads towards the given position relative to my head, avoiding the snake parts right next to it
+    fn steer(&mut self, target: (i8, i8), snake_parts: &HashMap<(i8, i8), u16>) {
         let norm_vec = (
-            (target.0).0 as f32
-                / ((((target.0).0 as i16).pow(2) + ((target.0).1 as i16).pow(2)) as f32).sqrt(),
-            (target.0).1 as f32
-                / ((((target.0).0 as i16).pow(2) + ((target.0).1 as i16).pow(2)) as f32).sqrt(),
+            target.0 as f32 / (((target.0 as i16).pow(2) + (target.1 as i16).pow(2)) as f32).sqrt(),
+            target.1 as f32 / (((target.0 as i16).pow(2) + (target.1 as i16).pow(2)) as f32).sqrt(),
         );
         let dir = if ((norm_vec.0).abs() - (norm_vec.1).abs()).abs() <= std::f32::EPSILON {
             if thread_rng().gen_range(0, 2) == 0 {
@@ -220,20 +459,20 @@ impl Bot {
             )
         };
 
-        if (data.1).contains_key(&(dir.0, dir.1)) {
-            // we might crash into a snake if we go there
+        if snake_parts.contains_key(&(dir.0, dir.1)) {
+            // we might crash into a snake or a wall if we go there
             // Check other directions
-            if !(data.1).contains_key(&(1, 0)) {
-                self.send_direction(3);
+            if !snake_parts.contains_key(&(1, 0)) {
+                self.send_direction(2);
                 return;
-            } else if !(data.1).contains_key(&(-1, 0)) {
-                self.send_direction(1);
+            } else if !snake_parts.contains_key(&(-1, 0)) {
+                self.send_direction(0);
                 return;
-            } else if !(data.1).contains_key(&(0, 1)) {
-                self.send_direction(2);
+            } else if !snake_parts.contains_key(&(0, 1)) {
+                self.send_direction(3);
                 return;
             } else {
-                self.send_direction(0);
+                self.send_direction(1);
                 return;
             }
         }
@@ -246,6 +485,27 @@ impl Bot {
             _ => thread_rng().gen_range(0, 4),
         });
     }
+    /// Finds the free direction that leads the furthest away from the closest part of another snake
+    fn flee_direction(&self, snake_parts: &HashMap<(i8, i8), u16>) -> Option<u8> {
+        let others: Vec<(i8, i8)> = snake_parts
+            .iter()
+            .filter(|&(_position, id)| *id != self.my_id && *id != WALL_ID)
+            .map(|(&position, _id)| position)
+            .collect();
+        [(0u8, (-1i8, 0i8)), (1, (0, -1)), (2, (1, 0)), (3, (0, 1))]
+            .iter()
+            .filter(|(_direction, step)| !snake_parts.contains_key(step))
+            .max_by_key(|(_direction, step)| {
+                others
+                    .iter()
+                    .map(|other| {
+                        (other.0 as i16 - step.0 as i16).abs()
+                            + (other.1 as i16 - step.1 as i16).abs()
+                    })
+                    .min()
+            })
+            .map(|&(direction, _step)| direction)
+    }
     /// Sends a new direction to server
     pub fn send_direction(self: &mut Self, direction: u8) {
         let mut bytes: Vec<u8> = vec![0x02];
@@ -260,8 +520,57 @@ impl Bot {
     }
 }
 
+/// Finds a position a few fields in front of the closest head of another snake, to cut it off
+fn cut_off_target(data: &GameData) -> Option<(i8, i8)> {
+    let (&id, &head) = data
+        .heads
+        .iter()
+        .min_by_key(|(_id, head)| (head.0 as i16).abs() + (head.1 as i16).abs())?;
+    // The part right behind the head tells which way the snake is going
+    let neck = [(-1, 0), (1, 0), (0, -1), (0, 1)]
+        .iter()
+        .map(|step| (head.0 + step.0, head.1 + step.1))
+        .find(|position| data.snake_parts.get(position) == Some(&id))?;
+    Some((
+        head.0 + CUT_OFF_LEAD * (head.0 - neck.0),
+        head.1 + CUT_OFF_LEAD * (head.1 - neck.1),
+    ))
+}
+
+/// Returns the nickname of the bot with the given number, cut to the given length.
+/// The prefix is cut first so that the number, which keeps the nicknames apart, stays
+fn bot_nickname(i: u16, max_nickname_length: usize) -> String {
+    let number = i.to_string();
+    if number.len() >= max_nickname_length {
+        // Only the last digits fit
+        return number[number.len() - max_nickname_length..].to_string();
+    }
+    let mut nickname = "bot_".to_string();
+    nickname.truncate(max_nickname_length - number.len());
+    nickname + &number
+}
+
+/// Picks one of the personalities at random, each with the chance given by its weight
+fn pick_personality(personalities: &[(Personality, u16)]) -> Personality {
+    let total_weight: u32 = personalities
+        .iter()
+        .map(|&(_personality, weight)| weight as u32)
+        .sum();
+    if total_weight == 0 {
+        return Personality::Greedy;
+    }
+    let mut roll = thread_rng().gen_range(0, total_weight);
+    for &(personality, weight) in personalities {
+        if roll < weight as u32 {
+            return personality;
+        }
+        roll -= weight as u32;
+    }
+    Personality::Greedy
+}
+
 /// Sends bytes to stream, with the buffer length appended to the beginning as an u8 integer
-pub fn send_to_stream(stream: &mut TcpStream, data: &[u8]) {
+pub fn send_to_stream(stream: &mut Stream, data: &[u8]) {
     let size: [u8; 1] = u8::to_be_bytes(data.len() as u8);
     let mut message: Vec<u8> = Vec::new();
     message.extend_from_slice(&size);
@@ -269,21 +578,148 @@ pub fn send_to_stream(stream: &mut TcpStream, data: &[u8]) {
 
     stream.write_all(&message).unwrap();
 }
-/// Reads 1 message from stream
+/// Reads 1 message, no bigger than `max_size` bytes, from stream
 /// Returns `Ok(bytes)` if the reading was successful
 /// and `Err(e)` if an error was encountered while reading
-pub fn read_from_stream(stream: &mut TcpStream) -> Result<Vec<u8>, std::io::ErrorKind> {
+pub fn read_from_stream(stream: &mut Stream, max_size: usize) -> Result<Vec<u8>, NetError> {
     // Figure out the size of the incoming message
     let mut size = [0u8; 2];
-    if let Err(e) = stream.read_exact(&mut size) {
-        return Err(e.kind());
-    }
+    stream.read_exact(&mut size)?;
     let size = u16::from_be_bytes(size);
+    // Every message has at least the magic byte,
+    // and don't even allocate the memory for messages that are too big
+    if size == 0 || size as usize > max_size {
+        return Err(NetError::Malformed);
+    }
 
     // Get the actual message
     let mut bytes = vec![0u8; size as usize];
-    if let Err(e) = stream.read_exact(&mut bytes) {
-        return Err(e.kind());
-    }
+    stream.read_exact(&mut bytes)?;
     Ok(bytes)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::client_stream_pair;
+    use std::collections::HashSet;
+    use std::net::TcpStream;
+
+    /// Returns a bot in a 40x40 world with walls on the left and right edges,
+    /// and the server's side of its connection
+    fn bot(personality: Personality) -> (Bot, TcpStream) {
+        let (stream, server_side) = client_stream_pair();
+        let bot = Bot {
+            stream,
+            my_id: 1,
+            nickname: "bot_0".to_string(),
+            world_size: (40, 40),
+            walls: (true, false),
+            personality,
+            index: 0,
+            active_bots: None,
+        };
+        (bot, server_side)
+    }
+
+    /// Builds the game data of a frame with only my snake, going up with its head at the given
+    /// position, and the given foods
+    fn frame(head: (u16, u16), foods: &[((i8, i8), u8)]) -> Vec<u8> {
+        // 1 snake: ID 1, nickname "b", score, kills, head, flags, length and banked score
+        let mut data = vec![0, 1, 0, 1, 1, b'b', 0, 0, 0, 0];
+        data.extend_from_slice(&head.0.to_be_bytes());
+        data.extend_from_slice(&head.1.to_be_bytes());
+        data.extend_from_slice(&[0, 0, 3, 0, 0]);
+        data.extend_from_slice(&(foods.len() as u16).to_be_bytes());
+        for &((x, y), amount) in foods {
+            data.extend_from_slice(&[x as u8, y as u8, amount, 0]);
+        }
+        // The parts of my snake, the head first
+        data.extend_from_slice(&[0, 3]);
+        for depth in 0..3u8 {
+            data.extend_from_slice(&[0, depth, 0, 1, 0, depth]);
+        }
+        data.extend_from_slice(&head.0.to_be_bytes());
+        data.extend_from_slice(&head.1.to_be_bytes());
+        data
+    }
+
+    /// Reads the direction the bot sent, skipping the requests to toggle fast mode
+    fn sent_direction(server_side: &mut TcpStream) -> u8 {
+        let mut length = [0u8; 1];
+        loop {
+            server_side.read_exact(&mut length).unwrap();
+            let mut message = vec![0u8; length[0] as usize];
+            server_side.read_exact(&mut message).unwrap();
+            if message != [0x08] {
+                assert_eq!(message[0], 0x02);
+                return message[1];
+            }
+        }
+    }
+
+    #[test]
+    fn short_bot_nicknames_keep_the_number() {
+        assert_eq!(bot_nickname(7, 10), "bot_7");
+        assert_eq!(bot_nickname(12, 4), "bo12");
+        assert_eq!(bot_nickname(123, 3), "123");
+        assert_eq!(bot_nickname(1234, 2), "34");
+
+        // The nicknames stay apart as long as the numbers fit
+        let nicknames: HashSet<String> = (0..100).map(|i| bot_nickname(i, 3)).collect();
+        assert_eq!(nicknames.len(), 100);
+        assert!(nicknames.iter().all(|nickname| nickname.len() <= 3));
+    }
+
+    #[test]
+    fn bots_dont_run_into_walls() {
+        let (mut bot, mut server_side) = bot(Personality::Greedy);
+
+        // The edges of the world are walls, the other side of the world isn't around the corner
+        let data = bot.parse_game_data(&frame((0, 20), &[]));
+        assert_eq!(data.snake_parts.get(&(-1, 0)), Some(&WALL_ID));
+        assert_eq!(data.snake_parts.get(&(1, 0)), None);
+        let data = bot.parse_game_data(&frame((39, 0), &[]));
+        assert_eq!(data.snake_parts.get(&(1, 0)), Some(&WALL_ID));
+        assert_eq!(data.snake_parts.get(&(0, -1)), None);
+
+        // The food behind the wall isn't worth dying for
+        for _ in 0..20 {
+            let data = bot.parse_game_data(&frame((0, 20), &[((-4, 0), 10)]));
+            bot.turn(data);
+            assert_eq!(sent_direction(&mut server_side), 2);
+        }
+    }
+
+    #[test]
+    fn each_personality_goes_its_own_way() {
+        // My snake going up, food to my right,
+        // and another snake going up a few fields to the right and ahead of me
+        let data = || {
+            let mut snake_parts = HashMap::new();
+            for depth in 0..3 {
+                snake_parts.insert((0, depth), 1);
+                snake_parts.insert((3, depth - 2), 2);
+            }
+            GameData {
+                foods: vec![((2, 0), 10)].into_iter().collect(),
+                snake_parts,
+                heads: vec![(2, (3, -2))].into_iter().collect(),
+                in_fast_mode: false,
+            }
+        };
+        // Greedy bots go for the food, cowardly bots run away from the other snake,
+        // and aggressive bots head to where the other snake is going
+        for &(personality, direction) in &[
+            (Personality::Greedy, 2),
+            (Personality::Cowardly, 0),
+            (Personality::Aggressive, 1),
+        ] {
+            let (mut bot, mut server_side) = bot(personality);
+            for _ in 0..20 {
+                bot.turn(data());
+                assert_eq!(sent_direction(&mut server_side), direction);
+            }
+        }
+    }
+}
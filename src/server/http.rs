@@ -0,0 +1,305 @@
+use super::leaderboard::escape;
+use super::{Server, ACCEPT_INTERVAL};
+use std::io::prelude::*;
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread::{self, sleep, JoinHandle};
+use std::time::Duration;
+
+// How long to wait for the request of a single HTTP client
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+// The biggest HTTP request that is read, anything after it is ignored
+const MAX_REQUEST_SIZE: usize = 4096;
+
+/// Counters of what happened on the server since it started
+#[derive(Default)]
+pub struct Metrics {
+    /// How many ticks passed
+    pub ticks: AtomicU64,
+    /// How long all the ticks took to process in total, without the waiting, in microseconds
+    pub tick_time_total: AtomicU64,
+    /// How many snakes crashed
+    pub deaths: AtomicU64,
+}
+
+/// Starts a thread serving the server statistics over HTTP on the given port,
+/// as JSON at `/stats` and in the Prometheus text format at `/metrics`, until the server is shut down
+pub fn spawn(server: Server, port: u16) -> Option<JoinHandle<()>> {
+    let listener = match TcpListener::bind(("0.0.0.0", port)) {
+        Ok(listener) => listener,
+        Err(e) => {
+            println!("Can't bind the HTTP statistics to port {}: {}", port, e);
+            return None;
+        }
+    };
+    // Don't wait for a request forever, so that the shutdown is noticed
+    if let Err(e) = listener.set_nonblocking(true) {
+        println!("Can't serve the HTTP statistics: {}", e);
+        return None;
+    }
+    println!("Serving HTTP statistics on port {}", port);
+    let handle = thread::Builder::new()
+        .name("http_stats".to_string())
+        .spawn(move || {
+            while !server.shutdown.load(Ordering::Relaxed) {
+                match listener.accept() {
+                    Ok((stream, _address)) => {
+                        if stream.set_nonblocking(false).is_err() {
+                            continue;
+                        }
+                        // A slow client doesn't hold up the others
+                        let server = server.clone();
+                        thread::Builder::new()
+                            .name("http_handler".to_string())
+                            .spawn(move || server.handle_http_request(stream))
+                            .unwrap();
+                    }
+                    Err(_) => sleep(ACCEPT_INTERVAL),
+                }
+            }
+        })
+        .unwrap();
+    Some(handle)
+}
+
+/// A snapshot of the statistics, taken so that no locks are held while responding
+struct Snapshot {
+    max_players: u16,
+    bots: u16,
+    humans: u16,
+    spectators: usize,
+    world_size: (u16, u16),
+    food_rate: u8,
+    game_speed: u8,
+    food_total: u32,
+    ticks: u64,
+    average_tick_time: f64,
+    connections: u64,
+    deaths: u64,
+    /// ID, nickname, score and kills of every player, bots included
+    players: Vec<(u16, String, u16, u16)>,
+}
+
+impl Server {
+    /// Reads a single HTTP request and responds with the statistics, then drops the connection
+    pub fn handle_http_request(&self, mut stream: TcpStream) {
+        if stream.set_read_timeout(Some(REQUEST_TIMEOUT)).is_err() {
+            return;
+        }
+        // Only the request line matters, so don't bother reading the whole request
+        let mut buffer = [0u8; MAX_REQUEST_SIZE];
+        let size = match stream.read(&mut buffer) {
+            Ok(size) => size,
+            Err(_) => return,
+        };
+        let request = String::from_utf8_lossy(&buffer[..size]);
+        let mut request_line = request.lines().next().unwrap_or("").split(' ');
+        let method = request_line.next().unwrap_or("");
+        let path = request_line.next().unwrap_or("");
+
+        let (status, content_type, body) = match (method, path) {
+            ("GET", "/stats") => ("200 OK", "application/json", self.snapshot().to_json()),
+            ("GET", "/metrics") => (
+                "200 OK",
+                "text/plain; version=0.0.4",
+                self.snapshot().to_prometheus(),
+            ),
+            ("GET", _) => ("404 Not Found", "text/plain", "not found\n".to_string()),
+            _ => (
+                "405 Method Not Allowed",
+                "text/plain",
+                "method not allowed\n".to_string(),
+            ),
+        };
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            content_type,
+            body.len(),
+            body
+        );
+        let _ = stream.write_all(response.as_bytes());
+    }
+    /// Collects all the statistics
+    fn snapshot(&self) -> Snapshot {
+        let ticks = self.metrics.ticks.load(Ordering::Relaxed);
+        let tick_time_total = self.metrics.tick_time_total.load(Ordering::Relaxed);
+        let (bots, humans) = self.count_players();
+        Snapshot {
+            max_players: self.config.max_players,
+            bots,
+            humans,
+            spectators: self.spectators.lock().unwrap().len(),
+            world_size: self.config.world_size,
+            food_rate: self.config.food_rate,
+            game_speed: self.current_game_speed.load(Ordering::Relaxed),
+            food_total: self.world.lock().unwrap().food_total,
+            ticks,
+            average_tick_time: if ticks > 0 {
+                tick_time_total as f64 / ticks as f64 / 1000f64
+            } else {
+                0f64
+            },
+            connections: self.connections.load(Ordering::Relaxed),
+            deaths: self.metrics.deaths.load(Ordering::Relaxed),
+            players: self
+                .players
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(&id, player)| {
+                    (
+                        id,
+                        player.nickname.clone(),
+                        player.total_score(),
+                        player.kills,
+                    )
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Snapshot {
+    fn to_json(&self) -> String {
+        let players: Vec<String> = self
+            .players
+            .iter()
+            .map(|(id, nickname, score, kills)| {
+                format!(
+                    "{{\"id\":{},\"nickname\":\"{}\",\"score\":{},\"kills\":{}}}",
+                    id,
+                    escape(nickname),
+                    score,
+                    kills
+                )
+            })
+            .collect();
+        format!(
+            "{{\"max_players\":{},\"bots\":{},\"humans_now\":{},\"spectators\":{},\
+             \"world_width\":{},\"world_height\":{},\"food_rate\":{},\"game_speed\":{},\
+             \"food_total\":{},\"ticks\":{},\"average_tick_time_ms\":{:.3},\
+             \"connections\":{},\"deaths\":{},\"players\":[{}]}}\n",
+            self.max_players,
+            self.bots,
+            self.humans,
+            self.spectators,
+            self.world_size.0,
+            self.world_size.1,
+            self.food_rate,
+            self.game_speed,
+            self.food_total,
+            self.ticks,
+            self.average_tick_time,
+            self.connections,
+            self.deaths,
+            players.join(",")
+        )
+    }
+    fn to_prometheus(&self) -> String {
+        let mut text = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: String| {
+            text += &format!(
+                "# HELP multisnake_{name} {help}\n# TYPE multisnake_{name} {kind}\nmultisnake_{name} {value}\n",
+                name = name,
+                kind = kind,
+                help = help,
+                value = value
+            );
+        };
+        metric(
+            "max_players",
+            "gauge",
+            "Maximum amount of players",
+            self.max_players.to_string(),
+        );
+        metric("bots", "gauge", "Bots playing now", self.bots.to_string());
+        metric(
+            "humans",
+            "gauge",
+            "Human players playing now",
+            self.humans.to_string(),
+        );
+        metric(
+            "spectators",
+            "gauge",
+            "Spectators watching now",
+            self.spectators.to_string(),
+        );
+        metric(
+            "world_width",
+            "gauge",
+            "Width of the world",
+            self.world_size.0.to_string(),
+        );
+        metric(
+            "world_height",
+            "gauge",
+            "Height of the world",
+            self.world_size.1.to_string(),
+        );
+        metric(
+            "food_rate",
+            "gauge",
+            "Food rate of the world",
+            self.food_rate.to_string(),
+        );
+        metric(
+            "game_speed",
+            "gauge",
+            "Ticks per second now",
+            self.game_speed.to_string(),
+        );
+        metric(
+            "food",
+            "gauge",
+            "Total amount of food in the world",
+            self.food_total.to_string(),
+        );
+        metric(
+            "ticks_total",
+            "counter",
+            "Ticks since the server started",
+            self.ticks.to_string(),
+        );
+        metric(
+            "average_tick_time_milliseconds",
+            "gauge",
+            "Average time it takes to process a tick",
+            format!("{:.3}", self.average_tick_time),
+        );
+        metric(
+            "connections_total",
+            "counter",
+            "Connections accepted since the server started",
+            self.connections.to_string(),
+        );
+        metric(
+            "deaths_total",
+            "counter",
+            "Snakes crashed since the server started",
+            self.deaths.to_string(),
+        );
+
+        text += "# HELP multisnake_player_score Score of a player\n# TYPE multisnake_player_score gauge\n";
+        // Nicknames can repeat, the IDs keep the series apart
+        for (id, nickname, score, _kills) in &self.players {
+            text += &format!(
+                "multisnake_player_score{{id=\"{}\",nickname=\"{}\"}} {}\n",
+                id,
+                escape(nickname),
+                score
+            );
+        }
+        text += "# HELP multisnake_player_kills Kills of a player\n# TYPE multisnake_player_kills gauge\n";
+        for (id, nickname, _score, kills) in &self.players {
+            text += &format!(
+                "multisnake_player_kills{{id=\"{}\",nickname=\"{}\"}} {}\n",
+                id,
+                escape(nickname),
+                kills
+            );
+        }
+        text
+    }
+}
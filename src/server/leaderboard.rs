@@ -0,0 +1,62 @@
+use super::{write_atomically, Server, LEADERBOARD_WRITE_INTERVAL};
+use std::path::PathBuf;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::thread::{self, JoinHandle};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+impl Server {
+    /// Returns the leaderboards as JSON, for websites showing the standings
+    pub fn leaderboard_json(&self) -> String {
+        let [by_score, by_kills, by_length] = self.leaderboards();
+        let board_json = |board: Vec<(u16, String)>, key: &str| {
+            let entries: Vec<String> = board
+                .iter()
+                .map(|(value, nickname)| {
+                    format!(
+                        "{{\"nickname\":\"{}\",\"{}\":{}}}",
+                        escape(nickname),
+                        key,
+                        value
+                    )
+                })
+                .collect();
+            format!("[{}]", entries.join(","))
+        };
+        let updated = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs());
+        format!(
+            "{{\"updated\":{},\"by_score\":{},\"by_kills\":{},\"by_length\":{}}}\n",
+            updated,
+            board_json(by_score, "score"),
+            board_json(by_kills, "kills"),
+            board_json(by_length, "length")
+        )
+    }
+}
+
+/// The name of the leaderboard writer, that its temporary file is named after
+pub const WRITER_NAME: &str = "leaderboard";
+
+/// Starts a thread writing the leaderboards to the given file every `LEADERBOARD_WRITE_INTERVAL`,
+/// so the game loop never waits for the disk. Stops when `stop` is disconnected
+pub fn spawn_writer(server: Server, path: PathBuf, stop: Receiver<()>) -> JoinHandle<()> {
+    thread::Builder::new()
+        .name("leaderboard_writer".to_string())
+        .spawn(move || {
+            while stop.recv_timeout(LEADERBOARD_WRITE_INTERVAL) == Err(RecvTimeoutError::Timeout) {
+                // Only the snapshot is taken under the lock of the players
+                let data = server.leaderboard_json();
+                if let Err(e) = write_atomically(&path, WRITER_NAME, &data) {
+                    println!("Can't write the leaderboard to {}: {}", path.display(), e);
+                }
+            }
+        })
+        .unwrap()
+}
+
+/// Escapes a nickname to be put inside quotes, both in JSON and in Prometheus labels.
+/// Nicknames are already escaped to visible ASCII characters, so only quotes and backslashes matter
+pub fn escape(nickname: &str) -> String {
+    nickname.replace('\\', "\\\\").replace('"', "\\\"")
+}
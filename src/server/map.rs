@@ -0,0 +1,170 @@
+use std::fs;
+use std::path::Path;
+
+/// Loads the wall cells of a world from a PBM image (plain or raw), where black pixels are walls.
+/// Each pixel covers a block of cells, so the world size has to be a whole multiple of the image size.
+/// Returns whether each cell of the world is a wall, row by row
+pub fn load(path: &Path, world_size: (u16, u16)) -> Result<Vec<bool>, String> {
+    let data =
+        fs::read(path).map_err(|e| format!("Can't read the map {}: {}", path.display(), e))?;
+    let (width, height, pixels) = parse_pbm(&data, world_size)
+        .map_err(|e| format!("Can't load the map {}: {}", path.display(), e))?;
+
+    // Every pixel of the image becomes a block of this many fields
+    let scale = (
+        world_size.0 as usize / width,
+        world_size.1 as usize / height,
+    );
+    let mut walls = Vec::with_capacity(world_size.0 as usize * world_size.1 as usize);
+    for y in 0..world_size.1 as usize {
+        for x in 0..world_size.0 as usize {
+            walls.push(pixels[(y / scale.1) * width + x / scale.0]);
+        }
+    }
+    Ok(walls)
+}
+
+/// Parses a PBM image for a world of the given size, returns its width, height and whether
+/// each pixel is black, row by row. The size in the header is checked against the world before
+/// anything is allocated for the pixels
+fn parse_pbm(data: &[u8], world_size: (u16, u16)) -> Result<(usize, usize, Vec<bool>), String> {
+    let invalid = || "it's not a valid PBM image".to_string();
+    let mut position = 0;
+    let magic = next_token(data, &mut position).ok_or_else(invalid)?;
+    let mut dimension = || {
+        std::str::from_utf8(next_token(data, &mut position)?)
+            .ok()?
+            .parse::<usize>()
+            .ok()
+    };
+    let width = dimension().ok_or_else(invalid)?;
+    let height = dimension().ok_or_else(invalid)?;
+
+    // Every pixel becomes a block of fields, so the world has to be a whole multiple of the image
+    if width == 0
+        || height == 0
+        || width > world_size.0 as usize
+        || height > world_size.1 as usize
+        || world_size.0 as usize / width * width != world_size.0 as usize
+        || world_size.1 as usize / height * height != world_size.1 as usize
+    {
+        return Err(format!(
+            "it's {}x{}, the world size ({}x{}) has to be a whole multiple of it",
+            width, height, world_size.0, world_size.1
+        ));
+    }
+    let pixel_amount = width.checked_mul(height).ok_or_else(invalid)?;
+
+    let mut pixels = Vec::with_capacity(pixel_amount);
+    match magic {
+        // Plain: '0' and '1' characters, whitespace between them is optional
+        b"P1" => {
+            for &byte in &data[position..] {
+                match byte {
+                    b'0' => pixels.push(false),
+                    b'1' => pixels.push(true),
+                    byte if byte.is_ascii_whitespace() => {}
+                    _ => return Err(invalid()),
+                }
+            }
+        }
+        // Raw: a single whitespace character after the header, then 1 bit per pixel,
+        // with each row padded to a whole byte
+        b"P4" => {
+            let row_size = (width + 7) >> 3;
+            let end = row_size
+                .checked_mul(height)
+                .and_then(|size| size.checked_add(position + 1))
+                .ok_or_else(invalid)?;
+            let rows = data.get(position + 1..end).ok_or_else(invalid)?;
+            for row in rows.chunks(row_size) {
+                for x in 0..width {
+                    pixels.push(row[x / 8] & (0x80 >> (x % 8)) != 0);
+                }
+            }
+        }
+        _ => return Err(invalid()),
+    }
+
+    if pixels.len() != pixel_amount {
+        return Err(invalid());
+    }
+    Ok((width, height, pixels))
+}
+
+/// Returns the next whitespace separated token of the PBM header, skipping comments
+fn next_token<'a>(data: &'a [u8], position: &mut usize) -> Option<&'a [u8]> {
+    loop {
+        match data.get(*position)? {
+            byte if byte.is_ascii_whitespace() => *position += 1,
+            b'#' => {
+                while *data.get(*position)? != b'\n' {
+                    *position += 1;
+                }
+            }
+            _ => break,
+        }
+    }
+    let start = *position;
+    while let Some(byte) = data.get(*position) {
+        if byte.is_ascii_whitespace() {
+            break;
+        }
+        *position += 1;
+    }
+    Some(&data[start..*position])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_and_raw_images_give_the_same_pixels() {
+        let pixels = vec![true, false, true, false, true, false];
+        let plain = b"P1\n# a comment\n3 2\n1 0 1\n010\n";
+        assert_eq!(parse_pbm(plain, (6, 4)), Ok((3, 2, pixels.clone())));
+        // Each row is padded to a whole byte
+        let raw = b"P4\n3 2\n\xA0\x40";
+        assert_eq!(parse_pbm(raw, (6, 4)), Ok((3, 2, pixels)));
+    }
+
+    #[test]
+    fn broken_images_are_rejected() {
+        for data in &[
+            &b""[..],
+            b"P2\n1 1\n1\n",
+            b"P1\n2 1\n1 2\n",
+            b"P1\n2 1\n1\n",
+            b"P4\n16 1\n\xFF",
+            b"P1\nx 1\n1\n",
+        ] {
+            assert_eq!(
+                parse_pbm(data, (16, 16)),
+                Err("it's not a valid PBM image".to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn the_size_is_checked_before_reading_the_pixels() {
+        for (data, size) in &[
+            (&b"P4\n0 1\n"[..], "0x1"),
+            (b"P1\n1 0\n", "1x0"),
+            (b"P1\n3 2\n1 0 1 0 1 0\n", "3x2"),
+            (
+                b"P4\n18446744073709551615 18446744073709551615\n",
+                "18446744073709551615x18446744073709551615",
+            ),
+            (b"P4\n4294967296 4294967296\n", "4294967296x4294967296"),
+        ] {
+            assert_eq!(
+                parse_pbm(data, (16, 16)),
+                Err(format!(
+                    "it's {}, the world size (16x16) has to be a whole multiple of it",
+                    size
+                ))
+            );
+        }
+    }
+}
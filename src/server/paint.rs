@@ -0,0 +1,153 @@
+use super::{offset_coordinate, Player, Server, SnakePartPos, World, WALL_ID};
+use crate::wrap::wrap_delta;
+use std::collections::{HashMap, VecDeque};
+
+// How far around its parts a new snake owns the territory
+const START_TERRITORY_RADIUS: i32 = 1;
+// A snake gets 1 score for every this many fields of territory it owns during a tick
+const TERRITORY_FIELDS_PER_POINT: u32 = 2000;
+
+impl Server {
+    /// Makes the field a part of the territory of the given snake, taking it from the last owner
+    pub fn set_owner(&self, world: &mut World, field: SnakePartPos, id: u16) {
+        let index = self.sfield_index(field);
+        let last_owner = world.owners[index];
+        if last_owner == id {
+            return;
+        }
+        if let Some(owned) = world.territory.get_mut(&last_owner) {
+            *owned -= 1;
+        }
+        world.owners[index] = id;
+        *world.territory.entry(id).or_insert(0) += 1;
+    }
+    /// Gives a new snake the territory around its parts
+    pub fn claim_start_territory(&self, world: &mut World, parts: &[SnakePartPos], id: u16) {
+        for &part in parts {
+            for dy in -START_TERRITORY_RADIUS..=START_TERRITORY_RADIUS {
+                for dx in -START_TERRITORY_RADIUS..=START_TERRITORY_RADIUS {
+                    if let Some(field) = self.offset_position(part, dx, dy) {
+                        if world.snake_parts[self.sfield_index(field)].id != WALL_ID {
+                            self.set_owner(world, field, id);
+                        }
+                    }
+                }
+            }
+        }
+    }
+    /// Takes away all the territory of a snake that's gone
+    pub fn clear_territory(&self, world: &mut World, id: u16) {
+        if world.territory.remove(&id).is_some() {
+            for owner in world.owners.iter_mut().filter(|owner| **owner == id) {
+                *owner = 0;
+            }
+        }
+    }
+    /// Leaves a trail behind the snake's head outside of its territory. When the head gets back,
+    /// the trail and everything it encloses together with the territory is claimed
+    pub fn paint(&self, snake: &mut Player, id: u16, world: &mut World, field: SnakePartPos) {
+        if world.owners[self.sfield_index(field)] != id {
+            snake.trail.push(field);
+        } else if !snake.trail.is_empty() {
+            let trail = std::mem::take(&mut snake.trail);
+            self.claim_trail(world, id, &trail);
+        }
+    }
+    /// Claims the trail and the fields that can't be reached from around it without crossing the
+    /// snake's territory. Only the area around the trail is searched, so territory bulging out of
+    /// it doesn't close anything in
+    fn claim_trail(&self, world: &mut World, id: u16, trail: &[SnakePartPos]) {
+        for &field in trail {
+            self.set_owner(world, field, id);
+        }
+
+        // Follow the trail step by step, so that it stays in one piece across the edges of the world
+        let mut unwrapped = Vec::with_capacity(trail.len());
+        let mut position = (trail[0].0 as i32, trail[0].1 as i32);
+        for (i, field) in trail.iter().enumerate() {
+            if i > 0 {
+                position.0 += wrap_delta(field.0, trail[i - 1].0, self.config.world_size.0);
+                position.1 += wrap_delta(field.1, trail[i - 1].1, self.config.world_size.1);
+            }
+            unwrapped.push(position);
+        }
+        // The area around the trail, with a margin of 1 field to start searching from
+        let min_x = unwrapped.iter().map(|position| position.0).min().unwrap() - 1;
+        let min_y = unwrapped.iter().map(|position| position.1).min().unwrap() - 1;
+        let width =
+            (unwrapped.iter().map(|position| position.0).max().unwrap() - min_x + 2) as usize;
+        let height =
+            (unwrapped.iter().map(|position| position.1).max().unwrap() - min_y + 2) as usize;
+        // A trail around the whole world doesn't enclose anything on its own
+        if width > self.config.world_size.0 as usize || height > self.config.world_size.1 as usize {
+            return;
+        }
+
+        let field_at = |x: usize, y: usize| {
+            Some(SnakePartPos(
+                offset_coordinate(
+                    0,
+                    min_x + x as i32,
+                    self.config.world_size.0,
+                    self.config.edges.0,
+                )?,
+                offset_coordinate(
+                    0,
+                    min_y + y as i32,
+                    self.config.world_size.1,
+                    self.config.edges.1,
+                )?,
+            ))
+        };
+        // Outside of the world is outside of the territory too
+        let open = |x: usize, y: usize| match field_at(x, y) {
+            Some(field) => world.owners[self.sfield_index(field)] != id,
+            None => true,
+        };
+
+        // Flood the area from its edges, whatever isn't reached is enclosed
+        let mut reached = vec![false; width * height];
+        let mut queue = VecDeque::new();
+        for y in 0..height {
+            for x in 0..width {
+                if (x == 0 || y == 0 || x == width - 1 || y == height - 1) && open(x, y) {
+                    reached[y * width + x] = true;
+                    queue.push_back((x, y));
+                }
+            }
+        }
+        while let Some((x, y)) = queue.pop_front() {
+            let neighbours = [
+                (x.wrapping_sub(1), y),
+                (x + 1, y),
+                (x, y.wrapping_sub(1)),
+                (x, y + 1),
+            ];
+            for &(x, y) in &neighbours {
+                if x < width && y < height && !reached[y * width + x] && open(x, y) {
+                    reached[y * width + x] = true;
+                    queue.push_back((x, y));
+                }
+            }
+        }
+
+        let enclosed: Vec<SnakePartPos> = (0..width * height)
+            .filter(|&i| !reached[i])
+            .filter_map(|i| field_at(i % width, i / width))
+            .filter(|&field| world.snake_parts[self.sfield_index(field)].id != WALL_ID)
+            .collect();
+        for field in enclosed {
+            self.set_owner(world, field, id);
+        }
+    }
+    /// Gives every snake score for the territory it owns
+    pub fn score_territory(&self, players: &mut HashMap<u16, Player>, world: &World) {
+        for (id, snake) in players.iter_mut() {
+            snake.territory_progress += world.territory.get(id).copied().unwrap_or(0);
+            let points = snake.territory_progress / TERRITORY_FIELDS_PER_POINT;
+            snake.territory_progress %= TERRITORY_FIELDS_PER_POINT;
+            snake.score = snake.score.saturating_add(points as u16);
+            self.cap_length(snake);
+        }
+    }
+}
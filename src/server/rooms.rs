@@ -0,0 +1,127 @@
+use super::{send_error, Server, ServerConfig, ERROR_BAD_ROOM_NAME, ERROR_DISABLED};
+use crate::stream::Stream;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// The longest room name allowed, after escaping
+const MAX_ROOM_NAME_LENGTH: usize = 16;
+// How long a room can stay empty before it's closed
+const ROOM_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+impl Server {
+    /// Finds the room with the given name, creating it if it doesn't exist yet.
+    /// If there's no such room and it can't be created, lets the client know and returns `None`
+    pub fn find_room(&self, stream: &mut Stream, name: &[u8]) -> Option<Server> {
+        if self.config.max_rooms == 0 {
+            send_error(stream, ERROR_DISABLED, "rooms are disabled");
+            return None;
+        }
+        // Room names are shown to everyone, so escape them just like nicknames
+        let name = match std::str::from_utf8(name) {
+            Ok(name) => name.escape_default().to_string(),
+            Err(_) => String::new(),
+        };
+        if name.is_empty() || name.len() > MAX_ROOM_NAME_LENGTH {
+            send_error(
+                stream,
+                ERROR_BAD_ROOM_NAME,
+                &format!(
+                    "bad room name (1-{} characters, special characters count as several)",
+                    MAX_ROOM_NAME_LENGTH
+                ),
+            );
+            return None;
+        }
+
+        // Counting the connection while the rooms are locked keeps the room from being closed
+        // before the client gets into it
+        let mut rooms = self.rooms.lock().unwrap();
+        if let Some(room) = rooms.get(&name) {
+            room.connections.fetch_add(1, Ordering::Relaxed);
+            return Some(room.clone());
+        }
+        // Rooms are only closed after being empty for a while, so waiting wouldn't help here
+        if rooms.len() >= self.config.max_rooms as usize {
+            send_error(
+                stream,
+                ERROR_BAD_ROOM_NAME,
+                "no such room, and there are too many rooms to create it",
+            );
+            return None;
+        }
+        println!("Creating room \"{}\"", name);
+        let room = self.new_room();
+        room.connections.fetch_add(1, Ordering::Relaxed);
+        rooms.insert(name.clone(), room.clone());
+
+        // Each room runs its own game, until it's closed or the whole server is shut down
+        let room_clone = room.clone();
+        let handle = thread::Builder::new()
+            .name(format!("room_{}", name))
+            .spawn(move || {
+                room_clone.game_loop();
+                room_clone.disconnect_everyone();
+            })
+            .unwrap();
+        self.room_threads.lock().unwrap().push(handle);
+
+        Some(room)
+    }
+    /// Closes the rooms that nobody joined or played in for `ROOM_IDLE_TIMEOUT`, stopping their
+    /// game loops. `activity` keeps how many connections each room had and when it was last used
+    pub fn close_idle_rooms(&self, activity: &mut HashMap<String, (u64, Instant)>) {
+        // A client that found a room can take this long to send its request to join it
+        let timeout = ROOM_IDLE_TIMEOUT.max(self.config.read_timeout);
+        let mut rooms = self.rooms.lock().unwrap();
+        activity.retain(|name, _| rooms.contains_key(name));
+        rooms.retain(|name, room| {
+            let connections = room.connections.load(Ordering::Relaxed);
+            let in_use = !room.client_streams.lock().unwrap().is_empty()
+                || !room.spectators.lock().unwrap().is_empty();
+            let last_used = activity
+                .entry(name.clone())
+                .or_insert((connections, Instant::now()));
+            if in_use || last_used.0 != connections {
+                *last_used = (connections, Instant::now());
+            }
+            if last_used.1.elapsed() < timeout {
+                return true;
+            }
+            println!("Closing room \"{}\", nobody played in it for a while", name);
+            print!("{}", room.usage_summary());
+            room.shutdown.store(true, Ordering::Relaxed);
+            false
+        });
+    }
+    /// Closes all the rooms, when the whole server is shut down
+    pub fn close_rooms(&self) {
+        for (name, room) in self.rooms.lock().unwrap().iter() {
+            println!("Closing room \"{}\"", name);
+            print!("{}", room.usage_summary());
+            room.shutdown.store(true, Ordering::Relaxed);
+        }
+    }
+    /// Constructs a room with the same rules as this server, but with its own world and no bots
+    fn new_room(&self) -> Server {
+        let config = ServerConfig {
+            bots: 0,
+            bots_make_room: false,
+            stats: None,
+            #[cfg(feature = "http-stats")]
+            http_port: None,
+            admin_socket: None,
+            leaderboard_out: None,
+            max_rooms: 0,
+            ..(*self.config).clone()
+        };
+        // Each room has its own shutdown flag, so that it can be closed by itself
+        let mut room = Server::new(config, Arc::new(AtomicBool::new(false)));
+        // The statistics of a player are the same in every room, and admins pause all rooms at once
+        room.stats = self.stats.clone();
+        room.paused = self.paused.clone();
+        room
+    }
+}
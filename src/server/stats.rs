@@ -0,0 +1,150 @@
+use super::{write_atomically, Player};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// How often the statistics are saved, if they changed
+const SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Lifetime totals of a single player
+#[derive(Copy, Clone, Debug, Default)]
+pub struct PlayerStats {
+    /// How many snakes the player had
+    pub games_played: u32,
+    /// How much food the player ate in total
+    pub food_eaten: u32,
+    /// How many snakes the player killed in total
+    pub kills: u32,
+    /// The highest score the player ever had
+    pub best_score: u16,
+    /// The longest time a snake of the player stayed alive, in seconds
+    pub longest_life: u32,
+}
+
+/// Statistics of all players, persisted in a file and keyed by nickname.
+///
+/// The file has one line per player: the nickname and then all the fields of
+/// `PlayerStats` in order, separated by tabs. Nicknames are already escaped so they can't contain tabs
+pub struct Stats {
+    /// The file the statistics are saved to
+    path: PathBuf,
+    /// Statistics of each player, keyed by nickname
+    records: HashMap<String, PlayerStats>,
+    /// Whether the statistics changed since they were last saved
+    changed: bool,
+}
+
+impl Stats {
+    /// Loads the statistics from the given file.
+    /// If the file is missing or corrupt, starts fresh
+    pub fn load(path: PathBuf) -> Self {
+        let records = match fs::read_to_string(&path) {
+            Ok(data) => match parse_records(&data) {
+                Some(records) => records,
+                None => {
+                    println!(
+                        "Player statistics file {} is corrupt, starting fresh",
+                        path.display()
+                    );
+                    HashMap::new()
+                }
+            },
+            Err(_) => HashMap::new(),
+        };
+        Stats {
+            path,
+            records,
+            changed: false,
+        }
+    }
+    /// Returns the statistics of the player with the given nickname, if there are any
+    pub fn get(&self, nickname: &str) -> Option<PlayerStats> {
+        self.records.get(nickname).copied()
+    }
+    /// Makes sure that the player with the given nickname has a record
+    pub fn add_player(&mut self, nickname: &str) {
+        self.records.entry(nickname.to_owned()).or_default();
+    }
+    /// Adds the finished game of a player to their statistics, they're saved later
+    pub fn record_game(&mut self, player: &Player) {
+        let record = self.records.entry(player.nickname.clone()).or_default();
+        record.games_played = record.games_played.saturating_add(1);
+        record.food_eaten = record.food_eaten.saturating_add(player.food_eaten);
+        record.kills = record.kills.saturating_add(player.kills as u32);
+        record.best_score = record.best_score.max(player.total_score());
+        record.longest_life = record
+            .longest_life
+            .max(player.spawned_at.elapsed().as_secs() as u32);
+        self.changed = true;
+    }
+    /// Returns the path of the file and what to write to it, if the statistics changed since
+    /// the last time. The file is written with `save`, so nobody waits for the disk while
+    /// holding the lock of the statistics
+    pub fn take_changes(&mut self) -> Option<(PathBuf, String)> {
+        if !self.changed {
+            return None;
+        }
+        self.changed = false;
+        let mut data = String::new();
+        for (nickname, record) in &self.records {
+            data += &format!(
+                "{}\t{}\t{}\t{}\t{}\t{}\n",
+                nickname,
+                record.games_played,
+                record.food_eaten,
+                record.kills,
+                record.best_score,
+                record.longest_life
+            );
+        }
+        Some((self.path.clone(), data))
+    }
+}
+
+/// The name of the statistics saver, that its temporary file is named after
+pub const WRITER_NAME: &str = "stats";
+
+/// Starts a thread saving the statistics every `SAVE_INTERVAL` if they changed, away from
+/// the game loop. When `stop` is disconnected, it saves them one last time and stops
+pub fn spawn_saver(stats: Arc<Mutex<Stats>>, stop: Receiver<()>) -> JoinHandle<()> {
+    thread::Builder::new()
+        .name("stats_saver".to_string())
+        .spawn(move || loop {
+            let stopped = stop.recv_timeout(SAVE_INTERVAL) != Err(RecvTimeoutError::Timeout);
+            let changes = stats.lock().unwrap().take_changes();
+            if let Some((path, data)) = changes {
+                if let Err(e) = write_atomically(&path, WRITER_NAME, &data) {
+                    println!("Can't save player statistics to {}: {}", path.display(), e);
+                }
+            }
+            if stopped {
+                break;
+            }
+        })
+        .unwrap()
+}
+
+/// Parses the contents of the statistics file, returns `None` if it's corrupt
+fn parse_records(data: &str) -> Option<HashMap<String, PlayerStats>> {
+    let mut records = HashMap::new();
+    for line in data.lines() {
+        let mut fields = line.split('\t');
+        let nickname = fields.next()?.to_owned();
+        let record = PlayerStats {
+            games_played: fields.next()?.parse().ok()?,
+            food_eaten: fields.next()?.parse().ok()?,
+            kills: fields.next()?.parse().ok()?,
+            best_score: fields.next()?.parse().ok()?,
+            longest_life: fields.next()?.parse().ok()?,
+        };
+        if fields.next().is_some() {
+            return None;
+        }
+        records.insert(nickname, record);
+    }
+    Some(records)
+}
@@ -0,0 +1,372 @@
+use std::fmt;
+#[cfg(unix)]
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::time::Duration;
+
+/// A connection between the server and a client, either over TCP or a Unix domain socket
+pub struct Stream {
+    socket: Socket,
+    /// Data that didn't fit in the socket buffer of a nonblocking stream yet,
+    /// it's written before anything else so that messages are never cut in half
+    unsent: Vec<u8>,
+    /// The beginning of a message that only partly arrived on a nonblocking stream,
+    /// the rest of it is read after it the next time
+    unread: Vec<u8>,
+    /// How many bytes were written to the socket
+    sent: u64,
+    /// How many bytes were read from the socket
+    received: u64,
+}
+
+/// The actual socket behind a stream
+enum Socket {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+/// Accepts new connections, either over TCP or on a Unix domain socket
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+/// An error encountered while reading a message from a stream
+#[derive(Debug)]
+pub enum NetError {
+    /// The other side closed the connection
+    ConnectionClosed,
+    /// The whole message isn't available yet on a nonblocking stream
+    WouldBlock,
+    /// Any other I/O error
+    Io(io::Error),
+    /// The message doesn't make sense, for example it's empty
+    Malformed,
+}
+
+impl From<io::Error> for NetError {
+    fn from(error: io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::UnexpectedEof => NetError::ConnectionClosed,
+            io::ErrorKind::WouldBlock => NetError::WouldBlock,
+            _ => NetError::Io(error),
+        }
+    }
+}
+
+impl fmt::Display for NetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NetError::ConnectionClosed => write!(f, "connection closed"),
+            NetError::WouldBlock => write!(f, "message not available yet"),
+            NetError::Io(error) => write!(f, "{}", error),
+            NetError::Malformed => write!(f, "malformed message"),
+        }
+    }
+}
+
+impl Stream {
+    fn new(socket: Socket) -> Self {
+        Stream {
+            socket,
+            unsent: Vec::new(),
+            unread: Vec::new(),
+            sent: 0,
+            received: 0,
+        }
+    }
+    /// Connects to the Unix domain socket at the given path if there is one,
+    /// otherwise connects to the given host and port over TCP
+    pub fn connect(host: &str, port: u16, socket: Option<&Path>) -> io::Result<Self> {
+        let socket = match socket {
+            #[cfg(unix)]
+            Some(path) => UnixStream::connect(path).map(Socket::Unix),
+            #[cfg(not(unix))]
+            Some(_) => Err(unix_sockets_unsupported()),
+            None => TcpStream::connect((host, port)).map(Socket::Tcp),
+        };
+        socket.map(Stream::new)
+    }
+    /// Creates a new independently owned handle to the same connection, without the unsent data
+    pub fn try_clone(&self) -> io::Result<Self> {
+        let socket = match &self.socket {
+            Socket::Tcp(stream) => stream.try_clone().map(Socket::Tcp),
+            #[cfg(unix)]
+            Socket::Unix(stream) => stream.try_clone().map(Socket::Unix),
+        };
+        socket.map(Stream::new)
+    }
+    /// Moves the stream into or out of nonblocking mode
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match &self.socket {
+            Socket::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Socket::Unix(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+    /// Sets the read timeout, `None` means that reads block indefinitely
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match &self.socket {
+            Socket::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Socket::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+    /// Writes the data after whatever is still unsent. On a nonblocking stream, the part
+    /// that doesn't fit in the socket buffer is kept and written first the next time
+    pub fn write_queued(&mut self, data: &[u8]) -> io::Result<()> {
+        self.unsent.extend_from_slice(data);
+        self.write_unsent()
+    }
+    /// Writes as much of the unsent data as fits in the socket buffer
+    pub fn write_unsent(&mut self) -> io::Result<()> {
+        while !self.unsent.is_empty() {
+            match self.socket.write(&self.unsent) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(written) => {
+                    self.unsent.drain(..written);
+                    self.sent += written as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+    /// Returns `true` if some data is still waiting for the other side to read what was sent before
+    pub fn is_behind(&self) -> bool {
+        !self.unsent.is_empty()
+    }
+    /// Waits until the given amount of bytes arrived and returns them, without consuming them.
+    /// On a nonblocking stream, the bytes that already arrived are kept if there's not enough yet.
+    /// Never reads more than asked for, so nothing is lost when the stream is cloned
+    pub fn peek_exact(&mut self, amount: usize) -> io::Result<&[u8]> {
+        while self.unread.len() < amount {
+            let mut buffer = vec![0u8; amount - self.unread.len()];
+            match self.socket.read(&mut buffer) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(read) => {
+                    self.unread.extend_from_slice(&buffer[..read]);
+                    self.received += read as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(&self.unread[..amount])
+    }
+    /// Throws away the given amount of bytes that were peeked at
+    pub fn consume(&mut self, amount: usize) {
+        self.unread.drain(..amount);
+    }
+    /// Returns how many bytes were written to this handle of the connection
+    pub fn bytes_sent(&self) -> u64 {
+        self.sent
+    }
+    /// Returns how many bytes were read from this handle of the connection
+    pub fn bytes_received(&self) -> u64 {
+        self.received
+    }
+}
+
+impl Read for Stream {
+    /// Reads the bytes that were peeked at first, and only then from the socket
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.unread.is_empty() {
+            let amount = buf.len().min(self.unread.len());
+            buf[..amount].copy_from_slice(&self.unread[..amount]);
+            self.consume(amount);
+            return Ok(amount);
+        }
+        let read = self.socket.read(buf)?;
+        self.received += read as u64;
+        Ok(read)
+    }
+}
+
+impl Write for Stream {
+    /// Queues the data after whatever is still unsent, so a nonblocking stream never blocks
+    /// and the messages never get mixed up
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_queued(buf)?;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_unsent()?;
+        self.socket.flush()
+    }
+}
+
+impl Read for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Socket::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Socket::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Socket::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Socket::Unix(stream) => stream.write(buf),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Socket::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Socket::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+impl Listener {
+    /// Binds to the Unix domain socket at the given path if there is one,
+    /// otherwise binds to the given port on all interfaces
+    pub fn bind(port: u16, socket: Option<&Path>) -> io::Result<Self> {
+        match socket {
+            #[cfg(unix)]
+            Some(path) => {
+                // Remove the socket left over by a previous server, if there is one,
+                // but never anything else that happens to be at the path
+                match fs::symlink_metadata(path) {
+                    Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path)?,
+                    Ok(_) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::AlreadyExists,
+                            format!("{} already exists and is not a socket", path.display()),
+                        ))
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e),
+                }
+                UnixListener::bind(path).map(Listener::Unix)
+            }
+            #[cfg(not(unix))]
+            Some(_) => Err(unix_sockets_unsupported()),
+            None => TcpListener::bind(("0.0.0.0", port)).map(Listener::Tcp),
+        }
+    }
+    /// Moves the listener into or out of nonblocking mode
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Listener::Tcp(listener) => listener.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener.set_nonblocking(nonblocking),
+        }
+    }
+    /// Accepts a new connection. The address is `None` for Unix domain sockets
+    pub fn accept(&self) -> io::Result<(Stream, Option<SocketAddr>)> {
+        match self {
+            Listener::Tcp(listener) => listener
+                .accept()
+                .map(|(stream, address)| (Stream::new(Socket::Tcp(stream)), Some(address))),
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener
+                .accept()
+                .map(|(stream, _address)| (Stream::new(Socket::Unix(stream)), None)),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn unix_sockets_unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Other,
+        "Unix domain sockets are not supported on this platform",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{stream_pair, temporary_path};
+    use std::thread;
+
+    #[test]
+    fn writing_to_a_stalled_reader_doesnt_block() {
+        let (mut stream, mut reader) = stream_pair();
+        stream.set_nonblocking(true).unwrap();
+
+        // Far more than fits in the socket buffers, while nobody reads
+        let mut expected = Vec::new();
+        for i in 0..=255u8 {
+            let chunk = vec![i; 1 << 16];
+            stream.write_all(&chunk).unwrap();
+            expected.extend_from_slice(&chunk);
+        }
+        assert!(stream.is_behind());
+
+        // Everything arrives in order once the other side reads again
+        let reader_thread = thread::spawn(move || {
+            let mut received = vec![0u8; expected.len()];
+            reader.read_exact(&mut received).unwrap();
+            received == expected
+        });
+        while stream.is_behind() {
+            stream.flush().unwrap();
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(reader_thread.join().unwrap(), "the data got mixed up");
+        assert_eq!(stream.bytes_sent(), 256 << 16);
+    }
+
+    #[test]
+    fn messages_arriving_one_byte_at_a_time_are_put_together() {
+        let (mut stream, mut writer) = stream_pair();
+        stream.set_nonblocking(true).unwrap();
+
+        let message = [4, 1, 2, 3, 4];
+        for (i, &byte) in message.iter().enumerate() {
+            // The message isn't complete until the last byte
+            let error = stream.peek_exact(message.len()).err().unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+            writer.write_all(&[byte]).unwrap();
+            // Wait for the byte to arrive
+            while stream.peek_exact(i + 1).is_err() {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+        assert_eq!(stream.peek_exact(message.len()).unwrap(), message);
+        assert_eq!(stream.bytes_received(), message.len() as u64);
+
+        // Reading gets the peeked bytes first, and then what came after them
+        stream.consume(1);
+        writer.write_all(&[5]).unwrap();
+        stream.set_nonblocking(false).unwrap();
+        let mut read = [0u8; 5];
+        stream.read_exact(&mut read).unwrap();
+        assert_eq!(read, [1, 2, 3, 4, 5]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn binding_replaces_only_sockets() {
+        let path = temporary_path("socket");
+        drop(Listener::bind(0, Some(&path)).unwrap());
+        // The socket file of the previous listener is still there, and gets replaced
+        drop(Listener::bind(0, Some(&path)).unwrap());
+        fs::remove_file(&path).unwrap();
+
+        let path = temporary_path("file");
+        fs::write(&path, "important").unwrap();
+        let error = Listener::bind(0, Some(&path)).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "important");
+        fs::remove_file(&path).unwrap();
+    }
+}
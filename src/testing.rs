@@ -0,0 +1,35 @@
+//! Helpers shared by the tests of the different modules
+
+use crate::stream::{Listener, Stream};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Returns a path in the temporary directory that's unique to this test run
+pub fn temporary_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("multisnake-{}-{}", std::process::id(), name))
+}
+
+/// Returns both ends of a TCP connection, the accepted end wrapped in a `Stream`
+pub fn stream_pair() -> (Stream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let client_side = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    client_side
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let (server_side, _) = Listener::Tcp(listener).accept().unwrap();
+    (server_side, client_side)
+}
+
+/// Returns both ends of a TCP connection, the connecting end wrapped in a `Stream`
+pub fn client_stream_pair() -> (Stream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let client_side = Stream::connect("127.0.0.1", port, None).unwrap();
+    let (server_side, _) = listener.accept().unwrap();
+    server_side
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    (client_side, server_side)
+}
@@ -0,0 +1,41 @@
+/// Returns the shortest signed offset from `b` to `a` in a world of the given size,
+/// taking into account that the world wraps around at the edges
+pub fn wrap_delta(a: u16, b: u16, size: u16) -> i32 {
+    let delta = a as i32 - b as i32;
+    if delta > size as i32 / 2 {
+        delta - size as i32
+    } else if delta < -(size as i32 / 2) {
+        delta + size as i32
+    } else {
+        delta
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offsets_take_the_short_way_around() {
+        assert_eq!(wrap_delta(5, 5, 40), 0);
+        assert_eq!(wrap_delta(5, 3, 40), 2);
+        assert_eq!(wrap_delta(3, 5, 40), -2);
+        // Across the edge
+        assert_eq!(wrap_delta(39, 0, 40), -1);
+        assert_eq!(wrap_delta(0, 39, 40), 1);
+        // Exactly half way around, either way is as short
+        assert_eq!(wrap_delta(20, 0, 40), 20);
+        assert_eq!(wrap_delta(0, 20, 40), -20);
+        assert_eq!(wrap_delta(21, 0, 41), -20);
+
+        for &size in &[20, 21, 40] {
+            for a in 0..size {
+                for b in 0..size {
+                    let delta = wrap_delta(a, b, size);
+                    assert!(delta.abs() <= size as i32 / 2);
+                    assert_eq!((b as i32 + delta).rem_euclid(size as i32), a as i32);
+                }
+            }
+        }
+    }
+}